@@ -1,464 +1,5812 @@
-use image::codecs::jpeg::JpegEncoder;
-use oxipng::{InFile, Options, OutFile};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::gif::GifDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, GenericImageView, ImageDecoder};
+use oxipng::{Options, StripChunks};
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use zip::write::FileOptions;
 use walkdir::WalkDir;
-use tauri::Manager;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tauri::{Emitter, Manager};
 use chrono::Local;
+use filetime::FileTime;
+use rayon::prelude::*;
 
-const SUPPORTED_EXTENSIONS: [&str; 16] = [
+const SUPPORTED_EXTENSIONS: [&str; 20] = [
     "png", "jpg", "jpeg", "webp", "tiff", "tif", "bmp", "gif", "ico", "tga", "dds", "pnm",
-    "qoi", "hdr", "exr", "ff",
+    "qoi", "hdr", "exr", "ff", "avif", "jxl", "heic", "heif",
 ];
 
-#[derive(serde::Serialize)]
-struct OptimizationResult {
-    original_size: u64,
-    new_size: u64,
-    saved_bytes: u64,
-    output_path: String,
-    skipped: bool,
-    duration_ms: u64,
+/// Error returned by every command, serialized as `{ "type": "<Variant>", ... }`
+/// so the frontend can react per kind instead of string-matching.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "type")]
+enum OptimizeError {
+    FileNotFound { path: String },
+    /// The file name isn't valid UTF-8, so it can't round-trip through the frontend.
+    /// `path` is a lossy rendering for display only.
+    UnrepresentablePath { path: String },
+    /// The source is 0 bytes.
+    EmptyFile { path: String },
+    /// An in-place overwrite target is read-only or locked. `suggested_path` is a free
+    /// sibling the output could go to instead, if the folder is writable.
+    TargetNotWritable { path: String, suggested_path: Option<String> },
+    /// `target_max_bytes` couldn't be reached; `smallest_size` is the best attempt.
+    CannotMeetTarget { max_bytes: u64, smallest_size: u64 },
+    /// The source ends before the image data does, e.g. an interrupted download.
+    TruncatedImage { message: String },
+    UnsupportedFormat { format: String },
+    /// Same-format optimization isn't available; the file needs `convert_to`.
+    NeedsConversion { format: String },
+    DecodeFailed { message: String },
+    EncodeFailed { message: String },
+    IoError { message: String },
+    InvalidInput { message: String },
+    Cancelled,
+    Internal { message: String },
 }
 
-#[tauri::command]
-async fn optimize_image(file_path: String, overwrite: bool, convert_to: Option<String>, quality_step: Option<u32>) -> Result<OptimizationResult, String> {
-    // Offload the heavy lifting to a blocking thread
-    tauri::async_runtime::spawn_blocking(move || {
-        let start_time = std::time::Instant::now();
-        let path = Path::new(&file_path);
-        if !path.exists() {
-            return Err("File not found".to_string());
+impl OptimizeError {
+    fn decode(e: impl std::fmt::Display) -> Self {
+        Self::DecodeFailed { message: e.to_string() }
+    }
+
+    fn encode(e: impl std::fmt::Display) -> Self {
+        Self::EncodeFailed { message: e.to_string() }
+    }
+
+    fn io(e: impl std::fmt::Display) -> Self {
+        Self::IoError { message: e.to_string() }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::InvalidInput { message: message.into() }
+    }
+
+    fn internal(e: impl std::fmt::Display) -> Self {
+        Self::Internal { message: e.to_string() }
+    }
+}
+
+impl std::fmt::Display for OptimizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileNotFound { path } => write!(f, "File not found: {}", path),
+            Self::UnrepresentablePath { path } => write!(f, "File name is not valid UTF-8: {}", path),
+            Self::EmptyFile { path } => write!(f, "File is empty: {}", path),
+            Self::TargetNotWritable { path, .. } => write!(f, "Cannot overwrite read-only or locked file: {}", path),
+            Self::CannotMeetTarget { max_bytes, smallest_size } => write!(
+                f,
+                "Could not get the image under {} bytes (smallest was {} bytes)",
+                max_bytes, smallest_size
+            ),
+            Self::TruncatedImage { message } => write!(f, "Image is truncated: {}", message),
+            Self::UnsupportedFormat { format } => write!(f, "Unsupported format: {}", format),
+            Self::NeedsConversion { .. } => write!(f, "Skipped: Enable auto-convert"),
+            Self::DecodeFailed { message } => write!(f, "Failed to decode image: {}", message),
+            Self::EncodeFailed { message } => write!(f, "Failed to encode image: {}", message),
+            Self::IoError { message } => write!(f, "I/O error: {}", message),
+            Self::InvalidInput { message } => write!(f, "{}", message),
+            Self::Cancelled => write!(f, "Cancelled"),
+            Self::Internal { message } => write!(f, "Internal error: {}", message),
         }
+    }
+}
 
-        let original_size = fs::metadata(path).map_err(|e| e.to_string())?.len();
-        let extension = path
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("")
-            .to_lowercase();
+impl std::error::Error for OptimizeError {}
 
-        // Determine target extension
-        let target_extension = if let Some(ref format) = convert_to {
-            match format.as_str() {
-                "jpg" | "jpeg" => "jpg",
-                "webp" => "webp",
-                "png" => "png",
-                _ => return Err("Unsupported conversion format".to_string()),
-            }
-        } else {
-            extension.as_str()
-        };
+/// The path as a `String`, or `UnrepresentablePath` instead of silently mangling a
+/// non-UTF-8 name the way `to_string_lossy` would.
+fn path_string(path: &Path) -> Result<String, OptimizeError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| OptimizeError::UnrepresentablePath { path: path.to_string_lossy().to_string() })
+}
 
-        // Map quality step (0-7) to actual quality (0-100)
-        // Default to 6 (90) if not provided
-        let step = quality_step.unwrap_or(6);
-        let quality = match step {
-            7 => 100,
-            6 => 90,
-            5 => 80,
-            4 => 70,
-            3 => 60,
-            2 => 50,
-            1 => 30,
-            0 => 10,
-            _ => 90, // Fallback
-        };
+impl From<std::io::Error> for OptimizeError {
+    fn from(e: std::io::Error) -> Self {
+        Self::io(e)
+    }
+}
 
-        // Always use a temporary file for optimization first
-        let file_stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
-        let temp_dir = std::env::temp_dir();
-        let temp_name = format!("{}_{}.{}", file_stem, uuid::Uuid::new_v4(), target_extension);
-        let temp_path = temp_dir.join(temp_name);
-
-        if let Some(ref _format) = convert_to {
-            // Conversion logic
-            // Use Reader to guess format from content, not just extension
-            let img = image::ImageReader::open(path)
-                .map_err(|e| e.to_string())?
-                .with_guessed_format()
-                .map_err(|e| e.to_string())?
-                .decode()
-                .map_err(|e| e.to_string())?;
-
-            let file = fs::File::create(&temp_path).map_err(|e| e.to_string())?;
-            let mut writer = std::io::BufWriter::new(file);
-
-            match target_extension {
-                "jpg" => {
-                    // JPEG does not support transparency (RGBA). Convert to RGB8.
-                    // This drops the alpha channel. For better results, we could blend with a background color,
-                    // but to_rgb8() is a standard "flatten" that works for now.
-                    let rgb_img = img.to_rgb8();
-                    let mut encoder = JpegEncoder::new_with_quality(&mut writer, quality);
-                    encoder
-                        .encode(
-                            &rgb_img,
-                            rgb_img.width(),
-                            rgb_img.height(),
-                            image::ColorType::Rgb8.into(),
-                        )
-                        .map_err(|e| e.to_string())?;
-                }
-                "webp" => {
-                    img.write_to(&mut writer, image::ImageFormat::WebP)
-                        .map_err(|e| e.to_string())?;
-                }
-                "png" => {
-                    img.write_to(&mut writer, image::ImageFormat::Png)
-                        .map_err(|e| e.to_string())?;
-                }
-                _ => return Err("Unsupported conversion format".to_string()),
-            }
-        } else {
-            // Optimization logic (same format)
-            match extension.as_str() {
-                "png" => {
-                    let options = Options::from_preset(2); // Default optimization level
-                    let input = InFile::Path(path.to_path_buf());
-                    let output = OutFile::Path {
-                        path: Some(temp_path.clone()),
-                        preserve_attrs: false,
-                    };
+impl From<zip::result::ZipError> for OptimizeError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::io(e)
+    }
+}
 
-                    oxipng::optimize(&input, &output, &options).map_err(|e| e.to_string())?;
-                }
-                "jpg" | "jpeg" => {
-                    let img = image::open(path).map_err(|e| e.to_string())?;
-                    let file = fs::File::create(&temp_path).map_err(|e| e.to_string())?;
-                    let mut writer = std::io::BufWriter::new(file);
+const DEFAULT_JPEG_QUALITY: u8 = 80;
 
-                    let mut encoder = JpegEncoder::new_with_quality(&mut writer, quality);
-                    encoder
-                        .encode(
-                            img.as_bytes(),
-                            img.width(),
-                            img.height(),
-                            img.color().into(),
-                        )
-                        .map_err(|e| e.to_string())?;
-                }
-                "webp" | "tiff" | "tif" | "bmp" | "gif" | "ico" | "tga" | "dds" | "pnm" | "qoi" | "hdr" | "exr" | "ff" => {
-                    return Err("Skipped: Enable auto-convert".to_string());
-                }
-                _ => return Err("Unsupported file format".to_string()),
-            }
+/// Resolves the JPEG encoder quality for a single run.
+/// An explicit `jpeg_quality` (1-100) wins over the UI's quality step (0-7).
+fn resolve_jpeg_quality(jpeg_quality: Option<u8>, quality_step: Option<u32>) -> Result<u8, OptimizeError> {
+    if let Some(q) = jpeg_quality {
+        if !(1..=100).contains(&q) {
+            return Err(OptimizeError::invalid(format!("JPEG quality must be between 1 and 100, got {}", q)));
         }
+        return Ok(q);
+    }
+
+    // Map quality step (0-7) to actual quality (0-100)
+    let quality = match quality_step {
+        Some(7) => 100,
+        Some(6) => 90,
+        Some(5) => 80,
+        Some(4) => 70,
+        Some(3) => 60,
+        Some(2) => 50,
+        Some(1) => 30,
+        Some(0) => 10,
+        Some(_) => 90, // Fallback
+        None => DEFAULT_JPEG_QUALITY,
+    };
+    Ok(quality)
+}
 
-        let new_size = fs::metadata(&temp_path).map_err(|e| e.to_string())?.len();
+#[derive(Clone, Copy)]
+enum JpegBackend {
+    Image,
+    #[cfg(feature = "mozjpeg")]
+    Mozjpeg,
+}
 
-        // Only check for size increase if we are NOT converting OR if we are converting to the SAME format.
-        // If converting to a DIFFERENT format, we accept the result regardless of size.
-        let is_same_format = extension == target_extension;
-        
-        if new_size >= original_size && (convert_to.is_none() || is_same_format) {
-            // Optimization failed to reduce size, discard result
-            fs::remove_file(&temp_path).map_err(|e| e.to_string())?;
-            return Ok(OptimizationResult {
-                original_size,
-                new_size: original_size,
-                saved_bytes: 0,
-                output_path: file_path, // Return original path
-                skipped: true,
-                duration_ms: start_time.elapsed().as_millis() as u64,
-            });
+fn resolve_jpeg_backend(name: Option<&str>) -> Result<JpegBackend, OptimizeError> {
+    match name.unwrap_or("image") {
+        "image" => Ok(JpegBackend::Image),
+        #[cfg(feature = "mozjpeg")]
+        "mozjpeg" => Ok(JpegBackend::Mozjpeg),
+        #[cfg(not(feature = "mozjpeg"))]
+        "mozjpeg" => Err(OptimizeError::invalid(
+            "The mozjpeg encoder is not available in this build (enable the `mozjpeg` feature)",
+        )),
+        other => Err(OptimizeError::invalid(format!("Unknown JPEG encoder: {}", other))),
+    }
+}
+
+/// Chroma subsampling for JPEG output. 4:2:0 halves color resolution both ways and
+/// suits photos; 4:4:4 keeps colored edges and text free of fringing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChromaSubsampling {
+    Ratio444,
+    Ratio422,
+    Ratio420,
+}
+
+fn resolve_jpeg_subsampling(name: Option<&str>) -> Result<ChromaSubsampling, OptimizeError> {
+    match name.unwrap_or("420") {
+        "444" | "4:4:4" => Ok(ChromaSubsampling::Ratio444),
+        "422" | "4:2:2" => Ok(ChromaSubsampling::Ratio422),
+        "420" | "4:2:0" => Ok(ChromaSubsampling::Ratio420),
+        other => Err(OptimizeError::invalid(format!(
+            "JPEG subsampling must be \"444\", \"422\" or \"420\", got {}",
+            other
+        ))),
+    }
+}
+
+/// Encodes JPEG pixels. `image`'s encoder can't pick chroma subsampling or write
+/// progressive JPEGs, so the default backend goes through the `jpeg-encoder` crate.
+#[allow(clippy::too_many_arguments)]
+fn encode_jpeg<W: Write>(
+    writer: W,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: image::ColorType,
+    quality: u8,
+    progressive: bool,
+    subsampling: ChromaSubsampling,
+    backend: JpegBackend,
+) -> Result<(), OptimizeError> {
+    match backend {
+        #[cfg(feature = "mozjpeg")]
+        JpegBackend::Mozjpeg => {
+            let data = encode_mozjpeg(pixels, width, height, color, quality, progressive, subsampling)?;
+            let mut writer = writer;
+            writer.write_all(&data)?;
+            return Ok(());
         }
+        JpegBackend::Image => {}
+    }
 
-        // Calculate saved bytes (can be negative if size increased during conversion)
-        let saved_bytes = if original_size > new_size {
-            original_size - new_size
-        } else {
-            0 
-        };
+    let color_type = match color {
+        image::ColorType::L8 => jpeg_encoder::ColorType::Luma,
+        image::ColorType::Rgb8 => jpeg_encoder::ColorType::Rgb,
+        image::ColorType::Rgba8 => jpeg_encoder::ColorType::Rgba,
+        other => {
+            return Err(OptimizeError::encode(format!(
+                "The JPEG encoder does not support {:?} pixels",
+                other
+            )))
+        }
+    };
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(OptimizeError::encode("Image is too large for JPEG")),
+    };
 
-        // Optimization successful
-        let output_path = if overwrite {
-            if convert_to.is_none() {
-                // Direct overwrite of source file
-                fs::copy(&temp_path, path).map_err(|e| e.to_string())?;
-                fs::remove_file(&temp_path).map_err(|e| e.to_string())?;
-                path.to_string_lossy().to_string()
-            } else {
-                // Conversion with overwrite enabled = Save to source dir, but handle conflicts
-                // We do NOT delete the original source file as it has a different extension.
-                
-                let parent = path.parent().unwrap_or(Path::new("."));
-                let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
-                let mut target_name = format!("{}.{}", stem, target_extension);
-                let mut target_path = parent.join(&target_name);
-                
-                // Conflict resolution: append (n) if file exists
-                // BUT skip conflict resolution if target_path IS the source path (we are overwriting ourselves)
-                let mut counter = 1;
-                while target_path.exists() && target_path != path {
-                    target_name = format!("{} ({}).{}", stem, counter, target_extension);
-                    target_path = parent.join(&target_name);
-                    counter += 1;
-                }
-                
-                fs::copy(&temp_path, &target_path).map_err(|e| e.to_string())?;
-                fs::remove_file(&temp_path).map_err(|e| e.to_string())?;
-                target_path.to_string_lossy().to_string()
-            }
-        } else {
-            // Keep temp file
-            temp_path.to_string_lossy().to_string()
-        };
+    let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+    encoder.set_progressive(progressive);
+    encoder.set_sampling_factor(match subsampling {
+        ChromaSubsampling::Ratio444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Ratio422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Ratio420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    });
+    encoder
+        .encode(pixels, width, height, color_type)
+        .map_err(OptimizeError::encode)
+}
 
-        let duration_ms = start_time.elapsed().as_millis() as u64;
+#[cfg(feature = "mozjpeg")]
+fn encode_mozjpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: image::ColorType,
+    quality: u8,
+    progressive: bool,
+    subsampling: ChromaSubsampling,
+) -> Result<Vec<u8>, OptimizeError> {
+    let color_space = match color {
+        image::ColorType::L8 => mozjpeg::ColorSpace::JCS_GRAYSCALE,
+        image::ColorType::Rgb8 => mozjpeg::ColorSpace::JCS_RGB,
+        image::ColorType::Rgba8 => mozjpeg::ColorSpace::JCS_EXT_RGBA,
+        other => {
+            return Err(OptimizeError::encode(format!(
+                "mozjpeg does not support {:?} pixels",
+                other
+            )))
+        }
+    };
 
-        Ok(OptimizationResult {
-            original_size,
-            new_size,
-            saved_bytes,
-            output_path,
-            skipped: false,
-            duration_ms,
-        })
+    // libjpeg reports fatal errors by unwinding, so contain them here
+    std::panic::catch_unwind(|| -> std::io::Result<Vec<u8>> {
+        let mut compress = mozjpeg::Compress::new(color_space);
+        compress.set_size(width as usize, height as usize);
+        compress.set_quality(quality as f32);
+        compress.set_optimize_coding(true);
+        compress.set_chroma_sampling_pixel_sizes((1, 1), match subsampling {
+            ChromaSubsampling::Ratio444 => (1, 1),
+            ChromaSubsampling::Ratio422 => (2, 1),
+            ChromaSubsampling::Ratio420 => (2, 2),
+        });
+        if progressive {
+            compress.set_progressive_mode();
+        }
+
+        let mut started = compress.start_compress(Vec::new())?;
+        started.write_scanlines(pixels)?;
+        started.finish()
     })
-    .await
-    .map_err(|e| e.to_string())?
+    .map_err(|_| OptimizeError::encode("mozjpeg failed to encode the image"))?
+    .map_err(OptimizeError::encode)
 }
 
-#[tauri::command]
-async fn backup_files(paths: Vec<String>) -> Result<String, String> {
-    if paths.is_empty() {
-        return Err("No files to backup".to_string());
+const DEFAULT_PNG_LEVEL: u8 = 2;
+const MAX_PNG_LEVEL: u8 = 6;
+
+/// oxipng options for `level`, with the color reductions overridden when requested.
+fn png_options(level: u8, palette_reduction: Option<bool>) -> Options {
+    let mut options = Options::from_preset(level);
+    if let Some(enabled) = palette_reduction {
+        options.palette_reduction = enabled;
+        options.bit_depth_reduction = enabled;
+        options.color_type_reduction = enabled;
+        options.grayscale_reduction = enabled;
     }
+    options
+}
 
-    let first_path = Path::new(&paths[0]);
-    let parent_dir = first_path.parent().unwrap_or(Path::new("."));
-    
-    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-    let zip_name = format!("sqsh-backup-{}.zip", timestamp);
-    let zip_path = parent_dir.join(&zip_name);
-    
-    let file = fs::File::create(&zip_path).map_err(|e| e.to_string())?;
-    let mut zip = zip::ZipWriter::new(file);
-    let options = FileOptions::<()>::default().compression_method(zip::CompressionMethod::Stored);
+/// oxipng preset tried when the configured one runs out of time without a result.
+const PNG_FALLBACK_LEVEL: u8 = 1;
 
-    let mut added_files = false;
+/// Runs oxipng with its own `timeout`, which makes it stop starting new trials once the
+/// deadline passes and return the best PNG it found so far. That output is kept whenever
+/// it's smaller than `data` ("preset", or "timeout" if the deadline passed). Only a late
+/// run with nothing smaller to show falls back: to the fast preset under the same
+/// deadline ("fast"), then to a plain `image` re-encode ("reencode"), and finally to
+/// `data` unchanged ("none"). The strategy is returned when a timeout was set.
+fn oxipng_with_timeout(
+    data: &[u8],
+    options: &Options,
+    timeout: Option<std::time::Duration>,
+    warnings: &mut Vec<String>,
+) -> Result<(Vec<u8>, Option<&'static str>), OptimizeError> {
+    let Some(timeout) = timeout else {
+        let output = oxipng::optimize_from_memory(data, options).map_err(OptimizeError::encode)?;
+        return Ok((output, None));
+    };
+    let smaller = |output: Vec<u8>| (output.len() < data.len()).then_some(output);
 
-    for path_str in paths {
-        let path = Path::new(&path_str);
-        let base = path.parent().unwrap_or(Path::new("."));
-        
-        if path.is_file() {
-             if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                    let name = path.file_name().ok_or("Invalid file name")?.to_string_lossy();
-                    zip.start_file(name, options).map_err(|e| e.to_string())?;
-                    let content = fs::read(path).map_err(|e| e.to_string())?;
-                    zip.write_all(&content).map_err(|e| e.to_string())?;
-                    added_files = true;
-                }
-             }
-        } else if path.is_dir() {
-            for entry in WalkDir::new(path) {
-                let entry = entry.map_err(|e| e.to_string())?;
-                let entry_path = entry.path();
-                
-                if entry_path.is_file() {
-                    if let Some(ext) = entry_path.extension().and_then(|e| e.to_str()) {
-                        if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                            let name = entry_path.strip_prefix(base).map_err(|e| e.to_string())?;
-                            let name_str = name.to_string_lossy().replace('\\', "/");
-                            
-                            zip.start_file(name_str, options).map_err(|e| e.to_string())?;
-                            let content = fs::read(entry_path).map_err(|e| e.to_string())?;
-                            zip.write_all(&content).map_err(|e| e.to_string())?;
-                            added_files = true;
-                        }
-                    }
-                }
-            }
-        }
+    let mut options = options.clone();
+    options.timeout = Some(timeout);
+    let started = std::time::Instant::now();
+    let outcome = oxipng::optimize_from_memory(data, &options);
+    if started.elapsed() <= timeout {
+        return Ok((outcome.map_err(OptimizeError::encode)?, Some("preset")));
     }
-    
-    zip.finish().map_err(|e| e.to_string())?;
-
-    if !added_files {
-        // If zip is empty/no valid files found, remove it
-        let _ = fs::remove_file(&zip_path);
-        return Err("No supported images found to backup".to_string());
+    if let Some(output) = outcome.ok().and_then(smaller) {
+        warnings.push(format!("oxipng hit the {} ms timeout; kept the best result it had", timeout.as_millis()));
+        return Ok((output, Some("timeout")));
     }
 
-    Ok(zip_path.to_string_lossy().to_string())
+    let preset = Options::from_preset(PNG_FALLBACK_LEVEL);
+    options.filter = preset.filter;
+    options.deflate = preset.deflate;
+    options.fast_evaluation = preset.fast_evaluation;
+    if let Some(output) = oxipng::optimize_from_memory(data, &options).ok().and_then(smaller) {
+        warnings.push(format!("oxipng took over {} ms; used the fast preset", timeout.as_millis()));
+        return Ok((output, Some("fast")));
+    }
+    let reencoded = image::load_from_memory(data).ok().and_then(|img| {
+        let mut output = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut output), image::ImageFormat::Png).ok()?;
+        smaller(output)
+    });
+    if let Some(output) = reencoded {
+        warnings.push(format!("oxipng took over {} ms; re-encoded the PNG without it", timeout.as_millis()));
+        return Ok((output, Some("reencode")));
+    }
+    warnings.push(format!("oxipng took over {} ms; the PNG was not optimized further", timeout.as_millis()));
+    Ok((data.to_vec(), Some("none")))
 }
 
-#[tauri::command]
-async fn zip_files(files: Vec<(String, String)>, output_path: String) -> Result<String, String> {
-    let path = Path::new(&output_path);
-    let file = fs::File::create(path).map_err(|e| e.to_string())?;
-    let mut zip = zip::ZipWriter::new(file);
+/// Validates the oxipng preset level (0 = fastest, 6 = smallest output).
+fn resolve_png_level(png_level: Option<u8>) -> Result<u8, OptimizeError> {
+    match png_level {
+        Some(level) if level > MAX_PNG_LEVEL => Err(OptimizeError::invalid(format!(
+            "PNG optimization level must be between 0 and {}, got {}",
+            MAX_PNG_LEVEL, level
+        ))),
+        Some(level) => Ok(level),
+        None => Ok(DEFAULT_PNG_LEVEL),
+    }
+}
 
-    let options = FileOptions::<()>::default().compression_method(zip::CompressionMethod::Stored);
-    let mut used_names = std::collections::HashSet::new();
+const DEFAULT_WEBP_QUALITY: f32 = 80.0;
+const DEFAULT_WEBP_LOSSLESS: bool = true;
 
-    for (fs_path, desired_name) in files {
-        let path = Path::new(&fs_path);
-        
-        // Handle conflicts
-        let mut name_in_zip = desired_name.clone();
-        let mut counter = 1;
-        
-        while used_names.contains(&name_in_zip) {
-            let path_obj = Path::new(&desired_name);
-            let stem = path_obj.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
-            let ext = path_obj.extension().and_then(|s| s.to_str()).unwrap_or("");
-            
-            name_in_zip = if ext.is_empty() {
-                format!("{} ({})", stem, counter)
-            } else {
-                format!("{} ({}).{}", stem, counter, ext)
-            };
-            counter += 1;
-        }
-        
-        used_names.insert(name_in_zip.clone());
-        
-        zip.start_file(name_in_zip, options).map_err(|e| e.to_string())?;
-        let content = fs::read(path).map_err(|e| e.to_string())?;
-        zip.write_all(&content).map_err(|e| e.to_string())?;
+fn resolve_webp_quality(webp_quality: Option<f32>) -> Result<f32, OptimizeError> {
+    match webp_quality {
+        Some(q) if !(0.0..=100.0).contains(&q) => Err(OptimizeError::invalid(format!(
+            "WebP quality must be between 0 and 100, got {}",
+            q
+        ))),
+        Some(q) => Ok(q),
+        None => Ok(DEFAULT_WEBP_QUALITY),
     }
+}
 
-    zip.finish().map_err(|e| e.to_string())?;
-    Ok(output_path)
+/// Encodes lossy WebP through libwebp, since `image` can only write lossless WebP.
+fn encode_lossy_webp(img: &image::DynamicImage, quality: f32) -> Result<Vec<u8>, OptimizeError> {
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(quality)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height()).encode(quality)
+    };
+    Ok(memory.to_vec())
 }
 
-#[tauri::command]
-async fn scan_directory(paths: Vec<String>) -> Result<Vec<String>, String> {
-    let mut files = Vec::new();
+/// Entropy passes libwebp may spend converging on `target_size` (1-10).
+const WEBP_TARGET_SIZE_PASSES: i32 = 6;
 
-    for path_str in paths {
-        let path = Path::new(&path_str);
-        if path.is_file() {
-             if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                    files.push(path_str);
-                }
-            }
-        } else if path.is_dir() {
-            for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-                let entry_path = entry.path();
-                if entry_path.is_file() {
-                    if let Some(ext) = entry_path.extension().and_then(|e| e.to_str()) {
-                        if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                            files.push(entry_path.to_string_lossy().to_string());
-                        }
-                    }
-                }
-            }
-        }
+/// Lossy WebP aimed at `target_size` bytes by libwebp's own rate control, which repeats
+/// only its entropy passes rather than whole encodes. It can land slightly over.
+fn encode_webp_to_size(img: &image::DynamicImage, target_size: u64) -> Result<Vec<u8>, OptimizeError> {
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| OptimizeError::encode("failed to initialise WebP encoder config"))?;
+    config.target_size = target_size.min(i32::MAX as u64) as i32;
+    config.pass = WEBP_TARGET_SIZE_PASSES;
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode_advanced(&config)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height()).encode_advanced(&config)
     }
-    Ok(files)
+    .map_err(|e| OptimizeError::encode(format!("{:?}", e)))?;
+    Ok(memory.to_vec())
 }
 
-#[tauri::command]
-async fn save_file(src_path: String, dest_path: String) -> Result<(), String> {
-    fs::copy(&src_path, &dest_path).map_err(|e| e.to_string())?;
-    Ok(())
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+const DEFAULT_AVIF_SPEED: u8 = 4;
+
+/// Validates AVIF quality (1-100) and encoder speed (1 = slowest/smallest, 10 = fastest).
+fn resolve_avif_settings(quality: Option<u8>, speed: Option<u8>) -> Result<(u8, u8), OptimizeError> {
+    let quality = quality.unwrap_or(DEFAULT_AVIF_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err(OptimizeError::invalid(format!("AVIF quality must be between 1 and 100, got {}", quality)));
+    }
+    let speed = speed.unwrap_or(DEFAULT_AVIF_SPEED);
+    if !(1..=10).contains(&speed) {
+        return Err(OptimizeError::invalid(format!("AVIF speed must be between 1 and 10, got {}", speed)));
+    }
+    Ok((quality, speed))
 }
 
-#[tauri::command]
-async fn get_config(state: tauri::State<'_, std::sync::Mutex<AppConfig>>) -> Result<AppConfig, String> {
-    Ok(state.lock().unwrap().clone())
+fn encode_avif<W: Write>(img: &image::DynamicImage, writer: W, quality: u8, speed: u8) -> Result<(), OptimizeError> {
+    let encoder = AvifEncoder::new_with_speed_quality(writer, speed, quality);
+    img.write_with_encoder(encoder).map_err(OptimizeError::encode)
 }
 
-#[tauri::command]
-async fn update_settings(
-    app_handle: tauri::AppHandle,
-    state: tauri::State<'_, std::sync::Mutex<AppConfig>>,
-    dark_mode: Option<bool>,
-    overwrite: Option<bool>,
-    convert_enabled: Option<bool>,
-    convert_format: Option<String>,
-    quality: Option<u32>,
-    backup: Option<bool>,
-) -> Result<(), String> {
-    let mut config = state.lock().unwrap();
-    if let Some(v) = dark_mode { config.dark_mode = v; }
-    if let Some(v) = overwrite { config.overwrite = v; }
-    if let Some(v) = convert_enabled { config.convert_enabled = v; }
-    if let Some(v) = convert_format { config.convert_format = v; }
-    if let Some(v) = quality { config.quality = v; }
-    if let Some(v) = backup { config.backup = v; }
-    
-    save_config(&app_handle, &config);
-    Ok(())
+/// Maps the `tiff_compression` option onto the `tiff` crate's codecs. Defaults to LZW,
+/// which every TIFF reader supports.
+fn resolve_tiff_compression(name: Option<&str>) -> Result<tiff::encoder::Compression, OptimizeError> {
+    use tiff::encoder::{Compression, DeflateLevel};
+    match name.unwrap_or("lzw") {
+        "lzw" => Ok(Compression::Lzw),
+        "deflate" => Ok(Compression::Deflate(DeflateLevel::Best)),
+        "packbits" => Ok(Compression::Packbits),
+        "none" => Ok(Compression::Uncompressed),
+        other => Err(OptimizeError::invalid(format!("Unknown TIFF compression: {}", other))),
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-struct AppConfig {
-    x: i32,
-    y: i32,
-    width: u32,
-    height: u32,
-    #[serde(default = "default_dark_mode")]
-    dark_mode: bool,
-    #[serde(default = "default_overwrite")]
-    overwrite: bool,
-    #[serde(default = "default_convert_enabled")]
-    convert_enabled: bool,
-    #[serde(default = "default_convert_format")]
-    convert_format: String,
-    #[serde(default = "default_quality")]
-    quality: u32,
-    #[serde(default = "default_backup")]
-    backup: bool,
+/// Maps `resize_filter` onto `image`'s filters; Lanczos3 unless set.
+fn resolve_resize_filter(name: Option<&str>) -> Result<FilterType, OptimizeError> {
+    match name.unwrap_or("lanczos3") {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "catmullrom" => Ok(FilterType::CatmullRom),
+        "gaussian" => Ok(FilterType::Gaussian),
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        other => Err(OptimizeError::invalid(format!("Unknown resize filter: {}", other))),
+    }
 }
 
-fn default_dark_mode() -> bool { true }
-fn default_overwrite() -> bool { true }
-fn default_convert_enabled() -> bool { false }
-fn default_convert_format() -> String { "jpg".to_string() }
-fn default_quality() -> u32 { 6 }
-fn default_backup() -> bool { false }
+/// Encodes a single-page TIFF, keeping 16-bit samples where the source has them.
+fn encode_tiff(img: &image::DynamicImage, compression: tiff::encoder::Compression) -> Result<Vec<u8>, OptimizeError> {
+    use image::DynamicImage::*;
+    use tiff::encoder::colortype;
 
-impl Default for AppConfig {
-    fn default() -> Self {
-        Self {
-            x: 0,
-            y: 0,
-            width: 800,
-            height: 600,
-            dark_mode: default_dark_mode(),
-            overwrite: default_overwrite(),
-            convert_enabled: default_convert_enabled(),
-            convert_format: default_convert_format(),
-            quality: default_quality(),
-            backup: default_backup(),
-        }
+    let mut data = std::io::Cursor::new(Vec::new());
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut data)
+        .map_err(OptimizeError::encode)?
+        .with_compression(compression);
+    let (width, height) = img.dimensions();
+    match img {
+        ImageLuma8(buffer) => encoder.write_image::<colortype::Gray8>(width, height, buffer.as_raw()),
+        ImageLuma16(buffer) => encoder.write_image::<colortype::Gray16>(width, height, buffer.as_raw()),
+        ImageRgb8(buffer) => encoder.write_image::<colortype::RGB8>(width, height, buffer.as_raw()),
+        ImageRgb16(buffer) => encoder.write_image::<colortype::RGB16>(width, height, buffer.as_raw()),
+        ImageRgba16(buffer) => encoder.write_image::<colortype::RGBA16>(width, height, buffer.as_raw()),
+        img if img.color().has_alpha() => encoder.write_image::<colortype::RGBA8>(width, height, img.to_rgba8().as_raw()),
+        img => encoder.write_image::<colortype::RGB8>(width, height, img.to_rgb8().as_raw()),
     }
+    .map_err(OptimizeError::encode)?;
+    Ok(data.into_inner())
 }
 
-fn save_config(app_handle: &tauri::AppHandle, state: &AppConfig) {
-    if let Ok(config_dir) = app_handle.path().config_dir() {
-        let app_dir = config_dir.join("sqsh");
-        if !app_dir.exists() {
-            let _ = std::fs::create_dir_all(&app_dir);
-        }
-        let path = app_dir.join("sqsh.toml");
-        let _ = std::fs::write(path, toml::to_string(state).unwrap_or_default());
+/// Number of pages (IFDs) in a TIFF; `image` only ever decodes the first.
+fn tiff_page_count(data: &[u8]) -> Result<usize, OptimizeError> {
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).map_err(OptimizeError::decode)?;
+    let mut pages = 1;
+    while decoder.more_images() {
+        decoder.next_image().map_err(OptimizeError::decode)?;
+        pages += 1;
     }
+    Ok(pages)
 }
 
-fn load_config(app_handle: &tauri::AppHandle) -> Option<AppConfig> {
-    let config_dir = app_handle.path().config_dir().ok()?;
-    let app_dir = config_dir.join("sqsh");
-    let path = app_dir.join("sqsh.toml");
-    if path.exists() {
-        let content = std::fs::read_to_string(path).ok()?;
-        toml::from_str(&content).ok()
+const DEFAULT_JXL_DISTANCE: f32 = 1.0;
+const MAX_JXL_DISTANCE: f32 = 15.0;
+
+/// Validates the JPEG XL Butteraugli distance (0 = lossless, 1 = visually lossless, up to 15).
+fn resolve_jxl_distance(distance: Option<f32>) -> Result<f32, OptimizeError> {
+    let distance = distance.unwrap_or(DEFAULT_JXL_DISTANCE);
+    if !(0.0..=MAX_JXL_DISTANCE).contains(&distance) {
+        return Err(OptimizeError::invalid(format!(
+            "JXL distance must be between 0 and {}, got {}",
+            MAX_JXL_DISTANCE, distance
+        )));
+    }
+    Ok(distance)
+}
+
+/// Maps a 1-100 quality to a Butteraugli distance the same way libjxl's `cjxl -q` does.
+fn jxl_distance_from_quality(quality: u8) -> f32 {
+    let quality = quality as f32;
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
     } else {
-        None
+        (6.4 + 2.5f32.powf((30.0 - quality) / 5.0) / 6.25).min(MAX_JXL_DISTANCE)
     }
 }
 
-const MIN_WINDOW_WIDTH: u32 = 400;
-const MIN_WINDOW_HEIGHT: u32 = 800;
+#[cfg(feature = "jxl")]
+fn encode_jxl(img: &image::DynamicImage, distance: f32) -> Result<Vec<u8>, OptimizeError> {
+    let has_alpha = img.color().has_alpha();
+    let lossless = distance == 0.0;
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .has_alpha(has_alpha)
+        .lossless(lossless)
+        .uses_original_profile(lossless)
+        .quality(distance)
+        .build()
+        .map_err(OptimizeError::encode)?;
+
+    let result: jpegxl_rs::encode::EncoderResult<u8> = if has_alpha {
+        let rgba = img.to_rgba8();
+        encoder.encode::<u8, u8>(&rgba, rgba.width(), rgba.height())
+    } else {
+        let rgb = img.to_rgb8();
+        encoder.encode::<u8, u8>(&rgb, rgb.width(), rgb.height())
+    }
+    .map_err(OptimizeError::encode)?;
+    Ok(result.data)
+}
+
+/// Transcodes JPEG data to JXL without re-encoding the DCT coefficients, keeping
+/// the reconstruction data so the original file can be restored bit for bit.
+#[cfg(feature = "jxl")]
+fn recompress_jpeg_to_jxl(jpeg: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .use_container(true)
+        .build()
+        .map_err(OptimizeError::encode)?;
+    let result: jpegxl_rs::encode::EncoderResult<u8> =
+        encoder.encode_jpeg(jpeg).map_err(OptimizeError::encode)?;
+    Ok(result.data)
+}
+
+#[cfg(feature = "jxl")]
+fn decode_jxl(data: &[u8]) -> Result<image::DynamicImage, OptimizeError> {
+    let decoder = jpegxl_rs::decoder_builder().build().map_err(OptimizeError::decode)?;
+    let (metadata, pixels) = decoder.decode_with::<u8>(data).map_err(OptimizeError::decode)?;
+    let (width, height) = (metadata.width, metadata.height);
+    let channels = metadata.num_color_channels + u32::from(metadata.has_alpha_channel);
+
+    let img = match channels {
+        1 => image::GrayImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageLuma8),
+        2 => image::GrayAlphaImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageLumaA8),
+        3 => image::RgbImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8),
+        4 => image::RgbaImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgba8),
+        _ => None,
+    };
+    img.ok_or_else(|| OptimizeError::decode(format!("unexpected JXL channel count {}", channels)))
+}
+
+#[cfg(not(feature = "jxl"))]
+fn encode_jxl(_img: &image::DynamicImage, _distance: f32) -> Result<Vec<u8>, OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "jxl".to_string() })
+}
+
+#[cfg(not(feature = "jxl"))]
+fn recompress_jpeg_to_jxl(_jpeg: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "jxl".to_string() })
+}
+
+#[cfg(not(feature = "jxl"))]
+fn decode_jxl(_data: &[u8]) -> Result<image::DynamicImage, OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "jxl".to_string() })
+}
+
+/// ISO-BMFF brands of HEVC-coded HEIF files. AVIF shares the container but has its own
+/// brands, which `image` already recognizes.
+const HEIF_BRANDS: [&[u8; 4]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis"];
+
+/// Whether `data` starts with an `ftyp` box naming a HEIF brand. The generic `mif1`
+/// major brand is used by AVIF too, so then an HEVC brand has to be among the
+/// compatible brands.
+fn is_heif(data: &[u8]) -> bool {
+    if data.len() < 12 || &data[4..8] != b"ftyp" {
+        return false;
+    }
+    let is_hevc_brand = |brand: &[u8]| HEIF_BRANDS.iter().any(|hevc| brand == &hevc[..]);
+    if &data[8..12] != b"mif1" {
+        return is_hevc_brand(&data[8..12]);
+    }
+    // Compatible brands follow the minor version and run to the end of the box
+    let box_end = (u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize).min(data.len());
+    data.get(16..box_end).is_some_and(|brands| brands.chunks_exact(4).any(is_hevc_brand))
+}
+
+/// Decodes the primary image of a HEIC/HEIF file. libheif applies the container's
+/// rotation and mirroring (`irot`/`imir`), so the result is already upright.
+#[cfg(feature = "heif")]
+fn decode_heif(data: &[u8]) -> Result<image::DynamicImage, OptimizeError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(data).map_err(OptimizeError::decode)?;
+    let handle = context.primary_image_handle().map_err(OptimizeError::decode)?;
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(OptimizeError::decode)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| OptimizeError::decode("HEIF image has no interleaved plane"))?;
+
+    // Rows are padded to `stride` bytes
+    let (width, height) = (plane.width, plane.height);
+    let row_bytes = width as usize * if has_alpha { 4 } else { 3 };
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(height as usize)
+        .flat_map(|row| &row[..row_bytes])
+        .copied()
+        .collect();
+    let img = match has_alpha {
+        true => image::RgbaImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgba8),
+        false => image::RgbImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8),
+    };
+    img.ok_or_else(|| OptimizeError::decode("HEIF plane is smaller than its dimensions"))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_data: &[u8]) -> Result<image::DynamicImage, OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "heic".to_string() })
+}
+
+/// Size of the primary image of a HEIC/HEIF file, read from the container without
+/// decoding.
+#[cfg(feature = "heif")]
+fn heif_dimensions(data: &[u8]) -> Result<(u32, u32), OptimizeError> {
+    let context = libheif_rs::HeifContext::read_from_bytes(data).map_err(OptimizeError::decode)?;
+    let handle = context.primary_image_handle().map_err(OptimizeError::decode)?;
+    Ok((handle.width(), handle.height()))
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_dimensions(_data: &[u8]) -> Result<(u32, u32), OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "heic".to_string() })
+}
+
+/// Decodes any supported input, guessing the format from content rather than the extension.
+fn load_image(path: &Path) -> Result<image::DynamicImage, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    decode_image(&fs::read(path)?, extension)
+}
+
+/// In-memory counterpart of `load_image`; `extension` is only needed to spot JXL,
+/// which `image` can't detect.
+fn decode_image(data: &[u8], extension: &str) -> Result<image::DynamicImage, OptimizeError> {
+    if extension.eq_ignore_ascii_case("jxl") {
+        return decode_jxl(data);
+    }
+    if is_heif(data) {
+        return decode_heif(data);
+    }
+    // Bake the EXIF orientation into the pixels: encoders never copy the tag, so an
+    // un-rotated buffer would come out sideways
+    let mut decoder = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(decode_error)?;
+    let orientation = decoder.orientation().unwrap_or(image::metadata::Orientation::NoTransforms);
+    let mut img = image::DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Maps a decode failure, reporting running out of data as `TruncatedImage`.
+fn decode_error(e: image::ImageError) -> OptimizeError {
+    let message = e.to_string();
+    let lower = message.to_lowercase();
+    let eof = matches!(&e, image::ImageError::IoError(io) if io.kind() == std::io::ErrorKind::UnexpectedEof);
+    if eof || lower.contains("eof") || lower.contains("end of") || lower.contains("truncated") {
+        OptimizeError::TruncatedImage { message }
+    } else {
+        OptimizeError::DecodeFailed { message }
+    }
+}
+
+/// Cheap structural check for incomplete files. JPEG and PNG have explicit end
+/// markers; some decoders (JPEG in particular) happily return a partially gray image
+/// instead of failing, so look for the marker before decoding.
+fn check_complete(data: &[u8]) -> Result<(), OptimizeError> {
+    let missing = match image::guess_format(data) {
+        // Entropy-coded data stuffs 0xFF bytes, so EOI can't appear by accident after the last SOS
+        Ok(image::ImageFormat::Jpeg) => {
+            let complete = data
+                .windows(2)
+                .rposition(|w| w == [0xFF, 0xDA])
+                .is_some_and(|sos| data[sos..].windows(2).any(|w| w == [0xFF, 0xD9]));
+            (!complete).then_some("JPEG is missing its end-of-image marker")
+        }
+        Ok(image::ImageFormat::Png) => {
+            (!data.windows(4).any(|w| w == b"IEND")).then_some("PNG is missing its IEND chunk")
+        }
+        _ => None,
+    };
+    match missing {
+        Some(message) => Err(OptimizeError::TruncatedImage { message: message.to_string() }),
+        None => Ok(()),
+    }
+}
+
+/// Reads dimensions from the header without decoding the pixels.
+fn image_dimensions(data: &[u8]) -> Result<(u32, u32), OptimizeError> {
+    if is_heif(data) {
+        return heif_dimensions(data);
+    }
+    image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()?
+        .into_dimensions()
+        .map_err(OptimizeError::decode)
+}
+
+/// Reads the embedded ICC profile (JPEG APP2, PNG iCCP, WebP ICCP, ...), if any.
+fn read_icc_profile(data: &[u8]) -> Result<Option<Vec<u8>>, OptimizeError> {
+    let reader = image::ImageReader::new(std::io::Cursor::new(data)).with_guessed_format()?;
+    match reader.into_decoder() {
+        Ok(mut decoder) => decoder.icc_profile().map_err(OptimizeError::decode),
+        // Formats `image` can't decode (e.g. JXL) are handled elsewhere; they just lose the profile
+        Err(_) => Ok(None),
+    }
+}
+
+fn icc_dropped_warning(format: &str) -> String {
+    format!("ICC profile dropped: the {} encoder cannot embed it", format.to_uppercase())
+}
+
+/// Writes `img` with `encoder`, embedding the ICC profile when the encoder supports it.
+fn write_with_icc<E: image::ImageEncoder>(
+    img: &image::DynamicImage,
+    mut encoder: E,
+    icc_profile: Option<&[u8]>,
+    format: &str,
+    warnings: &mut Vec<String>,
+) -> Result<(), OptimizeError> {
+    if let Some(icc) = icc_profile {
+        if encoder.set_icc_profile(icc.to_vec()).is_err() {
+            warnings.push(icc_dropped_warning(format));
+        }
+    }
+    img.write_with_encoder(encoder).map_err(OptimizeError::encode)
+}
+
+/// Payload bytes per APP2 segment: 65535 minus the length field and the 14-byte header.
+const ICC_CHUNK_SIZE: usize = 65_519;
+
+/// Inserts the ICC profile as `ICC_PROFILE` APP2 segments after SOI and any JFIF APP0.
+fn embed_jpeg_icc(jpeg: &[u8], icc: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    let chunks: Vec<&[u8]> = icc.chunks(ICC_CHUNK_SIZE).collect();
+    if chunks.len() > u8::MAX as usize {
+        return Err(OptimizeError::encode("ICC profile is too large to embed in a JPEG"));
+    }
+
+    let mut insert_at = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        insert_at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    let insert_at = insert_at.min(jpeg.len());
+
+    let mut out = Vec::with_capacity(jpeg.len() + icc.len() + chunks.len() * 18);
+    out.extend_from_slice(&jpeg[..insert_at]);
+    for (index, chunk) in chunks.iter().enumerate() {
+        out.extend_from_slice(&[0xFF, 0xE2]);
+        out.extend_from_slice(&((chunk.len() + 16) as u16).to_be_bytes());
+        out.extend_from_slice(b"ICC_PROFILE\0");
+        out.push(index as u8 + 1);
+        out.push(chunks.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&jpeg[insert_at..]);
+    Ok(out)
+}
+
+/// Keyword the PNG spec reserves for miscellaneous comments.
+const PNG_COMMENT_KEYWORD: &[u8] = b"Comment";
+
+/// Writes `comment` into `data`: a COM segment for JPEG, a `tEXt` chunk for PNG (`iTXt`
+/// when the text isn't Latin-1). Formats without a comment field keep the data as is.
+fn embed_comment(
+    data: Vec<u8>,
+    target_extension: &str,
+    comment: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<u8>, OptimizeError> {
+    match target_extension {
+        "jpg" | "jpeg" => embed_jpeg_comment(&data, comment),
+        "png" => embed_png_comment(&data, comment),
+        _ => {
+            warnings.push(format!(
+                "Comment dropped: the {} format has no comment field",
+                target_extension.to_uppercase()
+            ));
+            Ok(data)
+        }
+    }
+}
+
+/// Inserts a COM segment after SOI and any JFIF APP0.
+fn embed_jpeg_comment(jpeg: &[u8], comment: &str) -> Result<Vec<u8>, OptimizeError> {
+    let text = comment.as_bytes();
+    if text.len() > u16::MAX as usize - 2 {
+        return Err(OptimizeError::invalid("Comment is too long for a JPEG COM segment"));
+    }
+    let mut insert_at = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        insert_at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    let insert_at = insert_at.min(jpeg.len());
+
+    let mut out = Vec::with_capacity(jpeg.len() + text.len() + 4);
+    out.extend_from_slice(&jpeg[..insert_at]);
+    out.extend_from_slice(&[0xFF, 0xFE]);
+    out.extend_from_slice(&((text.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(text);
+    out.extend_from_slice(&jpeg[insert_at..]);
+    Ok(out)
+}
+
+/// Inserts a `Comment` text chunk just before IEND.
+fn embed_png_comment(png: &[u8], comment: &str) -> Result<Vec<u8>, OptimizeError> {
+    // tEXt is Latin-1; anything else goes in an uncompressed iTXt with empty language tags
+    let (kind, payload): (&[u8; 4], Vec<u8>) = match comment.chars().all(|c| (c as u32) < 0x100) {
+        true => {
+            let mut payload = PNG_COMMENT_KEYWORD.to_vec();
+            payload.push(0);
+            payload.extend(comment.chars().map(|c| c as u8));
+            (b"tEXt", payload)
+        }
+        false => {
+            let mut payload = PNG_COMMENT_KEYWORD.to_vec();
+            payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+            payload.extend_from_slice(comment.as_bytes());
+            (b"iTXt", payload)
+        }
+    };
+    // IEND is always the last 12 bytes: zero length, type, CRC
+    let iend = png
+        .len()
+        .checked_sub(12)
+        .filter(|&at| &png[at + 4..at + 8] == b"IEND")
+        .ok_or_else(|| OptimizeError::encode("PNG output has no IEND chunk"))?;
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(&payload);
+    let mut out = Vec::with_capacity(png.len() + payload.len() + 12);
+    out.extend_from_slice(&png[..iend]);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+    out.extend_from_slice(&png[iend..]);
+    Ok(out)
+}
+
+/// libjpeg reports fatal errors through `error_exit`; unwinding out of it (mozjpeg-sys is
+/// built with `unwinding`) lets `transcode_jpeg` turn them into an error.
+#[cfg(feature = "mozjpeg")]
+extern "C-unwind" fn jpeg_error_exit(cinfo: &mut mozjpeg_sys::jpeg_common_struct) {
+    let code = unsafe { cinfo.err.as_ref() }.map_or(0, |err| err.msg_code);
+    std::panic::resume_unwind(Box::new(format!("libjpeg error code {}", code)));
+}
+
+#[cfg(feature = "mozjpeg")]
+extern "C-unwind" fn jpeg_silence_message(_cinfo: &mut mozjpeg_sys::jpeg_common_struct, _level: std::os::raw::c_int) {}
+
+#[cfg(feature = "mozjpeg")]
+extern "C" {
+    fn free(ptr: *mut std::ffi::c_void);
+}
+
+/// Owns the libjpeg structs and the `jpeg_mem_dest` buffer so they are released even
+/// when libjpeg unwinds.
+#[cfg(feature = "mozjpeg")]
+struct JpegTranscoder {
+    src: Box<mozjpeg_sys::jpeg_decompress_struct>,
+    dst: Box<mozjpeg_sys::jpeg_compress_struct>,
+    output: *mut u8,
+    _errors: [Box<mozjpeg_sys::jpeg_error_mgr>; 2],
+}
+
+#[cfg(feature = "mozjpeg")]
+impl JpegTranscoder {
+    fn new() -> Self {
+        use mozjpeg_sys::*;
+        unsafe {
+            let mut errors: [Box<jpeg_error_mgr>; 2] = [Box::new(std::mem::zeroed()), Box::new(std::mem::zeroed())];
+            for err in &mut errors {
+                jpeg_std_error(err);
+                err.error_exit = Some(jpeg_error_exit);
+                err.emit_message = Some(jpeg_silence_message);
+            }
+            let mut src: Box<jpeg_decompress_struct> = Box::new(std::mem::zeroed());
+            let mut dst: Box<jpeg_compress_struct> = Box::new(std::mem::zeroed());
+            src.common.err = &mut *errors[0];
+            dst.common.err = &mut *errors[1];
+            jpeg_create_decompress(&mut *src);
+            jpeg_create_compress(&mut *dst);
+            Self { src, dst, output: std::ptr::null_mut(), _errors: errors }
+        }
+    }
+}
+
+#[cfg(feature = "mozjpeg")]
+impl Drop for JpegTranscoder {
+    fn drop(&mut self) {
+        unsafe {
+            mozjpeg_sys::jpeg_destroy_decompress(&mut self.src);
+            mozjpeg_sys::jpeg_destroy_compress(&mut self.dst);
+            if !self.output.is_null() {
+                free(self.output.cast());
+            }
+        }
+    }
+}
+
+/// Rewrites a JPEG through libjpeg's coefficient API, like `jpegtran`: the quantized DCT
+/// coefficients are copied without decoding, turned `rotate` degrees clockwise if set,
+/// and written with Huffman tables optimized for them, as one baseline scan or as
+/// progressive scans. `keep_marker` picks which APPn/COM segments (by marker and body)
+/// carry over; libjpeg writes the JFIF and Adobe headers itself.
+#[cfg(feature = "mozjpeg")]
+fn transcode_jpeg(
+    data: &[u8],
+    rotate: Option<u32>,
+    progressive: bool,
+    keep_marker: &dyn Fn(u8, &[u8]) -> bool,
+) -> Result<Vec<u8>, OptimizeError> {
+    use mozjpeg_sys::*;
+
+    let transcode = || unsafe {
+        let mut jpeg = JpegTranscoder::new();
+        let (src, dst) = (&mut *jpeg.src, &mut *jpeg.dst);
+        jpeg_mem_src(src, data.as_ptr(), data.len() as _);
+        jpeg_save_markers(src, 0xFE, 0xFFFF);
+        for app in 0xE0..=0xEF {
+            jpeg_save_markers(src, app, 0xFFFF);
+        }
+        jpeg_read_header(src, 1);
+
+        let mut transform: jpeg_transform_info = std::mem::zeroed();
+        if let Some(degrees) = rotate {
+            transform.transform = match degrees {
+                90 => JXFORM_CODE_JXFORM_ROT_90,
+                180 => JXFORM_CODE_JXFORM_ROT_180,
+                _ => JXFORM_CODE_JXFORM_ROT_270,
+            };
+            // Refuse instead of trimming partial edge blocks
+            transform.perfect = 1;
+            if jtransform_request_workspace(src, &mut transform) == 0 {
+                return Err(OptimizeError::invalid("the image size isn't a multiple of the JPEG block size"));
+            }
+        }
+        let src_coefficients = jpeg_read_coefficients(src);
+        jpeg_copy_critical_parameters(src, dst);
+        let dst_coefficients = match rotate {
+            Some(_) => jtransform_adjust_parameters(src, dst, src_coefficients, &mut transform),
+            None => src_coefficients,
+        };
+
+        dst.optimize_coding = 1;
+        if progressive {
+            jpeg_simple_progression(dst);
+        } else {
+            // mozjpeg's defaults are progressive; go back to a single sequential scan
+            jpeg_c_set_bool_param(dst, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, 0);
+            dst.num_scans = 0;
+            dst.scan_info = std::ptr::null();
+        }
+
+        let mut size = 0;
+        jpeg_mem_dest(dst, &mut jpeg.output, &mut size);
+        jpeg_write_coefficients(dst, dst_coefficients);
+        let mut marker = src.marker_list;
+        while let Some(saved) = marker.as_ref() {
+            let body = std::slice::from_raw_parts(saved.data, saved.data_length as usize);
+            let header = (saved.marker == 0xE0 && body.starts_with(b"JFIF\0"))
+                || (saved.marker == 0xEE && body.starts_with(b"Adobe"));
+            if !header && keep_marker(saved.marker, body) {
+                jpeg_write_marker(dst, saved.marker as _, saved.data, saved.data_length);
+            }
+            marker = saved.next;
+        }
+        if rotate.is_some() {
+            jtransform_execute_transform(src, dst, src_coefficients, &mut transform);
+        }
+        jpeg_finish_compress(dst);
+        jpeg_finish_decompress(src);
+        Ok(std::slice::from_raw_parts(jpeg.output, size as usize).to_vec())
+    };
+
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(transcode)).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<String>().map_or("libjpeg failed", String::as_str);
+        Err(OptimizeError::decode(message))
+    })
+}
+
+/// Lossless JPEG optimization, like `jpegtran -optimize -copy none`: drops metadata
+/// segments and rebuilds the Huffman tables (optionally as progressive scans). The DCT
+/// coefficients are copied untouched, so decoded pixels are identical.
+#[cfg(feature = "mozjpeg")]
+fn optimize_jpeg_lossless(data: &[u8], keep_icc: bool, keep_exif: bool, progressive: bool) -> Result<Vec<u8>, OptimizeError> {
+    let keep = |marker: u8, body: &[u8]| match marker {
+        0xE1 => keep_exif && body.starts_with(b"Exif\0\0"),
+        0xE2 => keep_icc && body.starts_with(b"ICC_PROFILE\0"),
+        _ => false,
+    };
+    transcode_jpeg(data, None, progressive, &keep)
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+fn optimize_jpeg_lossless(_data: &[u8], _keep_icc: bool, _keep_exif: bool, _progressive: bool) -> Result<Vec<u8>, OptimizeError> {
+    Err(OptimizeError::UnsupportedFormat { format: "lossless jpeg (needs the `mozjpeg` feature)".to_string() })
+}
+
+/// Turns a JPEG's DCT coefficients `degrees` clockwise with libjpeg's transform code,
+/// keeping every metadata segment. Fails when partial edge blocks would have to be
+/// trimmed.
+#[cfg(feature = "mozjpeg")]
+fn rotate_jpeg_coefficients(data: &[u8], degrees: u32) -> Result<Vec<u8>, OptimizeError> {
+    transcode_jpeg(data, Some(degrees), false, &|_, _| true)
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+fn rotate_jpeg_coefficients(_data: &[u8], _degrees: u32) -> Result<Vec<u8>, OptimizeError> {
+    Err(OptimizeError::invalid("lossless rotation needs the `mozjpeg` feature"))
+}
+
+/// Outcome of `rotate_jpeg_lossless`.
+#[derive(serde::Serialize, Clone, Debug)]
+struct RotationResult {
+    path: String,
+    /// False when the file had to be decoded and re-encoded.
+    lossless: bool,
+    warnings: Vec<String>,
+}
+
+/// Quality for the re-encode fallback; high because the source is already lossy.
+const ROTATE_FALLBACK_QUALITY: u8 = 92;
+
+/// Rotates the JPEG at `path` clockwise in place. Negative angles turn the other way.
+/// The intermediate file goes to `temp_dir` when it's writable.
+fn rotate_jpeg(path: &Path, degrees: i32, temp_dir: Option<&Path>) -> Result<RotationResult, OptimizeError> {
+    let degrees = match degrees.rem_euclid(360) {
+        degrees @ (90 | 180 | 270) => degrees as u32,
+        _ => return Err(OptimizeError::invalid("Rotation must be a multiple of 90 degrees")),
+    };
+    let file_path = path_string(path)?;
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: file_path });
+    }
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    if !matches!(extension.as_str(), "jpg" | "jpeg") {
+        return Err(OptimizeError::UnsupportedFormat { format: extension });
+    }
+    let data = fs::read(path)?;
+
+    // A mirrored EXIF orientation makes a clockwise pixel rotation look counter-clockwise
+    let orientation = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(&data))
+        .ok()
+        .and_then(|mut decoder| decoder.orientation().ok());
+    let mirrored = matches!(
+        orientation,
+        Some(
+            image::metadata::Orientation::FlipHorizontal
+                | image::metadata::Orientation::FlipVertical
+                | image::metadata::Orientation::Rotate90FlipH
+                | image::metadata::Orientation::Rotate270FlipH
+        )
+    );
+    let mut warnings = Vec::new();
+    let (output, lossless) = match rotate_jpeg_coefficients(&data, if mirrored { 360 - degrees } else { degrees }) {
+        Ok(output) => (output, true),
+        Err(reason) => {
+            warnings.push(format!("Re-encoded at quality {} because {}", ROTATE_FALLBACK_QUALITY, reason));
+            // `decode_image` applies the EXIF orientation, so this rotates what the user sees
+            let img = decode_image(&data, &extension)?;
+            let img = match degrees {
+                90 => img.rotate90(),
+                180 => img.rotate180(),
+                _ => img.rotate270(),
+            };
+            let pixels = jpeg_pixels(&img, DEFAULT_BACKGROUND_COLOR);
+            let mut output = Vec::new();
+            encode_jpeg(
+                &mut output,
+                pixels.as_bytes(),
+                pixels.width(),
+                pixels.height(),
+                pixels.color(),
+                ROTATE_FALLBACK_QUALITY,
+                false,
+                ChromaSubsampling::Ratio420,
+                JpegBackend::Image,
+            )?;
+            match read_icc_profile(&data)? {
+                Some(icc) => (embed_jpeg_icc(&output, &icc)?, false),
+                None => (output, false),
+            }
+        }
+    };
+
+    let temp_path = writable_temp_dir(temp_dir, &mut warnings).join(format!("rotate_{}.jpg", uuid::Uuid::new_v4()));
+    fs::write(&temp_path, &output)?;
+    write_output(&temp_path, path, false, false)?;
+    Ok(RotationResult { path: file_path, lossless, warnings })
+}
+
+/// Rotates a JPEG by 90, 180 or 270 degrees clockwise without re-compression when the
+/// block layout allows it, otherwise by re-encoding with a warning.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn rotate_jpeg_lossless(app_handle: tauri::AppHandle, path: String, degrees: i32) -> Result<RotationResult, OptimizeError> {
+    let temp_dir = app_handle.state::<std::sync::Mutex<AppConfig>>().lock().unwrap().temp_dir.clone();
+    tauri::async_runtime::spawn_blocking(move || rotate_jpeg(Path::new(&path), degrees, temp_dir.as_deref().map(Path::new)))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+const DEFAULT_BACKGROUND_COLOR: [u8; 3] = [255, 255, 255];
+
+/// Alpha-composites the image over a solid background for formats without transparency.
+fn flatten_alpha(img: &image::DynamicImage, background: [u8; 3]) -> image::RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+
+    let rgba = img.to_rgba8();
+    let mut rgb = image::RgbImage::new(rgba.width(), rgba.height());
+    for (dst, src) in rgb.pixels_mut().zip(rgba.pixels()) {
+        let alpha = src[3] as u32;
+        for ((d, &s), &bg) in dst.0.iter_mut().zip(&src.0[..3]).zip(&background) {
+            let blended = s as u32 * alpha + bg as u32 * (255 - alpha);
+            *d = ((blended + 127) / 255) as u8;
+        }
+    }
+    rgb
+}
+
+/// Downsamples 16-bit and float images to 8 bits per channel, keeping the channel layout.
+fn to_8bit(img: image::DynamicImage) -> image::DynamicImage {
+    use image::DynamicImage::*;
+    match img {
+        ImageLuma16(_) => ImageLuma8(img.to_luma8()),
+        ImageLumaA16(_) => ImageLumaA8(img.to_luma_alpha8()),
+        ImageRgb16(_) | ImageRgb32F(_) => ImageRgb8(img.to_rgb8()),
+        ImageRgba16(_) | ImageRgba32F(_) => ImageRgba8(img.to_rgba8()),
+        img => img,
+    }
+}
+
+/// Maps linear floating-point pixels (what HDR and EXR decode to) to 8-bit sRGB: scale
+/// by `2^exposure` stops, roll off highlights with Reinhard's `x / (1 + x)`, then apply
+/// the sRGB transfer curve. Other images pass through untouched.
+fn tonemap(img: image::DynamicImage, exposure: f32) -> image::DynamicImage {
+    let scale = 2f32.powf(exposure);
+    let map = |v: f32| {
+        let v = (v * scale).max(0.0);
+        let v = v / (1.0 + v);
+        let v = if v <= 0.003_130_8 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+        (v * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    match img {
+        image::DynamicImage::ImageRgb32F(buffer) => {
+            let (width, height) = buffer.dimensions();
+            let pixels = buffer.pixels().flat_map(|p| p.0.map(map)).collect();
+            image::DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, pixels).expect("buffer size matches"))
+        }
+        image::DynamicImage::ImageRgba32F(buffer) => {
+            let (width, height) = buffer.dimensions();
+            // Alpha is coverage, not light, so it is only clamped
+            let pixels = buffer
+                .pixels()
+                .flat_map(|p| [map(p[0]), map(p[1]), map(p[2]), (p[3].clamp(0.0, 1.0) * 255.0).round() as u8])
+                .collect();
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_raw(width, height, pixels).expect("buffer size matches"))
+        }
+        img => img,
+    }
+}
+
+/// Converts to 8-bit luminance, keeping an alpha channel if there is one.
+fn to_grayscale(img: image::DynamicImage) -> image::DynamicImage {
+    if img.color().has_alpha() {
+        image::DynamicImage::ImageLumaA8(img.to_luma_alpha8())
+    } else {
+        image::DynamicImage::ImageLuma8(img.to_luma8())
+    }
+}
+
+/// Share of pixels at each end of the histogram `stretch_contrast` lets clip, so a few
+/// specks of dust or glare don't pin the range.
+const CONTRAST_CLIP: f64 = 0.005;
+
+/// Linear histogram stretch on luminance: maps the `CONTRAST_CLIP` percentiles to 0 and
+/// 255 and applies the same curve to every color channel, so hues don't shift. Alpha is
+/// left alone; the result is 8-bit.
+fn stretch_contrast(img: image::DynamicImage) -> image::DynamicImage {
+    let mut histogram = [0u64; 256];
+    for pixel in img.to_luma8().pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    let clip = (img.width() as u64 * img.height() as u64) as f64 * CONTRAST_CLIP;
+    let past_clip = || {
+        let mut seen = 0;
+        move |&level: &usize| {
+            seen += histogram[level];
+            seen as f64 > clip
+        }
+    };
+    let (Some(low), Some(high)) = ((0..256).find(past_clip()), (0..256).rev().find(past_clip())) else {
+        return img;
+    };
+    if high <= low || (low == 0 && high == 255) {
+        return img;
+    }
+    let lut: Vec<u8> = (0..256)
+        .map(|v: usize| (v.saturating_sub(low) * 255 / (high - low)).min(255) as u8)
+        .collect();
+
+    let mut img = to_8bit(img);
+    let color_channels = match img.color() {
+        image::ColorType::L8 | image::ColorType::La8 => 1,
+        _ => 3,
+    };
+    let channels = img.color().channel_count() as usize;
+    let samples: &mut [u8] = match &mut img {
+        image::DynamicImage::ImageLuma8(buffer) => buffer,
+        image::DynamicImage::ImageLumaA8(buffer) => buffer,
+        image::DynamicImage::ImageRgb8(buffer) => buffer,
+        image::DynamicImage::ImageRgba8(buffer) => buffer,
+        _ => unreachable!("to_8bit only returns 8-bit pixels"),
+    };
+    for pixel in samples.chunks_mut(channels) {
+        for sample in &mut pixel[..color_channels] {
+            *sample = lut[*sample as usize];
+        }
+    }
+    img
+}
+
+/// Pixels the JPEG encoders accept: 8-bit grayscale for gray sources, otherwise
+/// 8-bit RGB; any alpha is composited over `background`.
+fn jpeg_pixels(img: &image::DynamicImage, background: [u8; 3]) -> image::DynamicImage {
+    use image::ColorType::*;
+    match img.color() {
+        L8 | L16 => image::DynamicImage::ImageLuma8(img.to_luma8()),
+        La8 | La16 => {
+            let flattened = image::DynamicImage::ImageRgb8(flatten_alpha(img, background));
+            image::DynamicImage::ImageLuma8(flattened.to_luma8())
+        }
+        _ => image::DynamicImage::ImageRgb8(flatten_alpha(img, background)),
+    }
+}
+
+fn exceeds_bounds(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>) -> bool {
+    max_width.is_some_and(|w| width > w) || max_height.is_some_and(|h| height > h)
+}
+
+/// Brightness difference below which the unsharp mask leaves a pixel alone, so flat
+/// areas and sensor noise aren't amplified.
+const SHARPEN_THRESHOLD: i32 = 2;
+
+/// Downscales the image to fit within the given bounds, preserving aspect ratio.
+/// Images already within bounds are returned untouched (never upscaled). `sharpen`
+/// is the unsharp-mask sigma applied only when a resize happened.
+fn fit_within(
+    img: image::DynamicImage,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    filter: FilterType,
+    sharpen: Option<f32>,
+    resized: &mut bool,
+) -> image::DynamicImage {
+    if !exceeds_bounds(img.width(), img.height(), max_width, max_height) {
+        return img;
+    }
+
+    *resized = true;
+    let bound_width = max_width.unwrap_or(img.width()).min(img.width());
+    let bound_height = max_height.unwrap_or(img.height()).min(img.height());
+    let img = img.resize(bound_width, bound_height, filter);
+    match sharpen {
+        Some(sigma) => img.unsharpen(sigma, SHARPEN_THRESHOLD),
+        None => img,
+    }
+}
+
+/// Encoder speed passed to the GIF quantizer (1 = best, 30 = fastest).
+const GIF_QUANTIZE_SPEED: i32 = 10;
+
+/// Reads the loop count from the NETSCAPE extension, which precedes the first frame.
+fn read_gif_repeat(data: &[u8]) -> Result<gif::Repeat, OptimizeError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(OptimizeError::decode)?;
+    decoder.next_frame_info().map_err(OptimizeError::decode)?;
+    Ok(decoder.repeat())
+}
+
+/// Decodes every frame of a GIF as a fully composited canvas, merging consecutive
+/// identical frames into one with the combined delay (in milliseconds).
+fn decode_gif_frames(data: &[u8]) -> Result<Vec<(image::RgbaImage, u32)>, OptimizeError> {
+    let decoder = GifDecoder::new(std::io::Cursor::new(data)).map_err(OptimizeError::decode)?;
+    let frames = decoder.into_frames().collect_frames().map_err(OptimizeError::decode)?;
+
+    let mut merged: Vec<(image::RgbaImage, u32)> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = numer / denom.max(1);
+        let buffer = frame.into_buffer();
+        match merged.last_mut() {
+            Some((previous, previous_delay)) if *previous == buffer => *previous_delay += delay_ms,
+            _ => merged.push((buffer, delay_ms)),
+        }
+    }
+
+    if merged.is_empty() {
+        return Err(OptimizeError::decode("GIF contains no frames"));
+    }
+    Ok(merged)
+}
+
+/// Crops `current` to the area that differs from `previous`, leaving unchanged pixels
+/// transparent. Returns `None` when a pixel turns transparent, which a frame drawn on
+/// top of the previous one cannot express.
+fn changed_region(previous: &image::RgbaImage, current: &image::RgbaImage) -> Option<(u32, u32, image::RgbaImage)> {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in current.enumerate_pixels() {
+        if pixel == previous.get_pixel(x, y) {
+            continue;
+        }
+        if pixel[3] == 0 {
+            return None;
+        }
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+
+    if min_x == u32::MAX {
+        return Some((0, 0, image::RgbaImage::new(1, 1)));
+    }
+
+    let region = image::RgbaImage::from_fn(max_x - min_x + 1, max_y - min_y + 1, |x, y| {
+        let pixel = *current.get_pixel(min_x + x, min_y + y);
+        if pixel == *previous.get_pixel(min_x + x, min_y + y) {
+            image::Rgba([0, 0, 0, 0])
+        } else {
+            pixel
+        }
+    });
+    Some((min_x, min_y, region))
+}
+
+/// Encodes composited frames as an animated WebP, mapping GIF delays to timestamps.
+fn encode_animated_webp(
+    frames: &[(image::RgbaImage, u32)],
+    repeat: gif::Repeat,
+    lossless: bool,
+    quality: f32,
+) -> Result<Vec<u8>, OptimizeError> {
+    let (width, height) = frames[0].0.dimensions();
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| OptimizeError::encode("failed to initialise WebP encoder config"))?;
+    config.lossless = lossless as i32;
+    config.quality = quality;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    // WebP counts total plays (0 = forever); GIF counts repeats after the first play
+    encoder.set_loop_count(match repeat {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(count) => count as i32 + 1,
+    });
+
+    let mut timestamp: i32 = 0;
+    for (buffer, delay_ms) in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(buffer, width, height, timestamp));
+        timestamp = timestamp.saturating_add(*delay_ms as i32);
+    }
+
+    let data = encoder
+        .try_encode()
+        .map_err(|e| OptimizeError::encode(format!("{:?}", e)))?;
+    Ok(data.to_vec())
+}
+
+/// Re-encodes a GIF frame by frame, keeping per-frame delays and the loop count.
+fn optimize_gif(data: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    let repeat = read_gif_repeat(data)?;
+    let frames = decode_gif_frames(data)?;
+    encode_gif_frames(&frames, repeat)
+}
+
+/// Encodes composited frames as a GIF. Each frame after the first only stores the
+/// region that changed.
+fn encode_gif_frames(frames: &[(image::RgbaImage, u32)], repeat: gif::Repeat) -> Result<Vec<u8>, OptimizeError> {
+    let (width, height) = frames[0].0.dimensions();
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(OptimizeError::encode("GIF dimensions exceed 65535 pixels"));
+    };
+
+    let mut encoded: Vec<gif::Frame<'static>> = Vec::with_capacity(frames.len());
+    for (index, (buffer, delay_ms)) in frames.iter().enumerate() {
+        let delta = match index {
+            0 => None,
+            _ => changed_region(&frames[index - 1].0, buffer),
+        };
+        let (left, top, mut region) = match delta {
+            Some(delta) => delta,
+            None => {
+                // Clear the previous frame so a full redraw can introduce transparency
+                if let Some(previous) = encoded.last_mut() {
+                    previous.dispose = gif::DisposalMethod::Background;
+                }
+                (0, 0, buffer.clone())
+            }
+        };
+
+        let mut frame = gif::Frame::from_rgba_speed(
+            region.width() as u16,
+            region.height() as u16,
+            &mut region,
+            GIF_QUANTIZE_SPEED,
+        );
+        frame.left = left as u16;
+        frame.top = top as u16;
+        frame.delay = ((delay_ms + 5) / 10).min(u16::MAX as u32) as u16;
+        frame.dispose = gif::DisposalMethod::Keep;
+        encoded.push(frame);
+    }
+
+    let mut output = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut output, width, height, &[]).map_err(OptimizeError::encode)?;
+    encoder.set_repeat(repeat).map_err(OptimizeError::encode)?;
+    for frame in &encoded {
+        encoder.write_frame(frame).map_err(OptimizeError::encode)?;
+    }
+    drop(encoder);
+    Ok(output)
+}
+
+/// Every frame of an animated GIF, PNG or WebP as a full canvas (the decoders apply
+/// disposal and blending), with its delay in milliseconds. Stills give a single frame.
+fn decode_animation_frames(data: &[u8]) -> Result<Vec<(image::RgbaImage, u32)>, OptimizeError> {
+    let cursor = std::io::Cursor::new(data);
+    let animated = match image::guess_format(data).map_err(OptimizeError::decode)? {
+        image::ImageFormat::Gif => Some(GifDecoder::new(cursor).map_err(OptimizeError::decode)?.into_frames()),
+        image::ImageFormat::Png => {
+            let decoder = image::codecs::png::PngDecoder::new(cursor).map_err(OptimizeError::decode)?;
+            if decoder.is_apng().map_err(OptimizeError::decode)? {
+                Some(decoder.apng().map_err(OptimizeError::decode)?.into_frames())
+            } else {
+                None
+            }
+        }
+        image::ImageFormat::WebP => {
+            let decoder = image::codecs::webp::WebPDecoder::new(cursor).map_err(OptimizeError::decode)?;
+            decoder.has_animation().then(|| decoder.into_frames())
+        }
+        _ => None,
+    };
+    let Some(frames) = animated else {
+        return Ok(vec![(decode_image(data, "")?.into_rgba8(), 0)]);
+    };
+
+    let frames = frames.collect_frames().map_err(OptimizeError::decode)?;
+    if frames.is_empty() {
+        return Err(OptimizeError::decode("Animation contains no frames"));
+    }
+    Ok(frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            (frame.into_buffer(), numer / denom.max(1))
+        })
+        .collect())
+}
+
+/// Writes each frame of `path` to `output_dir` as `<stem>_001.png`, `<stem>_002.png`, ...
+/// without overwriting existing files, and returns the paths in frame order.
+fn write_frames(path: &Path, output_dir: &Path) -> Result<Vec<String>, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let frames = decode_animation_frames(&fs::read(path)?)?;
+    fs::create_dir_all(output_dir)?;
+
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("frame");
+    let digits = frames.len().to_string().len().max(3);
+    frames
+        .iter()
+        .enumerate()
+        .map(|(index, (buffer, _))| {
+            let name = format!("{}_{:0width$}.png", stem, index + 1, width = digits);
+            let target = next_free_path(output_dir.join(name), path);
+            buffer.save_with_format(&target, image::ImageFormat::Png).map_err(OptimizeError::encode)?;
+            path_string(&target)
+        })
+        .collect()
+}
+
+/// Builds an animated GIF or WebP at `output` from still frames shown for `delays_ms`
+/// each. `loop_count` is the number of plays; unset or 0 loops forever.
+fn write_animation(
+    frames: &[String],
+    delays_ms: &[u32],
+    loop_count: Option<u16>,
+    output: &Path,
+    format: &str,
+) -> Result<(), OptimizeError> {
+    if frames.is_empty() {
+        return Err(OptimizeError::invalid("An animation needs at least one frame"));
+    }
+    if frames.len() != delays_ms.len() {
+        return Err(OptimizeError::invalid(format!(
+            "Got {} frames but {} delays",
+            frames.len(),
+            delays_ms.len()
+        )));
+    }
+    let format = format.to_lowercase();
+    if !matches!(format.as_str(), "gif" | "webp") {
+        return Err(OptimizeError::UnsupportedFormat { format });
+    }
+
+    let mut buffers: Vec<(image::RgbaImage, u32)> = Vec::with_capacity(frames.len());
+    for (frame, &delay_ms) in frames.iter().zip(delays_ms) {
+        let buffer = load_image(Path::new(frame))?.into_rgba8();
+        if let Some((first, _)) = buffers.first() {
+            if buffer.dimensions() != first.dimensions() {
+                return Err(OptimizeError::invalid(format!(
+                    "{} is {}x{}, but the first frame is {}x{}",
+                    frame,
+                    buffer.width(),
+                    buffer.height(),
+                    first.width(),
+                    first.height()
+                )));
+            }
+        }
+        buffers.push((buffer, delay_ms));
+    }
+
+    // GIF counts repeats after the first play
+    let repeat = match loop_count {
+        None | Some(0) => gif::Repeat::Infinite,
+        Some(plays) => gif::Repeat::Finite(plays - 1),
+    };
+    let data = match format.as_str() {
+        "gif" => encode_gif_frames(&buffers, repeat)?,
+        // Frames are usually exports of an existing animation, so don't add loss
+        _ => encode_animated_webp(&buffers, repeat, true, DEFAULT_WEBP_QUALITY)?,
+    };
+    fs::write(output, data)?;
+    Ok(())
+}
+
+/// Assembles ordered still frames into an animated GIF or WebP, the inverse of
+/// `extract_frames`.
+#[tauri::command]
+#[tracing::instrument(skip(frames, delays_ms), err)]
+async fn assemble_animation(
+    frames: Vec<String>,
+    delays_ms: Vec<u32>,
+    loop_count: Option<u16>,
+    output: String,
+    format: String,
+) -> Result<String, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        write_animation(&frames, &delays_ms, loop_count, Path::new(&output), &format)?;
+        Ok(output)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Splits an animated GIF, PNG or WebP into numbered PNGs of the fully composited frames.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn extract_frames(path: String, output_dir: String) -> Result<Vec<String>, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || write_frames(Path::new(&path), Path::new(&output_dir)))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+#[derive(serde::Serialize, Clone)]
+struct OptimizationResult {
+    original_size: u64,
+    new_size: u64,
+    saved_bytes: u64,
+    output_path: String,
+    skipped: bool,
+    duration_ms: u64,
+    backup_path: Option<String>,
+    /// Quality picked by `auto_quality`, if it ran.
+    chosen_quality: Option<u8>,
+    /// Non-fatal issues, e.g. an ICC profile the output format couldn't carry.
+    warnings: Vec<String>,
+    /// "lossless" or "lossy", whichever `webp-smart` kept.
+    webp_mode: Option<String>,
+    /// With `png_timeout_ms`: "preset", "timeout", "fast", "reencode" or "none" (see
+    /// `oxipng_with_timeout`).
+    png_strategy: Option<String>,
+    /// Output extension `convert_to: "auto"` settled on.
+    chosen_format: Option<String>,
+}
+
+/// Per-run settings shared by `optimize_image` and the batch commands.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+struct OptimizeOptions {
+    overwrite: bool,
+    convert_to: Option<String>,
+    quality_step: Option<u32>,
+    jpeg_quality: Option<u8>,
+    png_level: Option<u8>,
+    webp_quality: Option<f32>,
+    webp_lossless: Option<bool>,
+    avif_quality: Option<u8>,
+    avif_speed: Option<u8>,
+    /// JPEG XL Butteraugli distance; 0 is lossless. Needs the `jxl` feature.
+    jxl_distance: Option<f32>,
+    /// Target SSIM (0-1] for JPEG/WebP; picks the lowest quality that reaches it.
+    auto_quality: Option<f64>,
+    /// Carry the source ICC profile over to outputs that support it.
+    preserve_icc: Option<bool>,
+    /// Re-decode the written output and roll back if it isn't a valid image.
+    verify: Option<bool>,
+    /// Output file name with `{stem}`, `{ext}`, `{parent}` and `{date}` tokens.
+    output_template: Option<String>,
+    /// Folder for outputs; relative paths resolve against the source's folder.
+    output_dir: Option<String>,
+    strip_metadata: Option<bool>,
+    background_color: Option<[u8; 3]>,
+    backup: Option<bool>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    /// "nearest", "triangle", "catmullrom", "gaussian" or "lanczos3" (default) for resizing.
+    resize_filter: Option<String>,
+    /// Unsharp-mask sigma applied after a downscale (around 0.5-1.5 for thumbnails).
+    sharpen: Option<f32>,
+    jpeg_progressive: Option<bool>,
+    /// Chroma subsampling: "420" (default), "422" or "444" for sharp colored text.
+    jpeg_subsampling: Option<String>,
+    /// Same-format JPEGs: drop metadata and rebuild the Huffman tables (progressive with
+    /// `jpeg_progressive`) instead of re-encoding, so the pixels don't change. Can't be
+    /// combined with resizing or grayscale. Needs the `mozjpeg` feature.
+    jpeg_lossless: Option<bool>,
+    /// "image" (default) or "mozjpeg" when built with the `mozjpeg` feature.
+    jpeg_encoder: Option<String>,
+    preserve_timestamps: Option<bool>,
+    /// Keep the original when a cross-format conversion comes out larger.
+    never_grow: Option<bool>,
+    /// Keep the original unless the output is at least this many percent smaller.
+    min_savings_percent: Option<f32>,
+    /// Force oxipng's palette, bit-depth and color-type reductions on or off.
+    png_palette_reduction: Option<bool>,
+    /// Deadline for oxipng; past it the best result so far is kept, with the fast preset
+    /// and a plain re-encode as fallbacks when there is none.
+    png_timeout_ms: Option<u64>,
+    /// Convert to luminance (keeping alpha) before encoding.
+    grayscale: Option<bool>,
+    /// Stretch the tonal range so the darkest and brightest tones reach black and white.
+    auto_contrast: Option<bool>,
+    /// Re-encode files even if they are marked as already optimized.
+    force: Option<bool>,
+    /// Text written to a PNG `tEXt`/`iTXt` chunk or a JPEG COM segment; other formats warn.
+    comment: Option<String>,
+    /// "lzw" (default), "deflate", "packbits" or "none" for same-format TIFF output.
+    tiff_compression: Option<String>,
+    /// Hard cap on the output size: lowers quality, then dimensions, until it fits.
+    target_max_bytes: Option<u64>,
+    /// Exposure in stops applied when tonemapping HDR/EXR sources to 8-bit; 0 by default.
+    hdr_exposure: Option<f32>,
+    /// Convert uncompressed sources (BMP, TGA, PNM, farbfeld) to PNG when `convert_to` is unset.
+    auto_convert_lossless: Option<bool>,
+    /// Where intermediates are written; comes from `AppConfig.temp_dir`, not the caller.
+    #[serde(skip)]
+    temp_dir: Option<PathBuf>,
+    /// Set by `optimize_directory`'s `output_root`, not the caller.
+    #[serde(skip)]
+    mirror: Option<MirrorRoots>,
+    /// Byte size lossy WebP output aims for; set by `target_max_bytes`, not the caller.
+    #[serde(skip)]
+    webp_target_size: Option<u64>,
+    /// Called at phase boundaries; `optimize_image` sets it to emit `optimize://phase`.
+    #[serde(skip)]
+    phases: Option<PhaseReporter>,
+}
+
+/// Coarse progress for a single file: "decode", "optimize", "encode" and "write".
+#[derive(Clone)]
+struct PhaseReporter(std::sync::Arc<dyn Fn(&'static str) + Send + Sync>);
+
+impl std::fmt::Debug for PhaseReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PhaseReporter")
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+struct OptimizePhase {
+    path: String,
+    phase: &'static str,
+}
+
+impl PhaseReporter {
+    /// Emits `optimize://phase` with `path` for each phase.
+    fn emitting(app_handle: tauri::AppHandle, path: String) -> Self {
+        Self(std::sync::Arc::new(move |phase| {
+            let _ = app_handle.emit("optimize://phase", OptimizePhase { path: path.clone(), phase });
+        }))
+    }
+}
+
+/// Writes outputs under `output` at the same relative folder they have under `source`.
+#[derive(Clone, Debug)]
+struct MirrorRoots {
+    source: PathBuf,
+    output: PathBuf,
+}
+
+impl MirrorRoots {
+    /// Output folder for `path`, which must lie under the source root.
+    fn dir_for(&self, path: &Path) -> Result<PathBuf, OptimizeError> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let relative = parent.strip_prefix(&self.source).map_err(|_| {
+            OptimizeError::invalid(format!("{} is not inside {}", path.display(), self.source.display()))
+        })?;
+        Ok(self.output.join(relative))
+    }
+}
+
+/// Uncompressed formats that `auto_convert_lossless` turns into PNG.
+const AUTO_CONVERT_LOSSLESS_EXTENSIONS: [&str; 4] = ["bmp", "tga", "pnm", "ff"];
+
+impl OptimizeOptions {
+    /// Applies `auto_convert_lossless`: uncompressed sources with no explicit `convert_to`
+    /// are converted to PNG instead of failing with `NeedsConversion`.
+    fn for_source(&self, extension: &str) -> std::borrow::Cow<'_, Self> {
+        if self.convert_to.is_none()
+            && self.auto_convert_lossless.unwrap_or(false)
+            && AUTO_CONVERT_LOSSLESS_EXTENSIONS.contains(&extension)
+        {
+            std::borrow::Cow::Owned(Self { convert_to: Some("png".to_string()), ..self.clone() })
+        } else {
+            std::borrow::Cow::Borrowed(self)
+        }
+    }
+
+    /// Fills per-format settings the caller left unset from the stored `quality_presets`,
+    /// then from the saved encoder settings, and picks up the configured temp directory.
+    fn with_config(mut self, app_handle: &tauri::AppHandle) -> Self {
+        let state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+        let config = state.lock().unwrap();
+        let presets = &config.quality_presets;
+        self.temp_dir = config.temp_dir.as_ref().map(PathBuf::from);
+
+        if self.jpeg_quality.is_none() && self.quality_step.is_none() {
+            self.jpeg_quality = Some(presets.get("jpg").copied().unwrap_or(config.jpeg_quality));
+        }
+        if self.webp_quality.is_none() {
+            self.webp_quality = Some(presets.get("webp").map_or(config.webp_quality, |&q| q as f32));
+        }
+        self.png_level = self.png_level.or(Some(config.png_level));
+        self.webp_lossless = self.webp_lossless.or(Some(config.webp_lossless));
+        self.jpeg_progressive = self.jpeg_progressive.or(Some(config.jpeg_progressive));
+        if self.avif_quality.is_none() {
+            self.avif_quality = presets.get("avif").copied();
+        }
+        if self.jxl_distance.is_none() {
+            self.jxl_distance = presets.get("jxl").map(|&q| jxl_distance_from_quality(q));
+        }
+        if self.min_savings_percent.is_none() {
+            self.min_savings_percent = config.min_savings_percent;
+        }
+        self
+    }
+
+    /// `with_config` for batch runs, which also follow the saved convert setting.
+    fn with_batch_config(self, app_handle: &tauri::AppHandle) -> Self {
+        let mut options = self.with_config(app_handle);
+        let state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+        options.apply_default_format(&state.lock().unwrap());
+        options
+    }
+
+    /// Checksum of the settings that shape the encoded output, stored in
+    /// `OptimizedMarkers` so a file is only skipped when re-run with the same ones. Where
+    /// the output goes and how it's written (paths, overwrite, backups, timestamps,
+    /// verify, force) doesn't count.
+    fn output_fingerprint(&self) -> u32 {
+        let shaping = OptimizeOptions {
+            overwrite: false,
+            output_template: None,
+            output_dir: None,
+            backup: None,
+            preserve_timestamps: None,
+            verify: None,
+            force: None,
+            ..self.clone()
+        };
+        crc32fast::hash(serde_json::to_string(&shaping).unwrap_or_default().as_bytes())
+    }
+
+    /// Tells the `phases` reporter, if any, that `phase` is starting.
+    fn report_phase(&self, phase: &'static str) {
+        if let Some(PhaseReporter(report)) = &self.phases {
+            report(phase);
+        }
+    }
+
+    /// With `convert_enabled`, files without an explicit `convert_to` go to
+    /// `convert_format`; `convert_to: "none"` keeps the source format either way.
+    fn apply_default_format(&mut self, config: &AppConfig) {
+        match self.convert_to.as_deref() {
+            Some("none") => self.convert_to = None,
+            None if config.convert_enabled => self.convert_to = Some(config.convert_format.clone()),
+            _ => {}
+        }
+    }
+}
+
+#[tauri::command]
+async fn optimize_image(
+    app_handle: tauri::AppHandle,
+    file_path: String,
+    options: OptimizeOptions,
+) -> Result<OptimizationResult, OptimizeError> {
+    let mut options = options.with_config(&app_handle);
+    options.phases = Some(PhaseReporter::emitting(app_handle.clone(), file_path.clone()));
+    // Offload the heavy lifting (and the file stat behind the skip check) to a blocking thread
+    let pool = worker_pool(&app_handle, None)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Some(result) = already_optimized(&app_handle, &file_path, &options) {
+            return Ok(result);
+        }
+        let outcome = pool.install(|| optimize_core(Path::new(&file_path), &options));
+        log_outcome(&file_path, &options, &outcome);
+        let result = outcome?;
+        // These rewrite their JSON files, so they stay on the blocking thread too
+        record_markers(&app_handle, &options, [file_path.as_str()]);
+        record_stats(&app_handle, [&result]);
+        record_history(&app_handle, [(file_path.as_str(), &result)]);
+        Ok(result)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Worker count for a pool: the smaller of the caller's request and the configured
+/// `max_concurrency`. 0 lets rayon use every logical CPU.
+fn effective_threads(requested: Option<usize>, limit: Option<usize>) -> usize {
+    match (requested, limit) {
+        (Some(requested), Some(limit)) => requested.min(limit),
+        (requested, limit) => requested.or(limit).unwrap_or(0),
+    }
+}
+
+/// The rayon pool every command shares, so `max_concurrency` caps CPU use across
+/// concurrent calls rather than per call. `update_settings` swaps in a new one when the
+/// limit changes; calls already running keep the old pool until they finish.
+struct WorkerPool(std::sync::Mutex<std::sync::Arc<rayon::ThreadPool>>);
+
+impl WorkerPool {
+    fn new(limit: Option<usize>) -> Result<Self, OptimizeError> {
+        Ok(Self(std::sync::Mutex::new(build_pool(effective_threads(None, limit))?)))
+    }
+}
+
+fn build_pool(threads: usize) -> Result<std::sync::Arc<rayon::ThreadPool>, OptimizeError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map(std::sync::Arc::new)
+        .map_err(OptimizeError::internal)
+}
+
+/// The pool optimizations run in. oxipng has no thread option of its own; it
+/// parallelizes through whichever rayon pool it's called from, so running inside this
+/// pool caps its threads too. A caller asking for fewer workers than the shared pool
+/// has gets a narrower pool of its own.
+fn worker_pool(app_handle: &tauri::AppHandle, requested: Option<usize>) -> Result<std::sync::Arc<rayon::ThreadPool>, OptimizeError> {
+    let limit = app_handle.state::<std::sync::Mutex<AppConfig>>().lock().unwrap().max_concurrency;
+    let shared = app_handle.state::<WorkerPool>().0.lock().unwrap().clone();
+    match effective_threads(requested, limit) {
+        threads if threads > 0 && threads < shared.current_num_threads() => build_pool(threads),
+        _ => Ok(shared),
+    }
+}
+
+/// Logs one file's options and outcome to the app log.
+fn log_outcome(path: &str, options: &OptimizeOptions, outcome: &Result<OptimizationResult, OptimizeError>) {
+    match outcome {
+        Ok(result) => tracing::info!(
+            path,
+            ?options,
+            output = %result.output_path,
+            original_size = result.original_size,
+            new_size = result.new_size,
+            skipped = result.skipped,
+            duration_ms = result.duration_ms,
+            "optimized"
+        ),
+        Err(e) => tracing::error!(path, ?options, error = ?e, "optimization failed"),
+    }
+}
+
+/// Adds non-skipped results to the lifetime stats and persists them. Goes through the
+/// config mutex so concurrent runs can't lose updates.
+fn record_stats<'a>(app_handle: &tauri::AppHandle, results: impl IntoIterator<Item = &'a OptimizationResult>) {
+    let state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+    let mut config = state.lock().unwrap();
+    let mut changed = false;
+    for result in results.into_iter().filter(|r| !r.skipped) {
+        config.stats.files_optimized += 1;
+        config.stats.bytes_saved += result.saved_bytes;
+        config.stats.time_ms += result.duration_ms;
+        changed = true;
+    }
+    if changed {
+        save_config(app_handle, &config);
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+struct BatchProgress {
+    index: usize,
+    completed: usize,
+    total: usize,
+    current_path: String,
+    result: OptimizationResult,
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+struct BatchSummary {
+    total: usize,
+    succeeded: usize,
+    skipped: usize,
+    /// Files that errored; the rest of the batch still runs.
+    failed: Vec<BatchFailure>,
+    original_size: u64,
+    new_size: u64,
+    saved_bytes: u64,
+    duration_ms: u64,
+    cancelled: bool,
+    completed: Vec<String>,
+    remaining: Vec<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct BatchFailure {
+    path: String,
+    error: OptimizeError,
+}
+
+/// Set by `cancel_optimization`; checked by the batch loop between (and within) files.
+#[derive(Default)]
+struct CancelFlag(AtomicBool);
+
+/// Optimizes a list of files in one invoke on a rayon pool of `max_threads` workers
+/// (defaults to the number of logical CPUs), emitting `optimize://progress` as each
+/// file finishes and `optimize://done` with the aggregate summary at the end. With
+/// `manifest_path`, a per-file report is written there as JSON (or CSV for `.csv`).
+#[tauri::command]
+async fn optimize_images(
+    app_handle: tauri::AppHandle,
+    files: Vec<String>,
+    options: OptimizeOptions,
+    max_threads: Option<usize>,
+    manifest_path: Option<String>,
+) -> Result<BatchSummary, OptimizeError> {
+    let options = options.with_batch_config(&app_handle);
+    tauri::async_runtime::spawn_blocking(move || {
+        run_batch(&app_handle, files, &options, max_threads, manifest_path.as_deref().map(Path::new))
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Blocking body of `optimize_images`, shared with `optimize_directory`.
+fn run_batch(
+    app_handle: &tauri::AppHandle,
+    files: Vec<String>,
+    options: &OptimizeOptions,
+    max_threads: Option<usize>,
+    manifest_path: Option<&Path>,
+) -> Result<BatchSummary, OptimizeError> {
+    if let Some(path) = manifest_path {
+        check_manifest_path(path)?;
+    }
+    let start_time = std::time::Instant::now();
+    let cancel_flag = app_handle.state::<CancelFlag>();
+    cancel_flag.0.store(false, Ordering::SeqCst);
+
+    // oxipng parallelizes through rayon as well; running it inside this pool keeps
+    // its internal work on the same `max_threads` workers instead of oversubscribing.
+    let pool = worker_pool(app_handle, max_threads)?;
+
+    let total = files.len();
+    let finished = AtomicUsize::new(0);
+
+    // `None` marks a file that was not processed because the batch was cancelled.
+    // Per-file errors are kept in the outcome so one bad file doesn't abort the batch.
+    let outcomes: Vec<Option<Result<OptimizationResult, OptimizeError>>> = pool.install(|| {
+        files
+            .par_iter()
+            .enumerate()
+            .map(|(index, file_path)| {
+                if cancel_flag.0.load(Ordering::SeqCst) {
+                    return None;
+                }
+
+                let outcome = match already_optimized(app_handle, file_path, options) {
+                    Some(result) => Ok(result),
+                    None => optimize_cancellable(Path::new(file_path), options, Some(&cancel_flag.0)),
+                };
+                if !matches!(outcome, Err(OptimizeError::Cancelled)) {
+                    log_outcome(file_path, options, &outcome);
+                }
+                match outcome {
+                    Ok(result) => {
+                        let completed = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                        let _ = app_handle.emit("optimize://progress", BatchProgress {
+                            index,
+                            completed,
+                            total,
+                            current_path: file_path.clone(),
+                            result: result.clone(),
+                        });
+                        Some(Ok(result))
+                    }
+                    Err(OptimizeError::Cancelled) => None,
+                    Err(error) => {
+                        finished.fetch_add(1, Ordering::SeqCst);
+                        let _ = app_handle.emit("optimize://failed", BatchFailure {
+                            path: file_path.clone(),
+                            error: error.clone(),
+                        });
+                        Some(Err(error))
+                    }
+                }
+            })
+            .collect()
+    });
+
+    let results = || outcomes.iter().flatten().filter_map(|outcome| outcome.as_ref().ok());
+    record_markers(
+        app_handle,
+        options,
+        files.iter().zip(&outcomes).filter(|(_, outcome)| matches!(outcome, Some(Ok(_)))).map(|(path, _)| path.as_str()),
+    );
+    record_stats(app_handle, results());
+    record_history(
+        app_handle,
+        files
+            .iter()
+            .zip(&outcomes)
+            .filter_map(|(path, outcome)| Some((path.as_str(), outcome.as_ref()?.as_ref().ok()?))),
+    );
+
+    let manifest = manifest_path.map(|path| (path, BatchManifest::new(options, &files, &outcomes)));
+
+    let mut summary = BatchSummary { total, ..Default::default() };
+    for (file_path, outcome) in files.into_iter().zip(outcomes) {
+        let result = match outcome {
+            Some(Ok(result)) => result,
+            Some(Err(error)) => {
+                summary.failed.push(BatchFailure { path: file_path, error });
+                continue;
+            }
+            None => {
+                summary.cancelled = true;
+                summary.remaining.push(file_path);
+                continue;
+            }
+        };
+
+        if result.skipped {
+            summary.skipped += 1;
+        } else {
+            summary.succeeded += 1;
+        }
+        summary.original_size += result.original_size;
+        summary.new_size += result.new_size;
+        summary.saved_bytes += result.saved_bytes;
+        summary.completed.push(file_path);
+    }
+
+    summary.duration_ms = start_time.elapsed().as_millis() as u64;
+    if let Some((path, manifest)) = manifest {
+        manifest.write(path)?;
+    }
+    let _ = app_handle.emit("optimize://done", summary.clone());
+    Ok(summary)
+}
+
+/// One row of a batch manifest.
+#[derive(serde::Serialize, Clone, Debug)]
+struct ManifestEntry {
+    path: String,
+    /// "optimized", "skipped", "failed" or "cancelled".
+    status: &'static str,
+    source_format: String,
+    output_format: Option<String>,
+    output_path: Option<String>,
+    original_size: Option<u64>,
+    new_size: Option<u64>,
+    saved_bytes: Option<u64>,
+    duration_ms: Option<u64>,
+    chosen_quality: Option<u8>,
+    webp_mode: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+struct ManifestTotals {
+    files: usize,
+    optimized: usize,
+    skipped: usize,
+    failed: usize,
+    cancelled: usize,
+    original_size: u64,
+    new_size: u64,
+    saved_bytes: u64,
+}
+
+/// Audit record of a batch run, written wherever the caller asks.
+#[derive(serde::Serialize, Debug)]
+struct BatchManifest<'a> {
+    generated_at: String,
+    options: &'a OptimizeOptions,
+    totals: ManifestTotals,
+    files: Vec<ManifestEntry>,
+}
+
+impl<'a> BatchManifest<'a> {
+    fn new(
+        options: &'a OptimizeOptions,
+        files: &[String],
+        outcomes: &[Option<Result<OptimizationResult, OptimizeError>>],
+    ) -> Self {
+        let extension = |path: &str| {
+            Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase()
+        };
+        let mut totals = ManifestTotals { files: files.len(), ..Default::default() };
+        let entries = files
+            .iter()
+            .zip(outcomes)
+            .map(|(path, outcome)| {
+                let mut entry = ManifestEntry {
+                    path: path.clone(),
+                    status: "cancelled",
+                    source_format: extension(path),
+                    output_format: None,
+                    output_path: None,
+                    original_size: fs::metadata(path).ok().map(|m| m.len()),
+                    new_size: None,
+                    saved_bytes: None,
+                    duration_ms: None,
+                    chosen_quality: None,
+                    webp_mode: None,
+                    error: None,
+                };
+                match outcome {
+                    Some(Ok(result)) => {
+                        entry.status = if result.skipped { "skipped" } else { "optimized" };
+                        entry.output_format = Some(extension(&result.output_path));
+                        entry.output_path = Some(result.output_path.clone());
+                        entry.original_size = Some(result.original_size);
+                        entry.new_size = Some(result.new_size);
+                        entry.saved_bytes = Some(result.saved_bytes);
+                        entry.duration_ms = Some(result.duration_ms);
+                        entry.chosen_quality = result.chosen_quality;
+                        entry.webp_mode = result.webp_mode.clone();
+                        totals.original_size += result.original_size;
+                        totals.new_size += result.new_size;
+                        totals.saved_bytes += result.saved_bytes;
+                    }
+                    Some(Err(error)) => {
+                        entry.status = "failed";
+                        entry.error = Some(error.to_string());
+                    }
+                    None => {}
+                }
+                match entry.status {
+                    "optimized" => totals.optimized += 1,
+                    "skipped" => totals.skipped += 1,
+                    "failed" => totals.failed += 1,
+                    _ => totals.cancelled += 1,
+                }
+                entry
+            })
+            .collect();
+        Self { generated_at: Local::now().to_rfc3339(), options, totals, files: entries }
+    }
+
+    /// One header row plus one row per file; run-wide options only go into the JSON form.
+    fn to_csv(&self) -> String {
+        fn field(value: &str) -> String {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_string()
+            }
+        }
+        fn number<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+
+        let mut csv = String::from(
+            "path,status,source_format,output_format,output_path,original_size,new_size,saved_bytes,duration_ms,chosen_quality,webp_mode,error\n",
+        );
+        for entry in &self.files {
+            let row = [
+                field(&entry.path),
+                entry.status.to_string(),
+                field(&entry.source_format),
+                field(entry.output_format.as_deref().unwrap_or("")),
+                field(entry.output_path.as_deref().unwrap_or("")),
+                number(entry.original_size),
+                number(entry.new_size),
+                number(entry.saved_bytes),
+                number(entry.duration_ms),
+                number(entry.chosen_quality),
+                field(entry.webp_mode.as_deref().unwrap_or("")),
+                field(entry.error.as_deref().unwrap_or("")),
+            ];
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Writes CSV for a `.csv` path and pretty JSON otherwise.
+    fn write(&self, path: &Path) -> Result<(), OptimizeError> {
+        let is_csv = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+        let content = if is_csv {
+            self.to_csv()
+        } else {
+            serde_json::to_string_pretty(self).map_err(OptimizeError::internal)?
+        };
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// Fails early, before any file is touched, if the manifest couldn't be written afterwards.
+fn check_manifest_path(path: &Path) -> Result<(), OptimizeError> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !parent.is_dir() {
+        return Err(OptimizeError::invalid(format!("Manifest folder does not exist: {}", parent.display())));
+    }
+    Ok(())
+}
+
+/// Scans `root` with the same filters as `scan_directory`, then optimizes every match
+/// like `optimize_images`, emitting the same progress events and writing the same
+/// optional manifest. Returns the batch summary.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(app_handle, options), err)]
+async fn optimize_directory(
+    app_handle: tauri::AppHandle,
+    root: String,
+    options: OptimizeOptions,
+    include_globs: Option<Vec<String>>,
+    exclude_globs: Option<Vec<String>>,
+    max_depth: Option<usize>,
+    follow_symlinks: Option<bool>,
+    max_threads: Option<usize>,
+    manifest_path: Option<String>,
+    output_root: Option<String>,
+) -> Result<BatchSummary, OptimizeError> {
+    if !Path::new(&root).is_dir() {
+        return Err(OptimizeError::FileNotFound { path: root });
+    }
+    remember_folders(&app_handle, std::slice::from_ref(&root));
+    let mut options = options.with_batch_config(&app_handle);
+    if let Some(output_root) = output_root {
+        options.mirror = Some(mirror_roots(Path::new(&root), Path::new(&output_root), &options)?);
+    }
+    let filter = ScanFilter::new(
+        &include_globs.unwrap_or_default(),
+        &exclude_globs.unwrap_or_default(),
+    )?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let scan = scan_paths(vec![root], &filter, max_depth, follow_symlinks.unwrap_or(false))?;
+        let files = scan.files.into_iter().map(|entry| entry.path).collect();
+        run_batch(&app_handle, files, &options, max_threads, manifest_path.as_deref().map(Path::new))
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Checks an `output_root` for `optimize_directory` and creates it. It has to be a
+/// separate tree so the originals are never written to.
+fn mirror_roots(root: &Path, output_root: &Path, options: &OptimizeOptions) -> Result<MirrorRoots, OptimizeError> {
+    if options.output_dir.is_some() {
+        return Err(OptimizeError::invalid("output_root and output_dir can't be combined"));
+    }
+    if !output_root.is_absolute() {
+        return Err(OptimizeError::invalid("output_root must be an absolute path"));
+    }
+    fs::create_dir_all(output_root)?;
+    let (source, output) = (root.canonicalize()?, output_root.canonicalize()?);
+    if output.starts_with(&source) || source.starts_with(&output) {
+        return Err(OptimizeError::invalid("output_root must not overlap the source folder"));
+    }
+    Ok(MirrorRoots { source: root.to_path_buf(), output: output_root.to_path_buf() })
+}
+
+/// Source size and projected output size (`None` when the original would be kept).
+type Projection = (u64, Option<u64>);
+
+/// Encodes `path` in memory exactly as an optimization would, without writing anything.
+/// Returns the source size and the output size, or `None` when the original would be kept.
+fn project_file(path: &Path, options: &OptimizeOptions) -> Result<Projection, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let original_size = fs::metadata(path)?.len();
+    if original_size == 0 {
+        return Err(OptimizeError::EmptyFile { path: path.to_string_lossy().to_string() });
+    }
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let options = &*options.for_source(&extension);
+
+    let source = fs::read(path)?;
+    let (target_extension, encoded) = encode_to_target(&source, &extension, options)?;
+    let new_size = encoded.data.len() as u64;
+    let keep = should_keep_original(original_size, new_size, &extension, target_extension, options, encoded.resized);
+    Ok((original_size, (!keep).then_some(new_size)))
+}
+
+/// Projected result of optimizing a whole tree.
+#[derive(serde::Serialize, Clone, Debug, Default)]
+struct DirectorySavings {
+    current_total: u64,
+    projected_total: u64,
+    files: usize,
+    /// Files that would keep their original bytes, failures included.
+    skipped: usize,
+    failed: Vec<BatchFailure>,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct ProjectionProgress {
+    completed: usize,
+    total: usize,
+    current_path: String,
+}
+
+/// Dry-runs every file on the current rayon pool and adds up the sizes. `on_progress`
+/// gets each finished path; a set `cancel` flag stops the run with `Cancelled`.
+fn project_files(
+    files: &[String],
+    options: &OptimizeOptions,
+    cancel: &AtomicBool,
+    on_progress: impl Fn(&str) + Sync,
+) -> Result<DirectorySavings, OptimizeError> {
+    let outcomes: Vec<Option<Result<Projection, OptimizeError>>> = files
+        .par_iter()
+        .map(|path| {
+            if cancel.load(Ordering::SeqCst) {
+                return None;
+            }
+            let outcome = project_file(Path::new(path), options);
+            on_progress(path);
+            Some(outcome)
+        })
+        .collect();
+    if cancel.load(Ordering::SeqCst) {
+        return Err(OptimizeError::Cancelled);
+    }
+
+    let mut savings = DirectorySavings { files: files.len(), ..Default::default() };
+    for (path, outcome) in files.iter().zip(outcomes.into_iter().flatten()) {
+        match outcome {
+            Ok((current, projected)) => {
+                savings.current_total += current;
+                savings.projected_total += projected.unwrap_or(current);
+                if projected.is_none() {
+                    savings.skipped += 1;
+                }
+            }
+            Err(error) => {
+                // A file that can't be optimized stays as it is
+                let current = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                savings.current_total += current;
+                savings.projected_total += current;
+                savings.skipped += 1;
+                savings.failed.push(BatchFailure { path: path.clone(), error });
+            }
+        }
+    }
+    Ok(savings)
+}
+
+/// Analyzes a tree before committing to it: dry-runs every supported file under `root`
+/// with `options` and reports current vs. projected total size. Emits
+/// `project://progress` per file and stops on `cancel_optimization`.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, options), err)]
+async fn project_directory_savings(
+    app_handle: tauri::AppHandle,
+    root: String,
+    options: OptimizeOptions,
+    max_threads: Option<usize>,
+) -> Result<DirectorySavings, OptimizeError> {
+    if !Path::new(&root).is_dir() {
+        return Err(OptimizeError::FileNotFound { path: root });
+    }
+    let options = options.with_config(&app_handle);
+    tauri::async_runtime::spawn_blocking(move || {
+        let scan = scan_paths(vec![root], &ScanFilter::new(&[], &[])?, None, false)?;
+        let files: Vec<String> = scan.files.into_iter().map(|entry| entry.path).collect();
+        let cancel_flag = app_handle.state::<CancelFlag>();
+        cancel_flag.0.store(false, Ordering::SeqCst);
+
+        let total = files.len();
+        let completed = AtomicUsize::new(0);
+        let pool = worker_pool(&app_handle, max_threads)?;
+        pool.install(|| {
+            project_files(&files, &options, &cancel_flag.0, |path| {
+                let _ = app_handle.emit("project://progress", ProjectionProgress {
+                    completed: completed.fetch_add(1, Ordering::SeqCst) + 1,
+                    total,
+                    current_path: path.to_string(),
+                });
+            })
+        })
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// 64-bit difference hash: the image shrunk to 9x8 gray, one bit per pixel that's
+/// brighter than its right neighbour. Survives re-encoding, resizing and small edits.
+fn dhash(img: &image::DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Groups paths whose hashes are within `threshold` bits of each other, chaining
+/// transitively. Only groups with more than one member are returned, in input order.
+fn group_by_hash(hashes: &[(String, u64)], threshold: u32) -> Vec<Vec<String>> {
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if (hashes[i].1 ^ hashes[j].1).count_ones() <= threshold {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[b] = a;
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<String>)> = Vec::new();
+    for (i, (path, _)) in hashes.iter().enumerate() {
+        let group = root(&mut parents, i);
+        match groups.iter_mut().find(|(root, _)| *root == group) {
+            Some((_, members)) => members.push(path.clone()),
+            None => groups.push((group, vec![path.clone()])),
+        }
+    }
+    groups.into_iter().map(|(_, members)| members).filter(|members| members.len() > 1).collect()
+}
+
+/// Hashes every file on the current rayon pool. Files that can't be decoded are
+/// logged and left out; a set `cancel` flag stops the run with `Cancelled`.
+fn hash_files(files: &[String], cancel: &AtomicBool, on_progress: impl Fn(&str) + Sync) -> Result<Vec<(String, u64)>, OptimizeError> {
+    let hashes: Vec<Option<(String, u64)>> = files
+        .par_iter()
+        .map(|path| {
+            if cancel.load(Ordering::SeqCst) {
+                return None;
+            }
+            let hash = match load_image(Path::new(path)) {
+                Ok(img) => Some((path.clone(), dhash(&img))),
+                Err(error) => {
+                    tracing::warn!(path = %path, %error, "skipping file in duplicate search");
+                    None
+                }
+            };
+            on_progress(path);
+            hash
+        })
+        .collect();
+    if cancel.load(Ordering::SeqCst) {
+        return Err(OptimizeError::Cancelled);
+    }
+    Ok(hashes.into_iter().flatten().collect())
+}
+
+/// Finds visually identical images among `paths` (files or folders) by perceptual hash.
+/// `threshold` is the largest Hamming distance, out of 64 bits, still counted as a
+/// duplicate. Emits `duplicates://progress` per file and stops on `cancel_optimization`.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, paths), err)]
+async fn find_duplicates(
+    app_handle: tauri::AppHandle,
+    paths: Vec<String>,
+    threshold: u32,
+) -> Result<Vec<Vec<String>>, OptimizeError> {
+    if threshold > 64 {
+        return Err(OptimizeError::invalid("Threshold must be between 0 and 64"));
+    }
+    tauri::async_runtime::spawn_blocking(move || {
+        let scan = scan_paths(paths, &ScanFilter::new(&[], &[])?, None, false)?;
+        let files: Vec<String> = scan.files.into_iter().map(|entry| entry.path).collect();
+        let cancel_flag = app_handle.state::<CancelFlag>();
+        cancel_flag.0.store(false, Ordering::SeqCst);
+
+        let total = files.len();
+        let completed = AtomicUsize::new(0);
+        let pool = worker_pool(&app_handle, None)?;
+        let hashes = pool.install(|| {
+            hash_files(&files, &cancel_flag.0, |path| {
+                let _ = app_handle.emit("duplicates://progress", ProjectionProgress {
+                    completed: completed.fetch_add(1, Ordering::SeqCst) + 1,
+                    total,
+                    current_path: path.to_string(),
+                });
+            })
+        })?;
+        Ok(group_by_hash(&hashes, threshold))
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+#[tauri::command]
+async fn cancel_optimization(state: tauri::State<'_, CancelFlag>) -> Result<(), OptimizeError> {
+    state.0.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Maps `convert_to` to the output extension, or keeps the source's when not converting.
+fn resolve_target_extension<'a>(convert_to: Option<&str>, extension: &'a str) -> Result<&'a str, OptimizeError> {
+    let Some(format) = convert_to else {
+        return Ok(extension);
+    };
+    match format {
+        "jpg" | "jpeg" => Ok("jpg"),
+        "webp" | "webp-smart" => Ok("webp"),
+        "png" => Ok("png"),
+        "avif" => Ok("avif"),
+        "jxl" => Ok("jxl"),
+        "qoi" => Ok("qoi"),
+        "pnm" | "ppm" | "pgm" => Ok("pnm"),
+        "ff" | "farbfeld" => Ok("ff"),
+        "hdr" | "exr" | "dds" => Err(OptimizeError::invalid(format!(
+            "{} is only supported as a source; convert to png, jpg, webp, avif, qoi, pnm or ff instead",
+            format.to_uppercase()
+        ))),
+        _ => Err(OptimizeError::UnsupportedFormat { format: format.to_string() }),
+    }
+}
+
+/// What this build can read and write, so the frontend doesn't hardcode format lists.
+#[derive(serde::Serialize, Debug)]
+struct Capabilities {
+    /// Source extensions that can be decoded.
+    inputs: Vec<&'static str>,
+    /// `convert_to` formats that can be encoded.
+    targets: Vec<&'static str>,
+    /// Optional Cargo features compiled in: "mozjpeg", "avif-decode", "jxl", "heif".
+    features: Vec<&'static str>,
+}
+
+/// Conversion targets every build can write; JPEG XL needs the `jxl` feature.
+const BASE_TARGETS: [&str; 7] = ["jpg", "png", "webp", "avif", "qoi", "pnm", "ff"];
+
+fn capabilities() -> Capabilities {
+    let features: Vec<&'static str> = [
+        ("mozjpeg", cfg!(feature = "mozjpeg")),
+        ("avif-decode", cfg!(feature = "avif-decode")),
+        ("jxl", cfg!(feature = "jxl")),
+        ("heif", cfg!(feature = "heif")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    let has = |feature| features.contains(&feature);
+
+    let inputs = SUPPORTED_EXTENSIONS
+        .into_iter()
+        .filter(|&ext| match ext {
+            "avif" => has("avif-decode"),
+            "jxl" => has("jxl"),
+            "heic" | "heif" => has("heif"),
+            _ => true,
+        })
+        .collect();
+    let mut targets = BASE_TARGETS.to_vec();
+    if has("jxl") {
+        targets.push("jxl");
+    }
+    Capabilities { inputs, targets, features }
+}
+
+#[tauri::command]
+async fn get_capabilities() -> Result<Capabilities, OptimizeError> {
+    Ok(capabilities())
+}
+
+/// Resolves the output format and encodes to it; `convert_to: "auto"` tries several
+/// formats and keeps the smallest, and `target_max_bytes` searches for a fitting size.
+fn encode_to_target<'a>(
+    source: &[u8],
+    extension: &'a str,
+    options: &OptimizeOptions,
+) -> Result<(&'a str, Encoded), OptimizeError> {
+    if let Some(max_bytes) = options.target_max_bytes {
+        return encode_within_budget(source, extension, options, max_bytes);
+    }
+    if options.convert_to.as_deref() == Some("auto") {
+        return encode_auto(source, extension, options);
+    }
+    let target_extension = resolve_target_extension(options.convert_to.as_deref(), extension)?;
+    Ok((target_extension, encode_output(source, extension, target_extension, options)?))
+}
+
+const TARGET_SIZE_MIN_QUALITY: u8 = 10;
+const TARGET_SIZE_MAX_QUALITY: u8 = 95;
+/// Downscale rounds `target_max_bytes` tries once the lowest quality is still too big.
+const TARGET_SIZE_MAX_RESIZES: usize = 6;
+
+/// Sets the quality knob for a lossy target; `false` if the format has none to turn.
+fn set_target_quality(options: &mut OptimizeOptions, target_extension: &str, quality: u8) -> bool {
+    match target_extension {
+        "jpg" => options.jpeg_quality = Some(quality),
+        "webp" => {
+            options.webp_quality = Some(quality as f32);
+            options.webp_lossless = Some(false);
+            if options.convert_to.as_deref() == Some("webp-smart") {
+                options.convert_to = Some("webp".to_string());
+            }
+        }
+        "avif" => options.avif_quality = Some(quality),
+        _ => return false,
+    }
+    true
+}
+
+/// `target_max_bytes`: encodes as configured, then binary-searches quality, then shrinks
+/// the dimensions until the output fits. Bounded by `TARGET_SIZE_MAX_RESIZES` rounds of
+/// at most ~7 encodes each; fails with `CannotMeetTarget` and the best size reached.
+fn encode_within_budget<'a>(
+    source: &[u8],
+    extension: &'a str,
+    options: &OptimizeOptions,
+    max_bytes: u64,
+) -> Result<(&'a str, Encoded), OptimizeError> {
+    let (width, height) = image_dimensions(source)?;
+    let mut smallest = u64::MAX;
+    let mut scale = 1.0f64;
+    for _ in 0..=TARGET_SIZE_MAX_RESIZES {
+        let mut sized = OptimizeOptions { target_max_bytes: None, ..options.clone() };
+        if scale < 1.0 {
+            let bound = |size: u32, limit: Option<u32>| {
+                let scaled = ((size as f64 * scale).round() as u32).max(1);
+                Some(limit.map_or(scaled, |limit| limit.min(scaled)))
+            };
+            sized.max_width = bound(width, options.max_width);
+            sized.max_height = bound(height, options.max_height);
+        }
+
+        let (target_extension, encoded) = encode_to_target(source, extension, &sized)?;
+        let mut round_smallest = encoded.data.len() as u64;
+        if round_smallest <= max_bytes {
+            return Ok((target_extension, encoded));
+        }
+
+        // libwebp can aim for the size itself in one encode; the quality search below
+        // still covers it landing over budget
+        let mut targeted = OptimizeOptions { auto_quality: None, ..sized.clone() };
+        if set_target_quality(&mut targeted, target_extension, TARGET_SIZE_MAX_QUALITY) && target_extension == "webp" {
+            targeted.webp_target_size = Some(max_bytes);
+            let (chosen_extension, encoded) = encode_to_target(source, extension, &targeted)?;
+            let size = encoded.data.len() as u64;
+            round_smallest = round_smallest.min(size);
+            if size <= max_bytes {
+                return Ok((chosen_extension, encoded));
+            }
+        }
+
+        // Highest quality that still fits
+        let mut lossy = OptimizeOptions { auto_quality: None, ..sized.clone() };
+        if set_target_quality(&mut lossy, target_extension, TARGET_SIZE_MIN_QUALITY) {
+            let (mut low, mut high) = (TARGET_SIZE_MIN_QUALITY, TARGET_SIZE_MAX_QUALITY);
+            let mut best = None;
+            while low <= high {
+                let quality = low + (high - low) / 2;
+                set_target_quality(&mut lossy, target_extension, quality);
+                let (chosen_extension, mut encoded) = encode_to_target(source, extension, &lossy)?;
+                let size = encoded.data.len() as u64;
+                round_smallest = round_smallest.min(size);
+                if size <= max_bytes {
+                    encoded.chosen_quality = Some(quality);
+                    best = Some((chosen_extension, encoded));
+                    low = quality + 1;
+                } else if quality == TARGET_SIZE_MIN_QUALITY {
+                    break;
+                } else {
+                    high = quality - 1;
+                }
+            }
+            if let Some(best) = best {
+                return Ok(best);
+            }
+        }
+
+        smallest = smallest.min(round_smallest);
+        // Byte count scales roughly with pixel count, so shrink both edges by the square root
+        let ratio = max_bytes as f64 / round_smallest as f64;
+        scale *= ratio.sqrt().clamp(0.5, 0.9);
+    }
+    Err(OptimizeError::CannotMeetTarget { max_bytes, smallest_size: smallest })
+}
+
+/// More distinct colors than this means a photo rather than a graphic.
+const AUTO_MAX_GRAPHIC_COLORS: usize = 256;
+
+/// Candidate `(convert_to, webp_lossless)` pairs for `convert_to: "auto"`. Graphics with
+/// few colors stay lossless, transparent images never try JPEG, and opaque photos try
+/// every lossy format.
+fn auto_candidates(img: &image::DynamicImage) -> &'static [(&'static str, Option<bool>)] {
+    let rgba = img.to_rgba8();
+    let mut colors = std::collections::HashSet::new();
+    let few_colors = rgba.pixels().all(|p| {
+        colors.insert(p.0);
+        colors.len() <= AUTO_MAX_GRAPHIC_COLORS
+    });
+    let transparent = rgba.pixels().any(|p| p[3] < 255);
+
+    if few_colors {
+        &[("png", None), ("webp", Some(true))]
+    } else if transparent {
+        &[("png", None), ("webp", None), ("avif", None)]
+    } else {
+        &[("jpg", None), ("webp", None), ("avif", None)]
+    }
+}
+
+fn encode_auto<'a>(source: &[u8], extension: &'a str, options: &OptimizeOptions) -> Result<(&'a str, Encoded), OptimizeError> {
+    let img = decode_image(source, extension)?;
+    let mut best: Option<(&'a str, Encoded)> = None;
+    for &(format, webp_lossless) in auto_candidates(&img) {
+        let candidate = OptimizeOptions {
+            convert_to: Some(format.to_string()),
+            webp_lossless: webp_lossless.or(options.webp_lossless),
+            ..options.clone()
+        };
+        let encoded = encode_output(source, extension, format, &candidate)?;
+        let smaller = match &best {
+            Some((_, current)) => encoded.data.len() < current.data.len(),
+            None => true,
+        };
+        if smaller {
+            best = Some((format, encoded));
+        }
+    }
+    best.ok_or_else(|| OptimizeError::internal("no candidate formats"))
+}
+
+/// Result of the in-memory encode step shared by the file and byte entry points.
+struct Encoded {
+    data: Vec<u8>,
+    resized: bool,
+    chosen_quality: Option<u8>,
+    warnings: Vec<String>,
+    webp_mode: Option<String>,
+    png_strategy: Option<String>,
+}
+
+/// Optimizes or converts an in-memory source. `extension` is the lowercase source
+/// format and `target_extension` the resolved output format.
+fn encode_output(
+    source: &[u8],
+    extension: &str,
+    target_extension: &str,
+    options: &OptimizeOptions,
+) -> Result<Encoded, OptimizeError> {
+    let convert_to = &options.convert_to;
+    let quality = resolve_jpeg_quality(options.jpeg_quality, options.quality_step)?;
+    let png_level = resolve_png_level(options.png_level)?;
+    let webp_quality = resolve_webp_quality(options.webp_quality)?;
+    let webp_lossless = options.webp_lossless.unwrap_or(DEFAULT_WEBP_LOSSLESS);
+    let (avif_quality, avif_speed) = resolve_avif_settings(options.avif_quality, options.avif_speed)?;
+    let jxl_distance = resolve_jxl_distance(options.jxl_distance)?;
+    let auto_quality = resolve_auto_quality(options.auto_quality)?;
+    let mut chosen_quality = None;
+    let icc_profile = match options.preserve_icc.unwrap_or(false) {
+        true => read_icc_profile(source)?,
+        false => None,
+    };
+    let icc = icc_profile.as_deref();
+    let mut warnings = Vec::new();
+    let smart_webp = convert_to.as_deref() == Some("webp-smart");
+    let mut webp_mode = None;
+    let mut png_strategy = None;
+    let png_timeout = options.png_timeout_ms.map(std::time::Duration::from_millis);
+    let strip_metadata = options.strip_metadata.unwrap_or(false);
+    let background_color = options.background_color.unwrap_or(DEFAULT_BACKGROUND_COLOR);
+    let jpeg_progressive = options.jpeg_progressive.unwrap_or(false);
+    let jpeg_backend = resolve_jpeg_backend(options.jpeg_encoder.as_deref())?;
+    let jpeg_subsampling = resolve_jpeg_subsampling(options.jpeg_subsampling.as_deref())?;
+    let tiff_compression = resolve_tiff_compression(options.tiff_compression.as_deref())?;
+    let (max_width, max_height) = (options.max_width, options.max_height);
+    let resize_filter = resolve_resize_filter(options.resize_filter.as_deref())?;
+    let sharpen = match options.sharpen {
+        Some(sigma) if !(sigma.is_finite() && sigma > 0.0) => {
+            return Err(OptimizeError::invalid("Sharpen amount must be a positive number"));
+        }
+        sharpen => sharpen,
+    };
+    let grayscale = options.grayscale.unwrap_or(false);
+    let auto_contrast = options.auto_contrast.unwrap_or(false);
+    resolve_min_savings(options.min_savings_percent)?;
+    let mut resized = false;
+    check_complete(source)?;
+    options.report_phase("decode");
+
+    // Animated GIFs keep their frames when converted to WebP; single-frame GIFs
+    // fall through to the regular conversion below
+    let animated_frames = match (extension, target_extension) {
+        ("gif", "webp") if convert_to.is_some() => Some(decode_gif_frames(source)?).filter(|frames| frames.len() > 1),
+        _ => None,
+    };
+
+    // JPEG -> JXL is lossless unless a resize or grayscale forces a decode
+    let jpeg_to_jxl = convert_to.is_some()
+        && !grayscale
+        && !auto_contrast
+        && target_extension == "jxl"
+        && matches!(extension, "jpg" | "jpeg")
+        && {
+            let (width, height) = image_dimensions(source)?;
+            !exceeds_bounds(width, height, max_width, max_height)
+        };
+
+    let mut output = Vec::new();
+    if let Some(frames) = animated_frames {
+        let repeat = read_gif_repeat(source)?;
+        let frames: Vec<_> = frames
+            .into_iter()
+            .map(|(buffer, delay_ms)| {
+                let img = fit_within(image::DynamicImage::ImageRgba8(buffer), max_width, max_height, resize_filter, sharpen, &mut resized);
+                let img = if grayscale { to_grayscale(img) } else { img };
+                let img = if auto_contrast { stretch_contrast(img) } else { img };
+                (img.into_rgba8(), delay_ms)
+            })
+            .collect();
+        options.report_phase("encode");
+        output = encode_animated_webp(&frames, repeat, webp_lossless, webp_quality)?;
+    } else if jpeg_to_jxl {
+        // The reconstruction data keeps every APP segment, ICC included
+        options.report_phase("encode");
+        output = recompress_jpeg_to_jxl(source)?;
+    } else if convert_to.is_some() {
+        // Conversion logic
+        if matches!(extension, "tiff" | "tif") {
+            let pages = tiff_page_count(source)?;
+            if pages > 1 {
+                warnings.push(format!("Multi-page TIFF: only the first of {} pages was converted", pages));
+            }
+        }
+        let img = decode_image(source, extension)?;
+        let img = tonemap(img, options.hdr_exposure.unwrap_or(0.0));
+        let img = fit_within(img, max_width, max_height, resize_filter, sharpen, &mut resized);
+        let img = if grayscale { to_grayscale(img) } else { img };
+        let img = if auto_contrast { stretch_contrast(img) } else { img };
+        options.report_phase("encode");
+
+        match target_extension {
+            "jpg" => {
+                // JPEG does not support transparency (RGBA) or 16-bit samples, so composite
+                // over the background color and downsample
+                let pixels = jpeg_pixels(&img, background_color);
+                let encode = |quality: u8| -> Result<Vec<u8>, OptimizeError> {
+                    let mut data = Vec::new();
+                    encode_jpeg(
+                        &mut data,
+                        pixels.as_bytes(),
+                        pixels.width(),
+                        pixels.height(),
+                        pixels.color(),
+                        quality,
+                        jpeg_progressive,
+                        jpeg_subsampling,
+                        jpeg_backend,
+                    )?;
+                    Ok(data)
+                };
+                let data = match auto_quality {
+                    Some(target) => {
+                        let (chosen, data) = search_quality(&img, target, encode)?;
+                        chosen_quality = Some(chosen);
+                        data
+                    }
+                    None => encode(quality)?,
+                };
+                let data = match icc {
+                    Some(icc) => embed_jpeg_icc(&data, icc)?,
+                    None => data,
+                };
+                output = data;
+            }
+            "webp" => {
+                // The WebP encoders only take 8-bit samples
+                let img = to_8bit(img);
+                if let Some(target) = auto_quality {
+                    // Searching only makes sense for the lossy encoder
+                    let (chosen, data) = search_quality(&img, target, |q| encode_lossy_webp(&img, q as f32))?;
+                    chosen_quality = Some(chosen);
+                    if icc.is_some() {
+                        warnings.push(icc_dropped_warning("lossy webp"));
+                    }
+                    output = data;
+                } else if let Some(target_size) = options.webp_target_size {
+                    output = encode_webp_to_size(&img, target_size)?;
+                    if icc.is_some() {
+                        warnings.push(icc_dropped_warning("lossy webp"));
+                    }
+                } else if smart_webp {
+                    // Encode both ways and keep whichever is smaller for this image
+                    let mut lossless = Vec::new();
+                    let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut lossless);
+                    let mut lossless_warnings = Vec::new();
+                    write_with_icc(&img, encoder, icc, "webp", &mut lossless_warnings)?;
+                    let lossy = encode_lossy_webp(&img, webp_quality)?;
+
+                    if lossy.len() < lossless.len() {
+                        if icc.is_some() {
+                            warnings.push(icc_dropped_warning("lossy webp"));
+                        }
+                        webp_mode = Some("lossy".to_string());
+                        output = lossy;
+                    } else {
+                        warnings.extend(lossless_warnings);
+                        webp_mode = Some("lossless".to_string());
+                        output = lossless;
+                    }
+                } else if webp_lossless {
+                    let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut output);
+                    write_with_icc(&img, encoder, icc, "webp", &mut warnings)?;
+                } else {
+                    let data = encode_lossy_webp(&img, webp_quality)?;
+                    if icc.is_some() {
+                        warnings.push(icc_dropped_warning("lossy webp"));
+                    }
+                    output = data;
+                }
+            }
+            "png" => {
+                let encoder = image::codecs::png::PngEncoder::new(&mut output);
+                write_with_icc(&img, encoder, icc, "png", &mut warnings)?;
+                if options.png_palette_reduction == Some(true) {
+                    // PngEncoder always writes truecolor; let oxipng pick a smaller color type
+                    let png_options = png_options(png_level, Some(true));
+                    let (data, strategy) = oxipng_with_timeout(&output, &png_options, png_timeout, &mut warnings)?;
+                    output = data;
+                    png_strategy = strategy;
+                }
+            }
+            "avif" => {
+                encode_avif(&img, &mut output, avif_quality, avif_speed)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("avif"));
+                }
+            }
+            "jxl" => {
+                let data = encode_jxl(&img, jxl_distance)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("jxl"));
+                }
+                output = data;
+            }
+            "qoi" => {
+                // QOI is lossless-only, so every quality setting is ignored. The encoder
+                // only takes 8-bit RGB(A).
+                let img = to_8bit(img);
+                let img = match img.color().has_alpha() {
+                    true => image::DynamicImage::ImageRgba8(img.into_rgba8()),
+                    false => image::DynamicImage::ImageRgb8(img.into_rgb8()),
+                };
+                img.write_with_encoder(image::codecs::qoi::QoiEncoder::new(&mut output))
+                    .map_err(OptimizeError::encode)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("qoi"));
+                }
+            }
+            "pnm" => {
+                // PGM/PPM have no alpha channel, so composite like JPEG but keep 16-bit samples
+                let img = match img.color() {
+                    image::ColorType::L8 | image::ColorType::L16 | image::ColorType::Rgb8 | image::ColorType::Rgb16 => img,
+                    _ => jpeg_pixels(&img, background_color),
+                };
+                img.write_with_encoder(image::codecs::pnm::PnmEncoder::new(&mut output))
+                    .map_err(OptimizeError::encode)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("pnm"));
+                }
+            }
+            "ff" => {
+                // Farbfeld is always 16-bit RGBA
+                let img = image::DynamicImage::ImageRgba16(img.to_rgba16());
+                img.write_with_encoder(image::codecs::farbfeld::FarbfeldEncoder::new(&mut output))
+                    .map_err(OptimizeError::encode)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("farbfeld"));
+                }
+            }
+            _ => return Err(OptimizeError::UnsupportedFormat { format: target_extension.to_string() }),
+        }
+    } else {
+        // Optimization logic (same format)
+        match extension {
+            "png" => {
+                let mut png_options = png_options(png_level, options.png_palette_reduction);
+                if strip_metadata {
+                    // oxipng keeps ancillary chunks (tEXt, iTXt, eXIf, ...) by default.
+                    // `Safe` still drops text/EXIF but keeps color chunks like iCCP.
+                    png_options.strip = match icc {
+                        Some(_) => StripChunks::Safe,
+                        None => StripChunks::All,
+                    };
+                }
+
+                let (width, height) = image_dimensions(source)?;
+                let (data, strategy) = if grayscale || auto_contrast || exceeds_bounds(width, height, max_width, max_height) {
+                    // Resizing, grayscale and auto contrast need decoded pixels, so re-encode before handing the PNG to oxipng
+                    let img = decode_image(source, extension)?;
+                    let img = fit_within(img, max_width, max_height, resize_filter, sharpen, &mut resized);
+                    let img = if grayscale { to_grayscale(img) } else { img };
+                    let img = if auto_contrast { stretch_contrast(img) } else { img };
+                    options.report_phase("encode");
+                    let mut png_data = Vec::new();
+                    let encoder = image::codecs::png::PngEncoder::new(&mut png_data);
+                    write_with_icc(&img, encoder, icc, "png", &mut warnings)?;
+                    options.report_phase("optimize");
+                    oxipng_with_timeout(&png_data, &png_options, png_timeout, &mut warnings)?
+                } else {
+                    options.report_phase("optimize");
+                    oxipng_with_timeout(source, &png_options, png_timeout, &mut warnings)?
+                };
+                output = data;
+                png_strategy = strategy;
+            }
+            "jpg" | "jpeg" if options.jpeg_lossless.unwrap_or(false) => {
+                let (width, height) = image_dimensions(source)?;
+                if grayscale || auto_contrast || exceeds_bounds(width, height, max_width, max_height) {
+                    return Err(OptimizeError::invalid(
+                        "Lossless JPEG optimization keeps the DCT data as is, so it can't resize, convert to grayscale or adjust contrast",
+                    ));
+                }
+                // The pixels can't be rotated without re-encoding, so a non-default
+                // orientation keeps its EXIF segment
+                let orientation = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(source))
+                    .ok()
+                    .and_then(|mut decoder| decoder.orientation().ok());
+                let keep_exif = !matches!(orientation, None | Some(image::metadata::Orientation::NoTransforms));
+                options.report_phase("optimize");
+                output = optimize_jpeg_lossless(source, icc.is_some(), keep_exif, jpeg_progressive)?;
+            }
+            "jpg" | "jpeg" => {
+                let img = decode_image(source, extension)?;
+                let img = fit_within(img, max_width, max_height, resize_filter, sharpen, &mut resized);
+                let img = if grayscale { to_grayscale(img) } else { img };
+                let img = if auto_contrast { stretch_contrast(img) } else { img };
+                options.report_phase("encode");
+                // Decoded CMYK or 16-bit sources aren't in a layout the encoders accept, so
+                // normalize to 8-bit gray/RGB the same way the conversion path does
+                let pixels = jpeg_pixels(&img, background_color);
+
+                // Re-encoding never copies APP segments (EXIF/XMP/GPS) from the source
+                let encode = |quality: u8| -> Result<Vec<u8>, OptimizeError> {
+                    let mut data = Vec::new();
+                    encode_jpeg(
+                        &mut data,
+                        pixels.as_bytes(),
+                        pixels.width(),
+                        pixels.height(),
+                        pixels.color(),
+                        quality,
+                        jpeg_progressive,
+                        jpeg_subsampling,
+                        jpeg_backend,
+                    )?;
+                    Ok(data)
+                };
+                let data = match auto_quality {
+                    Some(target) => {
+                        let (chosen, data) = search_quality(&img, target, encode)?;
+                        chosen_quality = Some(chosen);
+                        data
+                    }
+                    None => encode(quality)?,
+                };
+                let data = match icc {
+                    Some(icc) => embed_jpeg_icc(&data, icc)?,
+                    None => data,
+                };
+                output = data;
+            }
+            "avif" => {
+                // Decoding AVIF requires the `avif-decode` feature (libdav1d)
+                let img = decode_image(source, extension)?;
+                let img = fit_within(img, max_width, max_height, resize_filter, sharpen, &mut resized);
+                let img = if grayscale { to_grayscale(img) } else { img };
+                let img = if auto_contrast { stretch_contrast(img) } else { img };
+                options.report_phase("encode");
+
+                encode_avif(&img, &mut output, avif_quality, avif_speed)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("avif"));
+                }
+            }
+            "gif" => {
+                options.report_phase("optimize");
+                output = optimize_gif(source)?;
+            }
+            "tiff" | "tif" => {
+                // Re-encoding would silently drop every page after the first
+                let pages = tiff_page_count(source)?;
+                if pages > 1 {
+                    return Err(OptimizeError::invalid(format!(
+                        "Multi-page TIFF ({} pages) can only be converted, which exports the first page",
+                        pages
+                    )));
+                }
+                let img = decode_image(source, extension)?;
+                let img = fit_within(img, max_width, max_height, resize_filter, sharpen, &mut resized);
+                let img = if grayscale { to_grayscale(img) } else { img };
+                let img = if auto_contrast { stretch_contrast(img) } else { img };
+                options.report_phase("encode");
+
+                output = encode_tiff(&img, tiff_compression)?;
+                if icc.is_some() {
+                    warnings.push(icc_dropped_warning("tiff"));
+                }
+            }
+            // HEIC has no encoder here, so it can only be converted
+            "webp" | "bmp" | "ico" | "tga" | "dds" | "pnm" | "qoi" | "hdr" | "exr" | "ff" | "jxl" | "heic" | "heif" => {
+                return Err(OptimizeError::NeedsConversion { format: extension.to_string() });
+            }
+            _ => return Err(OptimizeError::UnsupportedFormat { format: extension.to_string() }),
+        }
+    }
+
+    if let Some(comment) = &options.comment {
+        output = embed_comment(output, target_extension, comment, &mut warnings)?;
+    }
+
+    Ok(Encoded {
+        data: output,
+        resized,
+        chosen_quality,
+        warnings,
+        webp_mode,
+        png_strategy: png_strategy.map(str::to_string),
+    })
+}
+
+/// Errors with `TargetNotWritable` if `path` is read-only or can't be opened for
+/// writing (e.g. locked by another process on Windows).
+fn check_target_writable(path: &Path, metadata: &fs::Metadata) -> Result<(), OptimizeError> {
+    let writable = !metadata.permissions().readonly() && fs::OpenOptions::new().write(true).open(path).is_ok();
+    if writable {
+        return Ok(());
+    }
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let suggested_path = dir_is_writable(parent)
+        .then(|| next_free_path(path.to_path_buf(), Path::new("")).to_string_lossy().to_string());
+    Err(OptimizeError::TargetNotWritable { path: path.to_string_lossy().to_string(), suggested_path })
+}
+
+/// Probes `dir` by creating and removing a scratch file.
+/// The configured temp directory if it's writable, otherwise the system one (with a warning).
+fn writable_temp_dir(configured: Option<&Path>, warnings: &mut Vec<String>) -> PathBuf {
+    match configured {
+        Some(dir) if dir_is_writable(dir) => dir.to_path_buf(),
+        Some(dir) => {
+            warnings.push(format!(
+                "Temp directory {} is not writable; used the system temp directory",
+                dir.display()
+            ));
+            std::env::temp_dir()
+        }
+        None => std::env::temp_dir(),
+    }
+}
+
+fn dir_is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".sqsh-probe-{}", uuid::Uuid::new_v4()));
+    match fs::File::create(&probe) {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Validates `min_savings_percent`, which must be in [0, 100).
+fn resolve_min_savings(percent: Option<f32>) -> Result<Option<f32>, OptimizeError> {
+    match percent {
+        Some(p) if !(0.0..100.0).contains(&p) => Err(OptimizeError::invalid(format!(
+            "Minimum savings must be at least 0% and below 100%, got {}",
+            p
+        ))),
+        percent => Ok(percent),
+    }
+}
+
+/// Only check for size increase if we are NOT converting OR if we are converting to the SAME format.
+/// With `min_savings_percent`, outputs saving less than that share count as no smaller.
+/// If converting to a DIFFERENT format, we accept the result regardless of size, unless `never_grow` is set.
+/// When stripping metadata, resizing or adding a comment we always keep the result, since
+/// the original still carries the metadata / oversized dimensions / no comment.
+fn should_keep_original(
+    original_size: u64,
+    new_size: u64,
+    extension: &str,
+    target_extension: &str,
+    options: &OptimizeOptions,
+    resized: bool,
+) -> bool {
+    let is_same_format = extension == target_extension;
+    let min_savings = options.min_savings_percent.unwrap_or(0.0) as f64 / 100.0;
+    let saved = original_size.saturating_sub(new_size) as f64;
+    let too_small_a_gain = new_size >= original_size || saved < original_size as f64 * min_savings;
+    too_small_a_gain
+        && !options.strip_metadata.unwrap_or(false)
+        && options.comment.is_none()
+        && !resized
+        && (options.convert_to.is_none()
+            || is_same_format
+            || options.never_grow.unwrap_or(false)
+            // "auto" is about picking the smallest file, so it never grows one
+            || options.convert_to.as_deref() == Some("auto"))
+}
+
+/// Result of `optimize_bytes`; `data` is the original input when `skipped` is set.
+#[derive(serde::Serialize)]
+struct OptimizeBytesResult {
+    data: Vec<u8>,
+    /// Extension of the returned data, e.g. "webp".
+    format: String,
+    original_size: u64,
+    new_size: u64,
+    saved_bytes: u64,
+    skipped: bool,
+    duration_ms: u64,
+    chosen_quality: Option<u8>,
+    warnings: Vec<String>,
+    webp_mode: Option<String>,
+    png_strategy: Option<String>,
+}
+
+/// Runs the same pipeline as `optimize_core` on an in-memory buffer, never touching disk.
+fn optimize_in_memory(
+    data: Vec<u8>,
+    format_hint: Option<&str>,
+    options: &OptimizeOptions,
+) -> Result<OptimizeBytesResult, OptimizeError> {
+    let start_time = std::time::Instant::now();
+    if data.is_empty() {
+        return Err(OptimizeError::EmptyFile { path: String::new() });
+    }
+    let extension = match format_hint {
+        Some(hint) => hint.trim_start_matches('.').to_lowercase(),
+        None if is_heif(&data) => "heic".to_string(),
+        None => image::guess_format(&data)
+            .ok()
+            .and_then(|format| format.extensions_str().first().copied())
+            .ok_or_else(|| OptimizeError::UnsupportedFormat { format: "unknown".to_string() })?
+            .to_string(),
+    };
+    let options = &*options.for_source(&extension);
+    let (target_extension, encoded) = encode_to_target(&data, &extension, options)?;
+    let original_size = data.len() as u64;
+    let new_size = encoded.data.len() as u64;
+
+    let skipped = should_keep_original(original_size, new_size, &extension, target_extension, options, encoded.resized);
+    let (data, format, new_size) = if skipped {
+        (data, extension.clone(), original_size)
+    } else {
+        (encoded.data, target_extension.to_string(), new_size)
+    };
+
+    Ok(OptimizeBytesResult {
+        data,
+        format,
+        original_size,
+        new_size,
+        saved_bytes: original_size.saturating_sub(new_size),
+        skipped,
+        duration_ms: start_time.elapsed().as_millis() as u64,
+        chosen_quality: encoded.chosen_quality,
+        warnings: encoded.warnings,
+        webp_mode: encoded.webp_mode,
+        png_strategy: encoded.png_strategy,
+    })
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, data), fields(len = data.len()), err)]
+async fn optimize_bytes(
+    app_handle: tauri::AppHandle,
+    data: Vec<u8>,
+    format_hint: Option<String>,
+    options: OptimizeOptions,
+) -> Result<OptimizeBytesResult, OptimizeError> {
+    let options = options.with_config(&app_handle);
+    let pool = worker_pool(&app_handle, None)?;
+    tauri::async_runtime::spawn_blocking(move || pool.install(|| optimize_in_memory(data, format_hint.as_deref(), &options)))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+/// `optimize_in_memory` over everything `reader` yields, for piped input.
+fn optimize_reader(
+    mut reader: impl Read,
+    format_hint: Option<&str>,
+    options: &OptimizeOptions,
+) -> Result<OptimizeBytesResult, OptimizeError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    optimize_in_memory(data, format_hint, options)
+}
+
+/// Reads an image from the process's stdin until EOF, for when sqsh is driven from a
+/// script or pipe. Without `format_hint` the format is sniffed from the bytes.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle), err)]
+async fn optimize_stdin(
+    app_handle: tauri::AppHandle,
+    format_hint: Option<String>,
+    options: OptimizeOptions,
+) -> Result<OptimizeBytesResult, OptimizeError> {
+    let options = options.with_config(&app_handle);
+    let pool = worker_pool(&app_handle, None)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        pool.install(|| optimize_reader(std::io::stdin().lock(), format_hint.as_deref(), &options))
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Optimizes or converts a single file. Has no Tauri dependency, so it can be driven
+/// directly from tests; the commands are thin wrappers around it.
+fn optimize_core(input: &Path, options: &OptimizeOptions) -> Result<OptimizationResult, OptimizeError> {
+    optimize_cancellable(input, options, None)
+}
+
+/// `optimize_core` with a cancel flag that is checked once encoding has finished.
+fn optimize_cancellable(
+    path: &Path,
+    options: &OptimizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<OptimizationResult, OptimizeError> {
+    let start_time = std::time::Instant::now();
+    // The result goes back to JS as a string, so refuse names it couldn't represent
+    let file_path = path_string(path)?;
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: file_path.clone() });
+    }
+
+    let source_metadata = fs::metadata(path)?;
+    let original_size = source_metadata.len();
+    if original_size == 0 {
+        return Err(OptimizeError::EmptyFile { path: file_path });
+    }
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let options = &*options.for_source(&extension);
+
+    // Fail before encoding if the in-place overwrite below can't happen
+    if options.overwrite
+        && options.convert_to.is_none()
+        && options.output_template.is_none()
+        && options.output_dir.is_none()
+        && options.mirror.is_none()
+    {
+        check_target_writable(path, &source_metadata)?;
+    }
+
+    let source = fs::read(path)?;
+    let (target_extension, encoded) = encode_to_target(&source, &extension, options)?;
+    drop(source);
+    let chosen_format = (options.convert_to.as_deref() == Some("auto")).then(|| target_extension.to_string());
+
+    // Always use a temporary file for optimization first
+    let file_stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let Encoded { data, resized, chosen_quality, mut warnings, webp_mode, png_strategy } = encoded;
+    let temp_dir = writable_temp_dir(options.temp_dir.as_deref(), &mut warnings);
+    let temp_name = format!("{}_{}.{}", file_stem, uuid::Uuid::new_v4(), target_extension);
+    let temp_path = temp_dir.join(temp_name);
+    options.report_phase("write");
+    fs::write(&temp_path, &data)?;
+    drop(data);
+
+    // Bail out before touching the source if the batch was cancelled mid-encode
+    if cancel.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(OptimizeError::Cancelled);
+    }
+
+    let new_size = fs::metadata(&temp_path)?.len();
+
+    if should_keep_original(original_size, new_size, &extension, target_extension, options, resized) {
+        // Optimization failed to reduce size, discard result
+        fs::remove_file(&temp_path)?;
+        return Ok(OptimizationResult {
+            original_size,
+            new_size: original_size,
+            saved_bytes: 0,
+            output_path: file_path, // Return original path
+            skipped: true,
+            duration_ms: start_time.elapsed().as_millis() as u64,
+            backup_path: None,
+            chosen_quality,
+            warnings,
+            webp_mode,
+            png_strategy,
+            chosen_format: None,
+        });
+    }
+
+    // Calculate saved bytes (can be negative if size increased during conversion)
+    let saved_bytes = original_size.saturating_sub(new_size);
+
+    // Optimization successful
+    let backup = options.backup.unwrap_or(false);
+    let verify = options.verify.unwrap_or(false);
+    let mut backup_path = None;
+    let output_path = if options.output_template.is_some() || options.output_dir.is_some() || options.mirror.is_some() {
+        let mirrored_dir = options.mirror.as_ref().map(|mirror| mirror.dir_for(path)).transpose()?;
+        let target_path = templated_output_path(
+            path,
+            target_extension,
+            options.output_template.as_deref(),
+            mirrored_dir.as_deref().or(options.output_dir.as_deref().map(Path::new)),
+        )?;
+        let output_path = path_string(&target_path)?;
+        backup_path = write_output(&temp_path, &target_path, backup, verify)?;
+        output_path
+    } else if options.overwrite {
+        if options.convert_to.is_none() {
+            // Direct overwrite of source file
+            backup_path = write_output(&temp_path, path, backup, verify)?;
+            file_path.clone()
+        } else {
+            // Conversion with overwrite enabled = Save to source dir, but handle conflicts
+            // We do NOT delete the original source file as it has a different extension.
+            let target_path = next_free_path(path.with_extension(target_extension), path);
+            let output_path = path_string(&target_path)?;
+            
+            backup_path = write_output(&temp_path, &target_path, backup, verify)?;
+            output_path
+        }
+    } else {
+        // Keep temp file
+        if verify {
+            if let Err(e) = verify_output(&temp_path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        }
+        temp_path.to_string_lossy().to_string()
+    };
+
+    if options.preserve_timestamps.unwrap_or(false) {
+        filetime::set_file_times(
+            &output_path,
+            FileTime::from_last_access_time(&source_metadata),
+            FileTime::from_last_modification_time(&source_metadata),
+        )?;
+    }
+
+    let duration_ms = start_time.elapsed().as_millis() as u64;
+
+    Ok(OptimizationResult {
+        original_size,
+        new_size,
+        saved_bytes,
+        output_path,
+        skipped: false,
+        duration_ms,
+        backup_path: backup_path.map(|p| p.to_string_lossy().to_string()),
+        chosen_quality,
+        warnings,
+        webp_mode,
+        png_strategy,
+        chosen_format,
+    })
+}
+
+/// Appends ` (n)` to the file stem until the path is free. The source itself counts as
+/// free, since writing over it is an in-place overwrite.
+fn next_free_path(target: PathBuf, source: &Path) -> PathBuf {
+    // Built from `OsStr` pieces so non-UTF-8 names keep their exact bytes
+    let parent = target.parent().map(Path::to_path_buf).unwrap_or_default();
+    let stem = target.file_stem().map(std::ffi::OsStr::to_os_string).unwrap_or_else(|| "image".into());
+    let extension = target.extension().map(std::ffi::OsStr::to_os_string);
+
+    let mut candidate = target;
+    let mut counter = 1;
+    while candidate.exists() && candidate != source {
+        let mut name = stem.clone();
+        name.push(format!(" ({})", counter));
+        if let Some(extension) = &extension {
+            name.push(".");
+            name.push(extension);
+        }
+        candidate = parent.join(name);
+        counter += 1;
+    }
+    candidate
+}
+
+const DEFAULT_OUTPUT_TEMPLATE: &str = "{stem}.{ext}";
+
+/// Expands `{stem}`, `{ext}`, `{parent}` (source folder name) and `{date}` (YYYY-MM-DD).
+fn render_output_template(template: &str, source: &Path, extension: &str) -> Result<String, OptimizeError> {
+    let unrepresentable = || OptimizeError::UnrepresentablePath { path: source.to_string_lossy().to_string() };
+    let stem = match source.file_stem() {
+        Some(stem) => stem.to_str().ok_or_else(unrepresentable)?,
+        None => "image",
+    };
+    let parent = match source.parent().and_then(|p| p.file_name()) {
+        Some(parent) => parent.to_str().ok_or_else(unrepresentable)?,
+        None => "",
+    };
+    let name = template
+        .replace("{stem}", stem)
+        .replace("{ext}", extension)
+        .replace("{parent}", parent)
+        .replace("{date}", &Local::now().format("%Y-%m-%d").to_string());
+
+    if name.contains('{') || name.contains('}') {
+        return Err(OptimizeError::invalid(format!("Unknown token in output template: {}", template)));
+    }
+    if name.trim().is_empty() {
+        return Err(OptimizeError::invalid("Output template produced an empty file name"));
+    }
+    Ok(name)
+}
+
+/// Resolves where a templated output goes. A relative `output_dir` is taken relative to
+/// the source file's folder; missing folders are created.
+fn templated_output_path(
+    source: &Path,
+    extension: &str,
+    template: Option<&str>,
+    output_dir: Option<&Path>,
+) -> Result<PathBuf, OptimizeError> {
+    let source_dir = source.parent().unwrap_or(Path::new("."));
+    let dir = match output_dir {
+        Some(dir) if dir.is_absolute() => dir.to_path_buf(),
+        Some(dir) => source_dir.join(dir),
+        None => source_dir.to_path_buf(),
+    };
+
+    let name = render_output_template(template.unwrap_or(DEFAULT_OUTPUT_TEMPLATE), source, extension)?;
+    let target = dir.join(name);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(next_free_path(target, source))
+}
+
+/// Capture time from raw EXIF (a TIFF structure, with or without the `Exif\0\0` prefix):
+/// DateTimeOriginal from the EXIF sub-IFD, falling back to IFD0's DateTime.
+fn exif_capture_date(exif: &[u8]) -> Option<chrono::NaiveDateTime> {
+    let exif = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+    let big_endian = match exif.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let b = exif.get(offset..offset + 2)?;
+        Some(if big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) })
+    };
+    let u32_at = |offset: usize| {
+        let b = exif.get(offset..offset + 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        let value = if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) };
+        Some(value as usize)
+    };
+    // Offset of the 12-byte entry for `tag` in the IFD at `ifd`
+    let find = |ifd: usize, tag: u16| {
+        let count = u16_at(ifd)? as usize;
+        (0..count).map(|i| ifd + 2 + 12 * i).find(|&entry| u16_at(entry) == Some(tag))
+    };
+    let date = |entry: usize| {
+        let len = u32_at(entry + 4)?;
+        let offset = if len <= 4 { entry + 8 } else { u32_at(entry + 8)? };
+        let text = std::str::from_utf8(exif.get(offset..offset + len)?).ok()?;
+        chrono::NaiveDateTime::parse_from_str(text.trim_end_matches('\0').trim(), "%Y:%m:%d %H:%M:%S").ok()
+    };
+
+    let ifd0 = u32_at(4)?;
+    find(ifd0, 0x8769)
+        .and_then(|entry| u32_at(entry + 8))
+        .and_then(|sub_ifd| find(sub_ifd, 0x9003))
+        .and_then(&date)
+        .or_else(|| find(ifd0, 0x0132).and_then(&date))
+}
+
+/// What `{...}` tokens in a rename pattern can draw on.
+struct RenameFacts {
+    stem: String,
+    date: chrono::NaiveDateTime,
+    width: u32,
+    height: u32,
+}
+
+/// Reads dimensions and capture date (EXIF, else the file's modification time) from the header.
+fn rename_facts(path: &Path) -> Result<RenameFacts, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let mut decoder = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(OptimizeError::decode)?;
+    let (width, height) = decoder.dimensions();
+    let exif_date = decoder.exif_metadata().ok().flatten().and_then(|exif| exif_capture_date(&exif));
+    let date = match exif_date {
+        Some(date) => date,
+        None => chrono::DateTime::<Local>::from(fs::metadata(path)?.modified()?).naive_local(),
+    };
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image").to_string();
+    Ok(RenameFacts { stem, date, width, height })
+}
+
+const DEFAULT_RENAME_DATE_FORMAT: &str = "%Y%m%d";
+const DEFAULT_SEQ_DIGITS: usize = 3;
+
+/// Expands `{stem}`, `{width}`, `{height}`, `{date}` / `{date:<strftime>}` and
+/// `{seq}` / `{seq:<digits>}` (1-based, zero-padded) into a file stem.
+fn render_rename_pattern(pattern: &str, facts: &RenameFacts, seq: usize) -> Result<String, OptimizeError> {
+    let unknown = || OptimizeError::invalid(format!("Invalid token in rename pattern: {}", pattern));
+    let mut name = String::new();
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        name.push_str(&rest[..open]);
+        let close = rest[open..].find('}').ok_or_else(unknown)? + open;
+        let (token, argument) = match rest[open + 1..close].split_once(':') {
+            Some((token, argument)) => (token, Some(argument)),
+            None => (&rest[open + 1..close], None),
+        };
+        match (token, argument) {
+            ("stem", None) => name.push_str(&facts.stem),
+            ("width", None) => name.push_str(&facts.width.to_string()),
+            ("height", None) => name.push_str(&facts.height.to_string()),
+            ("date", format) => {
+                let format = format.unwrap_or(DEFAULT_RENAME_DATE_FORMAT);
+                // chrono panics while formatting an invalid specifier, so check up front
+                let items: Vec<_> = chrono::format::StrftimeItems::new(format).collect();
+                if items.iter().any(|item| matches!(item, chrono::format::Item::Error)) {
+                    return Err(unknown());
+                }
+                name.push_str(&facts.date.format_with_items(items.into_iter()).to_string());
+            }
+            ("seq", digits) => {
+                let digits = match digits {
+                    Some(digits) => digits.parse().map_err(|_| unknown())?,
+                    None => DEFAULT_SEQ_DIGITS,
+                };
+                name.push_str(&format!("{:0width$}", seq, width = digits));
+            }
+            _ => return Err(unknown()),
+        }
+        rest = &rest[close + 1..];
+    }
+    name.push_str(rest);
+
+    if name.contains('}') || name.contains(['/', '\\']) {
+        return Err(unknown());
+    }
+    if name.trim().is_empty() {
+        return Err(OptimizeError::invalid("Rename pattern produced an empty file name"));
+    }
+    Ok(name)
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct RenamedFile {
+    from: String,
+    to: String,
+}
+
+/// Renames each file in place to `pattern` plus its original extension, numbering `{seq}`
+/// in the given order. Every name is worked out before anything is renamed, so a bad
+/// pattern or unreadable metadata fails without moving anything. If a rename fails
+/// midway, the ones already done are undone; any that can't be are named in the error.
+/// Clashes get a ` (n)` suffix.
+fn rename_files_by_metadata(paths: &[String], pattern: &str) -> Result<Vec<RenamedFile>, OptimizeError> {
+    let mut plan = Vec::with_capacity(paths.len());
+    for (index, path) in paths.iter().enumerate() {
+        let path = PathBuf::from(path);
+        let stem = render_rename_pattern(pattern, &rename_facts(&path)?, index + 1)?;
+        let name = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("{}.{}", stem, extension),
+            None => stem,
+        };
+        plan.push((path.clone(), path.with_file_name(name)));
+    }
+
+    let mut renamed: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(plan.len());
+    for (from, to) in plan {
+        let to = next_free_path(to, &from);
+        if to != from {
+            if let Err(error) = fs::rename(&from, &to) {
+                let stuck: Vec<String> = renamed
+                    .iter()
+                    .rev()
+                    .filter(|(done_from, done_to)| done_from != done_to && fs::rename(done_to, done_from).is_err())
+                    .map(|(_, done_to)| done_to.to_string_lossy().to_string())
+                    .collect();
+                return Err(match stuck.is_empty() {
+                    true => OptimizeError::io(format!("{}: {}", from.display(), error)),
+                    false => OptimizeError::io(format!(
+                        "{}: {}; could not undo the rename of {}",
+                        from.display(),
+                        error,
+                        stuck.join(", ")
+                    )),
+                });
+            }
+        }
+        renamed.push((from, to));
+    }
+    Ok(renamed
+        .into_iter()
+        .map(|(from, to)| RenamedFile {
+            from: from.to_string_lossy().to_string(),
+            to: to.to_string_lossy().to_string(),
+        })
+        .collect())
+}
+
+/// Renames files from their metadata, e.g. `{date:%Y%m%d}_IMG_{seq}` -> `20240115_IMG_001.jpg`.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn rename_by_metadata(paths: Vec<String>, pattern: String) -> Result<Vec<RenamedFile>, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || rename_files_by_metadata(&paths, &pattern))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+/// Moves the original aside to a sibling `name.ext.bak`, appending (n) if that is taken.
+fn backup_original(path: &Path) -> Result<PathBuf, OptimizeError> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("image");
+    let mut backup_path = parent.join(format!("{}.bak", name));
+
+    let mut counter = 1;
+    while backup_path.exists() {
+        backup_path = parent.join(format!("{} ({}).bak", name, counter));
+        counter += 1;
+    }
+
+    fs::rename(path, &backup_path)?;
+    Ok(backup_path)
+}
+
+/// Copies the optimized temp file over `target`, backing up an existing target first
+/// when requested. The backup is moved back into place if the copy or verification fails.
+fn write_output(temp_path: &Path, target: &Path, backup: bool, verify: bool) -> Result<Option<PathBuf>, OptimizeError> {
+    // Captured before the original is moved aside, so the new file can inherit its mode
+    let original_metadata = fs::metadata(target).ok();
+
+    // Verification needs a way back, so keep a backup even when the user didn't ask for one
+    let backup_path = if (backup || verify) && original_metadata.is_some() {
+        Some(backup_original(target)?)
+    } else {
+        None
+    };
+
+    let written = match (&original_metadata, &backup_path) {
+        (Some(metadata), None) => ensure_writable(target, metadata),
+        _ => Ok(()),
+    }
+    .and_then(|_| fs::copy(temp_path, target).map_err(OptimizeError::from))
+    .and_then(|_| match &original_metadata {
+        Some(metadata) => restore_attributes(target, metadata),
+        None => Ok(()),
+    })
+    .and_then(|_| if verify { verify_output(target) } else { Ok(()) });
+    if let Err(e) = written {
+        match (&backup_path, &original_metadata) {
+            (Some(backup_path), _) => {
+                let _ = fs::remove_file(target);
+                let _ = fs::rename(backup_path, target);
+            }
+            // Nothing was there before, so don't leave a broken file behind
+            (None, None) => {
+                let _ = fs::remove_file(target);
+            }
+            (None, Some(metadata)) => {
+                let _ = fs::set_permissions(target, metadata.permissions());
+            }
+        }
+        let _ = fs::remove_file(temp_path);
+        return Err(e);
+    }
+    fs::remove_file(temp_path)?;
+
+    match backup_path {
+        Some(backup_path) if !backup => {
+            fs::remove_file(backup_path)?;
+            Ok(None)
+        }
+        backup_path => Ok(backup_path),
+    }
+}
+
+/// Clears the read-only bit so an in-place overwrite can open the file for writing.
+fn ensure_writable(path: &Path, metadata: &fs::Metadata) -> Result<(), OptimizeError> {
+    if !metadata.permissions().readonly() {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(metadata.permissions().mode() | 0o200)
+    };
+    #[cfg(not(unix))]
+    let permissions = {
+        let mut permissions = metadata.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        permissions
+    };
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// Re-applies the original's permissions and, on Unix, its owner and group where allowed.
+fn restore_attributes(path: &Path, metadata: &fs::Metadata) -> Result<(), OptimizeError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Changing the owner needs privileges; fall back to keeping just the group
+        let _ = std::os::unix::fs::chown(path, Some(metadata.uid()), Some(metadata.gid()))
+            .or_else(|_| std::os::unix::fs::chown(path, None, Some(metadata.gid())));
+    }
+    // After chown, which may clear setuid/setgid bits
+    fs::set_permissions(path, metadata.permissions())?;
+    Ok(())
+}
+
+/// Fully decodes a written output to make sure it is a readable image.
+fn verify_output(path: &Path) -> Result<(), OptimizeError> {
+    load_image(path)
+        .map(|_| ())
+        .map_err(|e| OptimizeError::encode(format!("Output failed verification: {}", e)))
+}
+
+/// Moves a `.bak` created by the overwrite backup back over the optimized file.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn restore_backup(backup_path: String, target_path: String) -> Result<String, OptimizeError> {
+    let backup = Path::new(&backup_path);
+    if !backup.is_file() {
+        return Err(OptimizeError::FileNotFound { path: backup_path });
+    }
+
+    let target = Path::new(&target_path);
+    if !target.parent().is_some_and(|parent| parent.as_os_str().is_empty() || parent.is_dir()) {
+        return Err(OptimizeError::invalid(format!(
+            "Cannot restore to {}: its folder no longer exists",
+            target_path
+        )));
+    }
+
+    restore_file(backup, target)?;
+    Ok(target_path)
+}
+
+/// Moves `backup` over `target`.
+fn restore_file(backup: &Path, target: &Path) -> Result<(), OptimizeError> {
+    // rename() fails across volumes, so fall back to copy + delete
+    if fs::rename(backup, target).is_err() {
+        fs::copy(backup, target)?;
+        fs::remove_file(backup)?;
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct QualityReport {
+    /// Mean structural similarity of the luma channel (1.0 = identical).
+    ssim: f64,
+    /// Peak signal-to-noise ratio over RGB in dB; `None` when the images are identical.
+    psnr: Option<f64>,
+    dimensions_match: bool,
+}
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: usize = 4;
+
+/// Mean SSIM over 8x8 windows with a stride of 4, using the standard constants for 8-bit data.
+fn ssim(a: &image::GrayImage, b: &image::GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let (window_w, window_h) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let n = (window_w * window_h) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    for y in (0..=height - window_h).step_by(SSIM_STRIDE) {
+        for x in (0..=width - window_w).step_by(SSIM_STRIDE) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..window_h {
+                for dx in 0..window_w {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn psnr(a: &image::RgbImage, b: &image::RgbImage) -> Option<f64> {
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&pa, &pb)| {
+            let diff = pa as f64 - pb as f64;
+            diff * diff
+        })
+        .sum();
+    let mse = squared_error / a.as_raw().len().max(1) as f64;
+    (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+/// Scores `optimized` against `original`; transparent areas are compared over white.
+fn quality_report(original: &image::DynamicImage, optimized: &image::DynamicImage) -> Result<QualityReport, OptimizeError> {
+    if original.dimensions() != optimized.dimensions() {
+        return Err(OptimizeError::invalid(format!(
+            "Cannot compare images of different dimensions ({}x{} vs {}x{})",
+            original.width(),
+            original.height(),
+            optimized.width(),
+            optimized.height()
+        )));
+    }
+
+    let original_rgb = flatten_alpha(original, DEFAULT_BACKGROUND_COLOR);
+    let optimized_rgb = flatten_alpha(optimized, DEFAULT_BACKGROUND_COLOR);
+    let original_luma = image::DynamicImage::ImageRgb8(original_rgb.clone()).to_luma8();
+    let optimized_luma = image::DynamicImage::ImageRgb8(optimized_rgb.clone()).to_luma8();
+
+    Ok(QualityReport {
+        ssim: ssim(&original_luma, &optimized_luma),
+        psnr: psnr(&original_rgb, &optimized_rgb),
+        dimensions_match: true,
+    })
+}
+
+const AUTO_QUALITY_MAX_ITERATIONS: u32 = 7;
+
+fn resolve_auto_quality(target: Option<f64>) -> Result<Option<f64>, OptimizeError> {
+    match target {
+        Some(t) if !(t > 0.0 && t <= 1.0) => {
+            Err(OptimizeError::invalid(format!("Auto quality target must be in (0, 1], got {}", t)))
+        }
+        _ => Ok(target),
+    }
+}
+
+/// Binary-searches the lowest encoder quality (1-100) whose decoded output keeps
+/// SSIM >= `target` against `reference`. Falls back to quality 100 if none does.
+fn search_quality(
+    reference: &image::DynamicImage,
+    target: f64,
+    mut encode: impl FnMut(u8) -> Result<Vec<u8>, OptimizeError>,
+) -> Result<(u8, Vec<u8>), OptimizeError> {
+    let to_luma = |img: &image::DynamicImage| {
+        image::DynamicImage::ImageRgb8(flatten_alpha(img, DEFAULT_BACKGROUND_COLOR)).to_luma8()
+    };
+    let reference_luma = to_luma(reference);
+
+    let (mut low, mut high) = (1u8, 100u8);
+    let mut best = None;
+    for _ in 0..AUTO_QUALITY_MAX_ITERATIONS {
+        if low > high {
+            break;
+        }
+        let quality = low + (high - low) / 2;
+        let data = encode(quality)?;
+        let decoded = image::load_from_memory(&data).map_err(OptimizeError::decode)?;
+        if ssim(&reference_luma, &to_luma(&decoded)) >= target {
+            best = Some((quality, data));
+            if quality == 1 {
+                break;
+            }
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(best),
+        None => Ok((100, encode(100)?)),
+    }
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn compare_quality(original: String, optimized: String) -> Result<QualityReport, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let original = load_image(Path::new(&original))?;
+        let optimized = load_image(Path::new(&optimized))?;
+        quality_report(&original, &optimized)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// One row of `benchmark_formats`.
+#[derive(serde::Serialize, Clone, Debug)]
+struct FormatBenchmark {
+    format: String,
+    bytes: u64,
+    encode_ms: u64,
+    /// Luma SSIM of the decoded output against the source; `None` if it couldn't be decoded.
+    ssim: Option<f64>,
+    /// Why this format couldn't be encoded; the other rows are still filled in.
+    error: Option<String>,
+}
+
+/// Encodes `source` to each of `formats` at one quality (ignored by lossless formats) and
+/// measures size, encode time and SSIM. Runs one format at a time so timings don't compete.
+fn benchmark_source(
+    source: &[u8],
+    extension: &str,
+    formats: &[String],
+    quality: u8,
+) -> Result<Vec<FormatBenchmark>, OptimizeError> {
+    if !(1..=100).contains(&quality) {
+        return Err(OptimizeError::invalid(format!("Quality must be between 1 and 100, got {}", quality)));
+    }
+    for format in formats {
+        resolve_target_extension(Some(format), extension)?;
+    }
+    let original = decode_image(source, extension)?;
+
+    Ok(formats
+        .iter()
+        .map(|format| {
+            let mut options = OptimizeOptions { convert_to: Some(format.clone()), ..Default::default() };
+            if let Ok(target) = resolve_target_extension(Some(format), extension) {
+                set_target_quality(&mut options, target, quality);
+            }
+            let start = std::time::Instant::now();
+            let encoded = encode_to_target(source, extension, &options);
+            let encode_ms = start.elapsed().as_millis() as u64;
+            match encoded {
+                Ok((target, encoded)) => FormatBenchmark {
+                    format: target.to_string(),
+                    bytes: encoded.data.len() as u64,
+                    encode_ms,
+                    ssim: decode_image(&encoded.data, target)
+                        .ok()
+                        .and_then(|decoded| quality_report(&original, &decoded).ok())
+                        .map(|report| report.ssim),
+                    error: None,
+                },
+                Err(error) => FormatBenchmark {
+                    format: format.clone(),
+                    bytes: 0,
+                    encode_ms,
+                    ssim: None,
+                    error: Some(error.to_string()),
+                },
+            }
+        })
+        .collect())
+}
+
+/// Size/quality table for picking an output format from one representative image.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle), err)]
+async fn benchmark_formats(
+    app_handle: tauri::AppHandle,
+    path: String,
+    formats: Vec<String>,
+    quality: u8,
+) -> Result<Vec<FormatBenchmark>, OptimizeError> {
+    let pool = worker_pool(&app_handle, None)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let path = Path::new(&path);
+        if !path.exists() {
+            return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+        }
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        let source = fs::read(path)?;
+        pool.install(|| benchmark_source(&source, &extension, &formats, quality))
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Downscales both images to the same size (the original's aspect ratio, longest edge at
+/// most `max_edge`) and encodes them as PNG, so a before/after slider lines up even when
+/// the optimized copy was resized.
+fn compare_previews(
+    original: &image::DynamicImage,
+    optimized: &image::DynamicImage,
+    max_edge: u32,
+) -> Result<(Vec<u8>, Vec<u8>), OptimizeError> {
+    if max_edge == 0 {
+        return Err(OptimizeError::invalid("Preview size must be greater than 0"));
+    }
+    let (width, height) = original.dimensions();
+    let scale = (max_edge as f64 / width.max(height) as f64).min(1.0);
+    let width = ((width as f64 * scale).round() as u32).max(1);
+    let height = ((height as f64 * scale).round() as u32).max(1);
+
+    let encode = |img: &image::DynamicImage| -> Result<Vec<u8>, OptimizeError> {
+        let preview = if img.dimensions() == (width, height) {
+            img.clone()
+        } else {
+            img.resize_exact(width, height, FilterType::Triangle)
+        };
+        let mut data = Vec::new();
+        preview
+            .write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png)
+            .map_err(OptimizeError::encode)?;
+        Ok(data)
+    };
+    Ok((encode(original)?, encode(optimized)?))
+}
+
+/// PNG previews of an original and its optimized copy at identical dimensions.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn get_compare_previews(
+    original: String,
+    optimized: String,
+    max_edge: u32,
+) -> Result<(Vec<u8>, Vec<u8>), OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let original = load_image(Path::new(&original))?;
+        let optimized = load_image(Path::new(&optimized))?;
+        compare_previews(&original, &optimized, max_edge)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Longest edge images are shrunk to before quantizing; plenty for picking swatches.
+const PALETTE_SAMPLE_EDGE: u32 = 256;
+const MAX_PALETTE_COLORS: usize = 256;
+
+/// Dominant colors, most common first. Fully transparent pixels are ignored.
+fn dominant_colors(img: &image::DynamicImage, count: usize) -> Result<Vec<[u8; 3]>, OptimizeError> {
+    if !(1..=MAX_PALETTE_COLORS).contains(&count) {
+        return Err(OptimizeError::invalid(format!(
+            "Palette size must be between 1 and {}, got {}",
+            MAX_PALETTE_COLORS, count
+        )));
+    }
+    let sample = img.thumbnail(PALETTE_SAMPLE_EDGE, PALETTE_SAMPLE_EDGE).into_rgba8();
+    let pixels: Vec<u8> = sample
+        .pixels()
+        .filter(|p| p[3] > 0)
+        .flat_map(|p| [p[0], p[1], p[2], 255])
+        .collect();
+    if pixels.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // NeuQuant picks the palette; counting which entry each pixel maps to ranks it
+    let quantizer = color_quant::NeuQuant::new(10, count, &pixels);
+    let mut hits = vec![0usize; count];
+    for pixel in pixels.chunks_exact(4) {
+        hits[quantizer.index_of(pixel)] += 1;
+    }
+    let palette = quantizer.color_map_rgb();
+    let mut ranked: Vec<(usize, [u8; 3])> = palette
+        .chunks_exact(3)
+        .zip(hits)
+        .filter(|(_, hits)| *hits > 0)
+        .map(|(rgb, hits)| (hits, [rgb[0], rgb[1], rgb[2]]))
+        .collect();
+    ranked.sort_by_key(|&(hits, _)| std::cmp::Reverse(hits));
+    Ok(ranked.into_iter().map(|(_, rgb)| rgb).collect())
+}
+
+/// Returns up to `count` representative colors of an image for swatches.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn extract_palette(path: String, count: usize) -> Result<Vec<[u8; 3]>, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || dominant_colors(&load_image(Path::new(&path))?, count))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+/// Rough size prediction from header data only; see `estimate_savings`.
+#[derive(serde::Serialize, Debug)]
+struct EstimatedSavings {
+    /// Always true: nothing was encoded, so treat the numbers as a hint.
+    is_estimate: bool,
+    format: String,
+    width: u32,
+    height: u32,
+    size_bytes: u64,
+    bytes_per_pixel: f64,
+    estimated_bytes: u64,
+    /// 0-100.
+    estimated_savings_percent: f64,
+}
+
+/// Typical bytes per pixel after optimization, and the most a same-format pass
+/// realistically saves, per source format. Uncompressed formats assume a PNG conversion.
+fn expected_density(format: &str) -> Option<(f64, f64)> {
+    match format {
+        "jpg" | "jpeg" => Some((0.35, 60.0)),
+        "png" => Some((1.2, 40.0)),
+        "webp" => Some((0.25, 30.0)),
+        "avif" | "jxl" => Some((0.15, 20.0)),
+        "gif" => Some((0.5, 30.0)),
+        "bmp" | "tga" | "pnm" | "ff" | "tiff" | "tif" => Some((1.2, 90.0)),
+        _ => None,
+    }
+}
+
+fn estimate_from_header(format: &str, width: u32, height: u32, size_bytes: u64) -> Result<EstimatedSavings, OptimizeError> {
+    let (expected_bpp, max_savings) =
+        expected_density(format).ok_or_else(|| OptimizeError::UnsupportedFormat { format: format.to_string() })?;
+    let pixels = (width as f64 * height as f64).max(1.0);
+    let bytes_per_pixel = size_bytes as f64 / pixels;
+    let savings = ((1.0 - expected_bpp / bytes_per_pixel) * 100.0).clamp(0.0, max_savings);
+    Ok(EstimatedSavings {
+        is_estimate: true,
+        format: format.to_string(),
+        width,
+        height,
+        size_bytes,
+        bytes_per_pixel,
+        estimated_bytes: (size_bytes as f64 * (1.0 - savings / 100.0)).round() as u64,
+        estimated_savings_percent: savings,
+    })
+}
+
+/// Predicts savings from the format, dimensions and file size alone, without decoding
+/// pixels, so whole libraries can be triaged quickly.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn estimate_savings(path: String) -> Result<EstimatedSavings, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let file = Path::new(&path);
+        if !file.exists() {
+            return Err(OptimizeError::FileNotFound { path });
+        }
+        let format = file.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        let size_bytes = fs::metadata(file)?.len();
+        // Only reads the header
+        let (width, height) = image::image_dimensions(file).map_err(decode_error)?;
+        estimate_from_header(&format, width, height, size_bytes)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+#[derive(serde::Serialize)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    color_type: String,
+    format: String,
+    has_alpha: bool,
+    /// Bits per channel.
+    bit_depth: u16,
+    frame_count: u32,
+}
+
+impl ImageInfo {
+    fn new(format: &str, (width, height): (u32, u32), color_type: image::ColorType, frame_count: u32) -> Self {
+        ImageInfo {
+            width,
+            height,
+            color_type: format!("{:?}", color_type),
+            format: format.to_string(),
+            has_alpha: color_type.has_alpha(),
+            bit_depth: color_type.bits_per_pixel() / color_type.channel_count() as u16,
+            frame_count,
+        }
+    }
+}
+
+/// Counts frames for formats that can animate. GIF frames are walked without decoding
+/// pixel data; animated PNG/WebP have to be decoded frame by frame.
+fn count_frames(path: &Path, format: image::ImageFormat) -> Result<u32, OptimizeError> {
+    let open = || -> Result<_, OptimizeError> { Ok(std::io::BufReader::new(fs::File::open(path)?)) };
+    let frames = match format {
+        image::ImageFormat::Gif => {
+            let mut options = gif::DecodeOptions::new();
+            options.set_color_output(gif::ColorOutput::Indexed);
+            let mut decoder = options.read_info(open()?).map_err(OptimizeError::decode)?;
+            let mut count = 0;
+            while decoder.next_frame_info().map_err(OptimizeError::decode)?.is_some() {
+                count += 1;
+            }
+            count
+        }
+        image::ImageFormat::Png => {
+            let decoder = image::codecs::png::PngDecoder::new(open()?).map_err(OptimizeError::decode)?;
+            if !decoder.is_apng().map_err(OptimizeError::decode)? {
+                return Ok(1);
+            }
+            let apng = decoder.apng().map_err(OptimizeError::decode)?;
+            apng.into_frames().count() as u32
+        }
+        image::ImageFormat::WebP => {
+            let decoder = image::codecs::webp::WebPDecoder::new(open()?).map_err(OptimizeError::decode)?;
+            if !decoder.has_animation() {
+                return Ok(1);
+            }
+            decoder.into_frames().count() as u32
+        }
+        _ => 1,
+    };
+    Ok(frames)
+}
+
+/// Reads dimensions and color type from the header, without decoding the pixels.
+fn image_info(path: &Path) -> Result<ImageInfo, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let reader = image::ImageReader::open(path)?.with_guessed_format()?;
+    let Some(format) = reader.format() else {
+        if extension == "jxl" {
+            // No header-only probe for JXL; decode it fully
+            let img = decode_jxl(&fs::read(path)?)?;
+            return Ok(ImageInfo::new("jxl", img.dimensions(), img.color(), 1));
+        }
+        if matches!(extension.as_str(), "heic" | "heif") {
+            let img = decode_heif(&fs::read(path)?)?;
+            return Ok(ImageInfo::new("heic", img.dimensions(), img.color(), 1));
+        }
+        return Err(OptimizeError::UnsupportedFormat { format: extension });
+    };
+
+    let decoder = reader.into_decoder().map_err(OptimizeError::decode)?;
+    let (dimensions, color_type) = (decoder.dimensions(), decoder.color_type());
+    drop(decoder);
+
+    let name = format.extensions_str().first().copied().unwrap_or("unknown");
+    Ok(ImageInfo::new(name, dimensions, color_type, count_frames(path, format)?))
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn get_image_info(path: String) -> Result<ImageInfo, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || image_info(Path::new(&path)))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+/// EXIF fields of `path` as (tag name, readable value) pairs in file order, skipping the
+/// embedded thumbnail's IFD. Formats that can't carry EXIF, and files without it, give
+/// an empty list.
+fn exif_fields(path: &Path) -> Result<Vec<(String, String)>, OptimizeError> {
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+    let data = fs::read(path)?;
+    let container = matches!(
+        image::guess_format(&data),
+        Ok(image::ImageFormat::Jpeg | image::ImageFormat::Tiff | image::ImageFormat::Png | image::ImageFormat::WebP | image::ImageFormat::Avif)
+    );
+    if !container {
+        return Ok(Vec::new());
+    }
+
+    // Keep whatever parsed when some fields are malformed
+    let read = exif::Reader::new()
+        .continue_on_error(true)
+        .read_from_container(&mut std::io::Cursor::new(&data))
+        .or_else(|e| {
+            e.distill_partial_result(|errors| {
+                for error in errors {
+                    tracing::warn!(path = %path.display(), %error, "skipping malformed EXIF field");
+                }
+            })
+        });
+    let exif = match read {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(OptimizeError::decode(e)),
+    };
+    Ok(exif
+        .fields()
+        .filter(|field| field.ifd_num == exif::In::PRIMARY)
+        .map(|field| (field.tag.to_string(), field.display_value().with_unit(&exif).to_string()))
+        .collect())
+}
+
+/// EXIF metadata for a metadata panel: camera, lens, exposure, GPS and so on.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn read_exif(path: String) -> Result<Vec<(String, String)>, OptimizeError> {
+    tauri::async_runtime::spawn_blocking(move || exif_fields(Path::new(&path)))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+const THUMBNAIL_QUALITY: f32 = 75.0;
+
+/// Cache file name derived from the source path, its mtime and the requested size,
+/// so edits to the source or a different size produce a fresh thumbnail.
+fn thumbnail_cache_key(path: &Path, modified: std::time::SystemTime, max_edge: u32) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.hash(&mut hasher);
+    modified.hash(&mut hasher);
+    max_edge.hash(&mut hasher);
+    format!("{:016x}.webp", hasher.finish())
+}
+
+fn render_thumbnail(path: &Path, max_edge: u32, cache_dir: &Path) -> Result<Vec<u8>, OptimizeError> {
+    if max_edge == 0 {
+        return Err(OptimizeError::invalid("Thumbnail size must be greater than 0"));
+    }
+    if !path.exists() {
+        return Err(OptimizeError::FileNotFound { path: path.to_string_lossy().to_string() });
+    }
+
+    let modified = fs::metadata(path)?.modified()?;
+    let cache_path = cache_dir.join(thumbnail_cache_key(path, modified, max_edge));
+    if let Ok(cached) = fs::read(&cache_path) {
+        return Ok(cached);
+    }
+
+    let img = load_image(path)?;
+    let thumbnail = if img.width() > max_edge || img.height() > max_edge {
+        img.thumbnail(max_edge, max_edge)
+    } else {
+        img
+    };
+    let data = encode_lossy_webp(&thumbnail, THUMBNAIL_QUALITY)?;
+
+    // A failed cache write only costs a re-render next time
+    if fs::create_dir_all(cache_dir).is_ok() {
+        let _ = fs::write(&cache_path, &data);
+    }
+    Ok(data)
+}
+
+/// Returns WebP bytes for a grid thumbnail no larger than `max_edge` on either side.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle), err)]
+async fn generate_thumbnail(app_handle: tauri::AppHandle, path: String, max_edge: u32) -> Result<Vec<u8>, OptimizeError> {
+    let cache_dir = app_handle
+        .path()
+        .app_cache_dir()
+        .map_err(OptimizeError::internal)?
+        .join("thumbnails");
+    tauri::async_runtime::spawn_blocking(move || render_thumbnail(Path::new(&path), max_edge, &cache_dir))
+        .await
+        .map_err(OptimizeError::internal)?
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn backup_files(paths: Vec<String>) -> Result<String, OptimizeError> {
+    if paths.is_empty() {
+        return Err(OptimizeError::invalid("No files to backup"));
+    }
+
+    let first_path = Path::new(&paths[0]);
+    let parent_dir = first_path.parent().unwrap_or(Path::new("."));
+    
+    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+    let zip_name = format!("sqsh-backup-{}.zip", timestamp);
+    let zip_path = parent_dir.join(&zip_name);
+    
+    let file = fs::File::create(&zip_path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = FileOptions::<()>::default().compression_method(zip::CompressionMethod::Stored);
+
+    let mut added_files = false;
+
+    for path_str in paths {
+        let path = Path::new(&path_str);
+        let base = path.parent().unwrap_or(Path::new("."));
+        
+        if path.is_file() {
+             if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
+                    let name = path
+                        .file_name()
+                        .ok_or_else(|| OptimizeError::invalid("Invalid file name"))?;
+                    zip.start_file(path_string(Path::new(name))?, options)?;
+                    let content = fs::read(path)?;
+                    zip.write_all(&content)?;
+                    added_files = true;
+                }
+             }
+        } else if path.is_dir() {
+            for entry in WalkDir::new(path) {
+                let entry = entry.map_err(OptimizeError::io)?;
+                let entry_path = entry.path();
+                
+                if entry_path.is_file() {
+                    if let Some(ext) = entry_path.extension().and_then(|e| e.to_str()) {
+                        if SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
+                            let name = entry_path.strip_prefix(base).map_err(OptimizeError::io)?;
+                            // A lossy entry name would restore to the wrong file
+                            let name_str = path_string(name)?.replace('\\', "/");
+                            
+                            zip.start_file(name_str, options)?;
+                            let content = fs::read(entry_path)?;
+                            zip.write_all(&content)?;
+                            added_files = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    
+    zip.finish()?;
+
+    if !added_files {
+        // If zip is empty/no valid files found, remove it
+        let _ = fs::remove_file(&zip_path);
+        return Err(OptimizeError::invalid("No supported images found to backup"));
+    }
+
+    Ok(zip_path.to_string_lossy().to_string())
+}
+
+/// Maps the requested compression onto zip entry options. Defaults to Stored,
+/// since re-compressing already-compressed images rarely helps.
+fn zip_file_options(method: Option<&str>, level: Option<i64>) -> Result<FileOptions<'static, ()>, OptimizeError> {
+    let method = match method.unwrap_or("stored") {
+        "stored" => zip::CompressionMethod::Stored,
+        "deflated" => zip::CompressionMethod::Deflated,
+        "zstd" => zip::CompressionMethod::Zstd,
+        "bzip2" => zip::CompressionMethod::Bzip2,
+        other => {
+            return Err(OptimizeError::invalid(format!("Unsupported zip compression method: {}", other)))
+        }
+    };
+    Ok(FileOptions::<()>::default()
+        .compression_method(method)
+        .compression_level(level))
+}
+
+#[derive(serde::Serialize, Clone)]
+struct ZipProgress {
+    completed: usize,
+    total: usize,
+    current_path: String,
+}
+
+/// Nests each entry under the folder its file sits in relative to `base_dir`, keeping the
+/// file name from the requested name, so `a/1.png` and `b/1.png` stay distinct.
+fn structured_zip_entries(files: Vec<(String, String)>, base_dir: &Path) -> Result<Vec<(String, String)>, OptimizeError> {
+    files
+        .into_iter()
+        .map(|(fs_path, desired_name)| {
+            let relative = Path::new(&fs_path).strip_prefix(base_dir).map_err(|_| {
+                OptimizeError::invalid(format!("{} is not inside {}", fs_path, base_dir.display()))
+            })?;
+            let folders: Vec<String> = relative
+                .parent()
+                .into_iter()
+                .flat_map(|parent| parent.components())
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let name = Path::new(&desired_name)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or(desired_name);
+            let name_in_zip = folders.into_iter().chain([name]).collect::<Vec<_>>().join("/");
+            Ok((fs_path, name_in_zip))
+        })
+        .collect()
+}
+
+/// Zips `(path on disk, name in zip)` pairs, emitting `zip://progress` after each file.
+/// `cancel_optimization` stops it between files and removes the partial archive. With
+/// `base_dir`, entries mirror the source tree instead of all landing in the root.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, files), fields(count = files.len()), err)]
+async fn zip_files(
+    app_handle: tauri::AppHandle,
+    files: Vec<(String, String)>,
+    output_path: String,
+    method: Option<String>,
+    level: Option<i64>,
+    base_dir: Option<String>,
+) -> Result<String, OptimizeError> {
+    // Validate before creating the archive so a bad method doesn't leave an empty zip behind
+    let options = zip_file_options(method.as_deref(), level)?;
+    let files = match base_dir {
+        Some(base_dir) => structured_zip_entries(files, Path::new(&base_dir))?,
+        None => files,
+    };
+    tauri::async_runtime::spawn_blocking(move || {
+        let cancel_flag = app_handle.state::<CancelFlag>();
+        cancel_flag.0.store(false, Ordering::SeqCst);
+        let total = files.len();
+        write_zip(files, Path::new(&output_path), options, Some(&cancel_flag.0), |completed, path| {
+            let _ = app_handle.emit("zip://progress", ZipProgress {
+                completed,
+                total,
+                current_path: path.to_string(),
+            });
+        })?;
+        Ok(output_path)
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+/// Writes `(path on disk, name in zip)` pairs to a new archive, renaming duplicate names.
+/// `on_progress` gets the running count and the file just added. If `cancel` gets set,
+/// or anything fails, the partial archive is deleted.
+fn write_zip(
+    files: Vec<(String, String)>,
+    path: &Path,
+    options: FileOptions<'static, ()>,
+    cancel: Option<&AtomicBool>,
+    on_progress: impl FnMut(usize, &str),
+) -> Result<(), OptimizeError> {
+    let written = write_zip_entries(files, path, options, cancel, on_progress);
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+fn write_zip_entries(
+    files: Vec<(String, String)>,
+    path: &Path,
+    options: FileOptions<'static, ()>,
+    cancel: Option<&AtomicBool>,
+    mut on_progress: impl FnMut(usize, &str),
+) -> Result<(), OptimizeError> {
+    let file = fs::File::create(path)?;
+    let mut zip = zip::ZipWriter::new(file);
+
+    let mut used_names = std::collections::HashSet::new();
+
+    for (index, (fs_path, desired_name)) in files.into_iter().enumerate() {
+        if cancel.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            return Err(OptimizeError::Cancelled);
+        }
+
+        let path = Path::new(&fs_path);
+        
+        // Handle conflicts
+        let mut name_in_zip = desired_name.clone();
+        let mut counter = 1;
+        
+        while used_names.contains(&name_in_zip) {
+            // Keep any folder prefix so the renamed entry stays where it was headed
+            let (folder, file_name) = match desired_name.rsplit_once('/') {
+                Some((folder, file_name)) => (format!("{}/", folder), file_name),
+                None => (String::new(), desired_name.as_str()),
+            };
+            let path_obj = Path::new(file_name);
+            let stem = path_obj.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+            let ext = path_obj.extension().and_then(|s| s.to_str()).unwrap_or("");
+            
+            name_in_zip = if ext.is_empty() {
+                format!("{}{} ({})", folder, stem, counter)
+            } else {
+                format!("{}{} ({}).{}", folder, stem, counter, ext)
+            };
+            counter += 1;
+        }
+        
+        used_names.insert(name_in_zip.clone());
+        
+        zip.start_file(name_in_zip, options)?;
+        // Stream the file so memory stays bounded regardless of its size
+        let mut source = fs::File::open(path)?;
+        std::io::copy(&mut source, &mut zip)?;
+        on_progress(index + 1, &fs_path);
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Optimizes `files` into temp outputs, then zips them under the source base names
+/// with the output format's extension. Files that fail are left out of the archive and
+/// reported next to the results, whose `output_path` is the entry name. Temps are
+/// removed whether or not zipping succeeds.
+fn optimize_to_zip(
+    files: &[String],
+    options: &OptimizeOptions,
+    output_zip: &Path,
+    on_progress: impl Fn(usize, &OptimizationResult) + Sync,
+    on_failure: impl Fn(&BatchFailure) + Sync,
+) -> Result<(Vec<OptimizationResult>, Vec<BatchFailure>), OptimizeError> {
+    // Outputs must land in temp files, never over the sources
+    let options = OptimizeOptions {
+        overwrite: false,
+        output_template: None,
+        output_dir: None,
+        mirror: None,
+        backup: None,
+        ..options.clone()
+    };
+    let outcomes: Vec<_> = files
+        .par_iter()
+        .enumerate()
+        .map(|(index, file_path)| {
+            let outcome = optimize_core(Path::new(file_path), &options);
+            log_outcome(file_path, &options, &outcome);
+            match &outcome {
+                Ok(result) => on_progress(index, result),
+                Err(error) => on_failure(&BatchFailure { path: file_path.clone(), error: error.clone() }),
+            }
+            outcome
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(files.len());
+    let mut failed = Vec::new();
+    let mut entries = Vec::with_capacity(files.len());
+    let mut temps = Vec::new();
+    for (file_path, outcome) in files.iter().zip(outcomes) {
+        let mut result = match outcome {
+            Ok(result) => result,
+            Err(error) => {
+                failed.push(BatchFailure { path: file_path.clone(), error });
+                continue;
+            }
+        };
+        let stem = Path::new(file_path).file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+        let ext = Path::new(&result.output_path).extension().and_then(|s| s.to_str()).unwrap_or("");
+        let entry_name = format!("{}.{}", stem, ext);
+        if result.output_path != *file_path {
+            temps.push(result.output_path.clone());
+        }
+        entries.push((std::mem::replace(&mut result.output_path, entry_name.clone()), entry_name));
+        results.push(result);
+    }
+    let zipped = zip_file_options(None, None).and_then(|zip_options| write_zip(entries, output_zip, zip_options, None, |_, _| {}));
+    for temp in temps {
+        let _ = fs::remove_file(temp);
+    }
+    zipped?;
+    Ok((results, failed))
+}
+
+/// Outcome of `optimize_and_zip`.
+#[derive(serde::Serialize, Clone)]
+struct ZipBatch {
+    output_zip: String,
+    /// One per zipped file; `output_path` is the name of its entry in the archive.
+    results: Vec<OptimizationResult>,
+    /// Files that couldn't be optimized and were left out of the archive.
+    failed: Vec<BatchFailure>,
+}
+
+/// Optimizes `files` and packs the results into `output_zip` in one step, emitting
+/// `optimize://progress` and `optimize://failed` like `optimize_images`. Sources are
+/// left untouched.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, files, options), fields(count = files.len()), err)]
+async fn optimize_and_zip(
+    app_handle: tauri::AppHandle,
+    files: Vec<String>,
+    options: OptimizeOptions,
+    output_zip: String,
+) -> Result<ZipBatch, OptimizeError> {
+    let options = options.with_config(&app_handle);
+    let pool = worker_pool(&app_handle, None)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let total = files.len();
+        let finished = AtomicUsize::new(0);
+        let on_progress = |index: usize, result: &OptimizationResult| {
+            let completed = finished.fetch_add(1, Ordering::SeqCst) + 1;
+            let _ = app_handle.emit("optimize://progress", BatchProgress {
+                index,
+                completed,
+                total,
+                current_path: files[index].clone(),
+                result: result.clone(),
+            });
+        };
+        let on_failure = |failure: &BatchFailure| {
+            finished.fetch_add(1, Ordering::SeqCst);
+            let _ = app_handle.emit("optimize://failed", failure.clone());
+        };
+        let (results, failed) =
+            pool.install(|| optimize_to_zip(&files, &options, Path::new(&output_zip), on_progress, on_failure))?;
+        record_stats(&app_handle, &results);
+        Ok(ZipBatch { output_zip, results, failed })
+    })
+    .await
+    .map_err(OptimizeError::internal)?
+}
+
+#[derive(serde::Serialize, Clone)]
+struct ScanEntry {
+    path: String,
+    size_bytes: u64,
+    extension: String,
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+struct ScanResult {
+    files: Vec<ScanEntry>,
+    total_files: usize,
+    total_bytes: u64,
+    /// Supported files left out because their path isn't valid UTF-8 (shown lossily).
+    unrepresentable: Vec<String>,
+}
+
+impl ScanResult {
+    fn push(&mut self, entry: ScanEntry) {
+        self.total_files += 1;
+        self.total_bytes += entry.size_bytes;
+        self.files.push(entry);
+    }
+}
+
+/// Include/exclude glob filter for `scan_directory`. Patterns are matched against both
+/// the full path and the file name, so `thumb_*` and `**/node_modules/**` both work.
+struct ScanFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    /// Decide the format from the file's magic bytes instead of its extension.
+    detect_by_content: bool,
+}
+
+/// Bytes read from each file when detecting by content; enough for every signature
+/// `image::guess_format` knows.
+const CONTENT_SNIFF_BYTES: u64 = 32;
+
+impl ScanFilter {
+    fn new(include_globs: &[String], exclude_globs: &[String]) -> Result<Self, OptimizeError> {
+        Ok(Self {
+            include: build_globset(include_globs)?,
+            exclude: build_globset(exclude_globs)?,
+            detect_by_content: false,
+        })
+    }
+
+    /// The supported format of a file that passes the globs, if any. With content
+    /// detection the magic bytes win; the extension is the fallback for formats that
+    /// have no signature `image` recognizes (JPEG XL, TGA).
+    fn format_of(&self, path: &Path) -> Option<String> {
+        if !self.accepts(path) {
+            return None;
+        }
+        let by_name = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase)
+            .filter(|ext| SUPPORTED_EXTENSIONS.contains(&ext.as_str()));
+        if !self.detect_by_content {
+            return by_name;
+        }
+        sniff_format(path).or(by_name)
+    }
+
+    /// Excludes always win; an empty include list accepts every supported file.
+    fn accepts(&self, path: &Path) -> bool {
+        let matches = |set: &GlobSet| {
+            set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name))
+        };
+        if self.exclude.as_ref().is_some_and(matches) {
+            return false;
+        }
+        self.include.as_ref().is_none_or(matches)
+    }
+}
+
+/// Guesses a supported format from the first few bytes of `path`.
+fn sniff_format(path: &Path) -> Option<String> {
+    let mut header = Vec::new();
+    fs::File::open(path).ok()?.take(CONTENT_SNIFF_BYTES).read_to_end(&mut header).ok()?;
+    if is_heif(&header) {
+        return Some("heic".to_string());
+    }
+    let format = image::guess_format(&header).ok()?;
+    if format == image::ImageFormat::Pnm {
+        return Some("pnm".to_string());
+    }
+    format
+        .extensions_str()
+        .iter()
+        .find(|ext| SUPPORTED_EXTENSIONS.contains(ext))
+        .map(|ext| ext.to_string())
+}
+
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>, OptimizeError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|e| OptimizeError::invalid(format!("Invalid glob '{}': {}", pattern, e)))?;
+        builder.add(glob);
+    }
+    let set = builder.build().map_err(|e| OptimizeError::invalid(e.to_string()))?;
+    Ok(Some(set))
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(app_handle), err)]
+async fn scan_directory(
+    app_handle: tauri::AppHandle,
+    paths: Vec<String>,
+    include_globs: Option<Vec<String>>,
+    exclude_globs: Option<Vec<String>>,
+    max_depth: Option<usize>,
+    follow_symlinks: Option<bool>,
+    detect_by_content: Option<bool>,
+) -> Result<ScanResult, OptimizeError> {
+    let filter = ScanFilter {
+        detect_by_content: detect_by_content.unwrap_or(false),
+        ..ScanFilter::new(&include_globs.unwrap_or_default(), &exclude_globs.unwrap_or_default())?
+    };
+    remember_folders(&app_handle, &paths);
+    scan_paths(paths, &filter, max_depth, follow_symlinks.unwrap_or(false))
+}
+
+const RECENT_FOLDERS_LIMIT: usize = 10;
+
+/// Moves the directories among `paths` to the front of `recent`, most recent first.
+fn push_recent_folders(recent: &mut Vec<String>, paths: &[String]) {
+    for path in paths.iter().filter(|p| Path::new(p).is_dir()) {
+        recent.retain(|existing| existing != path);
+        recent.insert(0, path.clone());
+    }
+    recent.truncate(RECENT_FOLDERS_LIMIT);
+}
+
+fn remember_folders(app_handle: &tauri::AppHandle, paths: &[String]) {
+    let state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+    let mut config = state.lock().unwrap();
+    let before = config.recent_folders.clone();
+    push_recent_folders(&mut config.recent_folders, paths);
+    if config.recent_folders != before {
+        save_config(app_handle, &config);
+    }
+}
+
+/// Recently scanned folders, most recent first. Folders that no longer exist are dropped.
+#[tauri::command]
+async fn get_recent_folders(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, std::sync::Mutex<AppConfig>>,
+) -> Result<Vec<String>, OptimizeError> {
+    let mut config = state.lock().unwrap();
+    let count = config.recent_folders.len();
+    config.recent_folders.retain(|path| Path::new(path).is_dir());
+    if config.recent_folders.len() != count {
+        save_config(&app_handle, &config);
+    }
+    Ok(config.recent_folders.clone())
+}
+
+#[tauri::command]
+async fn clear_recent_folders(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, std::sync::Mutex<AppConfig>>,
+) -> Result<(), OptimizeError> {
+    let mut config = state.lock().unwrap();
+    config.recent_folders.clear();
+    save_config(&app_handle, &config);
+    Ok(())
+}
+
+/// Blocking walk behind `scan_directory`, shared with `optimize_directory`.
+fn scan_paths(
+    paths: Vec<String>,
+    filter: &ScanFilter,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+) -> Result<ScanResult, OptimizeError> {
+    let mut result = ScanResult::default();
+
+    for path_str in paths {
+        let path = Path::new(&path_str);
+        if path.is_file() {
+            if let Some(ext) = filter.format_of(path) {
+                let size_bytes = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                result.push(ScanEntry { path: path_str, size_bytes, extension: ext });
+            }
+        } else if path.is_dir() {
+            // `Some(1)` limits the walk to the directory's immediate contents
+            let mut walker = WalkDir::new(path).follow_links(follow_symlinks);
+            if let Some(depth) = max_depth {
+                walker = walker.max_depth(depth);
+            }
+
+            for entry in walker {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    // WalkDir detects symlink cycles when following links; report them
+                    // instead of silently dropping the subtree
+                    Err(e) if e.loop_ancestor().is_some() => return Err(OptimizeError::io(e)),
+                    Err(_) => continue,
+                };
+                let entry_path = entry.path();
+                if entry_path.is_file() {
+                    if let Some(ext) = filter.format_of(entry_path) {
+                        // WalkDir already holds the directory entry, so this is cheap
+                        let size_bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                        match entry_path.to_str() {
+                            Some(path) => result.push(ScanEntry {
+                                path: path.to_string(),
+                                size_bytes,
+                                extension: ext,
+                            }),
+                            // A lossy path would point at a file that doesn't exist
+                            None => result.unrepresentable.push(entry_path.to_string_lossy().to_string()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn save_file(src_path: String, dest_path: String) -> Result<(), OptimizeError> {
+    fs::copy(&src_path, &dest_path)?;
+    Ok(())
+}
+
+/// Opens the folder containing `path` in the OS file manager, selecting the file where supported.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn reveal_in_file_manager(path: String) -> Result<(), OptimizeError> {
+    if !Path::new(&path).exists() {
+        return Err(OptimizeError::FileNotFound { path });
+    }
+    tauri_plugin_opener::reveal_item_in_dir(&path).map_err(OptimizeError::io)
+}
+
+#[tauri::command]
+async fn get_config(state: tauri::State<'_, std::sync::Mutex<AppConfig>>) -> Result<AppConfig, OptimizeError> {
+    Ok(state.lock().unwrap().clone())
+}
+
+/// Entries kept in the history log; the oldest are dropped first.
+const HISTORY_LIMIT: usize = 1000;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct HistoryEntry {
+    timestamp: String,
+    input_path: String,
+    output_path: String,
+    original_size: u64,
+    new_size: u64,
+    skipped: bool,
+    /// `.bak` of the file the output replaced, if a backup was taken.
+    #[serde(default)]
+    backup_path: Option<String>,
+    /// Shared by every file of one run; entries from before it existed group by timestamp.
+    #[serde(default)]
+    run_id: Option<String>,
+}
+
+impl HistoryEntry {
+    fn run_key(&self) -> &str {
+        self.run_id.as_deref().unwrap_or(&self.timestamp)
+    }
+}
+
+struct History(std::sync::Mutex<std::collections::VecDeque<HistoryEntry>>);
+
+fn history_path(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    let config_dir = app_handle.path().config_dir().ok()?;
+    Some(config_dir.join("sqsh").join("history.json"))
+}
+
+fn load_history(app_handle: &tauri::AppHandle) -> std::collections::VecDeque<HistoryEntry> {
+    history_path(app_handle)
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_history(app_handle: &tauri::AppHandle, entries: &std::collections::VecDeque<HistoryEntry>) {
+    if let Some(path) = history_path(app_handle) {
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::write(path, serde_json::to_string(entries).unwrap_or_default());
+    }
+}
+
+/// Appends processed files to the on-disk history, trimming it to `HISTORY_LIMIT`.
+fn record_history<'a>(app_handle: &tauri::AppHandle, items: impl IntoIterator<Item = (&'a str, &'a OptimizationResult)>) {
+    let state: tauri::State<History> = app_handle.state();
+    let mut entries = state.0.lock().unwrap();
+    let timestamp = Local::now().to_rfc3339();
+    let run_id = uuid::Uuid::new_v4().to_string();
+    for (input_path, result) in items {
+        entries.push_back(HistoryEntry {
+            timestamp: timestamp.clone(),
+            input_path: input_path.to_string(),
+            output_path: result.output_path.clone(),
+            original_size: result.original_size,
+            new_size: result.new_size,
+            skipped: result.skipped,
+            backup_path: result.backup_path.clone(),
+            run_id: Some(run_id.clone()),
+        });
+    }
+    while entries.len() > HISTORY_LIMIT {
+        entries.pop_front();
+    }
+    save_history(app_handle, &entries);
+}
+
+/// Size and modification time of a file, used to tell whether it changed since it
+/// was last optimized.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+struct FileStamp {
+    size: u64,
+    modified_ms: u64,
+}
+
+fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(FileStamp { size: metadata.len(), modified_ms: modified.as_millis() as u64 })
+}
+
+/// Entries kept in `optimized.json`; the least recently recorded are dropped first.
+const MARKERS_LIMIT: usize = 10_000;
+
+/// A source file as it was after sqsh last processed it, and the settings it used.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+struct OptimizedMarker {
+    stamp: FileStamp,
+    /// `OptimizeOptions::output_fingerprint` of that run.
+    options: u32,
+    recorded_ms: u64,
+}
+
+/// Files sqsh already processed, keyed by source path, so unchanged files can be skipped
+/// on re-runs with the same settings.
+struct OptimizedMarkers(std::sync::Mutex<std::collections::HashMap<String, OptimizedMarker>>);
+
+fn markers_path(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    let config_dir = app_handle.path().config_dir().ok()?;
+    Some(config_dir.join("sqsh").join("optimized.json"))
+}
+
+fn load_markers(app_handle: &tauri::AppHandle) -> std::collections::HashMap<String, OptimizedMarker> {
+    markers_path(app_handle)
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Returns a skipped result when `path` is unchanged since sqsh last processed it with
+/// the same output settings. Only same-format runs short-circuit; conversions and
+/// `force` always re-encode.
+fn already_optimized(app_handle: &tauri::AppHandle, path: &str, options: &OptimizeOptions) -> Option<OptimizationResult> {
+    if options.force.unwrap_or(false) || options.convert_to.is_some() {
+        return None;
+    }
+    let stamp = file_stamp(Path::new(path))?;
+    let state: tauri::State<OptimizedMarkers> = app_handle.state();
+    let marker = state.0.lock().unwrap().get(path).copied()?;
+    if marker.stamp != stamp || marker.options != options.output_fingerprint() {
+        return None;
+    }
+    Some(OptimizationResult {
+        original_size: stamp.size,
+        new_size: stamp.size,
+        saved_bytes: 0,
+        output_path: path.to_string(),
+        skipped: true,
+        duration_ms: 0,
+        backup_path: None,
+        chosen_quality: None,
+        warnings: Vec::new(),
+        webp_mode: None,
+        png_strategy: None,
+        chosen_format: None,
+    })
+}
+
+/// Marks each processed source as optimized with `options`, keyed by the size and mtime
+/// it has now (after an overwrite, those of the new file). Conversions are never
+/// skipped, so they aren't recorded. Blocking: it rewrites `optimized.json`.
+fn record_markers<'a>(app_handle: &tauri::AppHandle, options: &OptimizeOptions, input_paths: impl IntoIterator<Item = &'a str>) {
+    if options.convert_to.is_some() {
+        return;
+    }
+    let fingerprint = options.output_fingerprint();
+    let recorded_ms = Local::now().timestamp_millis() as u64;
+    let state: tauri::State<OptimizedMarkers> = app_handle.state();
+    let mut markers = state.0.lock().unwrap();
+    for path in input_paths {
+        if let Some(stamp) = file_stamp(Path::new(path)) {
+            markers.insert(path.to_string(), OptimizedMarker { stamp, options: fingerprint, recorded_ms });
+        }
+    }
+    if markers.len() > MARKERS_LIMIT {
+        let mut by_age: Vec<(u64, String)> = markers.iter().map(|(path, m)| (m.recorded_ms, path.clone())).collect();
+        by_age.sort_unstable();
+        for (_, path) in by_age.into_iter().take(markers.len() - MARKERS_LIMIT) {
+            markers.remove(&path);
+        }
+    }
+    if let Some(path) = markers_path(app_handle) {
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::write(path, serde_json::to_string(&*markers).unwrap_or_default());
+    }
+}
+
+const LOG_FILE_PREFIX: &str = "sqsh";
+const LOG_FILE_SUFFIX: &str = "log";
+const MAX_LOG_FILES: usize = 7;
+
+fn log_dir(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    let config_dir = app_handle.path().config_dir().ok()?;
+    Some(config_dir.join("sqsh").join("logs"))
+}
+
+/// Routes `tracing` output to a daily rolling file in the config dir.
+fn init_logging(app_handle: &tauri::AppHandle) {
+    let Some(dir) = log_dir(app_handle) else { return };
+    let appender = tracing_appender::rolling::Builder::new()
+        .rotation(tracing_appender::rolling::Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir);
+    if let Ok(appender) = appender {
+        let _ = tracing_subscriber::fmt()
+            .with_writer(appender)
+            .with_ansi(false)
+            .with_max_level(tracing::Level::INFO)
+            .try_init();
+    }
+}
+
+/// Last `lines` lines of `content`.
+fn tail_lines(content: &str, lines: usize) -> String {
+    let all: Vec<&str> = content.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+/// Returns the last `lines` lines of the newest log file, for pasting into bug reports.
+#[tauri::command]
+async fn get_log_tail(app_handle: tauri::AppHandle, lines: usize) -> Result<String, OptimizeError> {
+    let Some(dir) = log_dir(&app_handle) else { return Ok(String::new()) };
+    let Ok(entries) = fs::read_dir(&dir) else { return Ok(String::new()) };
+    // Daily files are named `sqsh.YYYY-MM-DD.log`, so the newest sorts last
+    let newest = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with(LOG_FILE_PREFIX) && name.ends_with(LOG_FILE_SUFFIX)
+        })
+        .max();
+    match newest {
+        Some(path) => Ok(tail_lines(&fs::read_to_string(path)?, lines)),
+        None => Ok(String::new()),
+    }
+}
+
+#[tauri::command]
+async fn get_history(state: tauri::State<'_, History>) -> Result<Vec<HistoryEntry>, OptimizeError> {
+    Ok(state.0.lock().unwrap().iter().cloned().collect())
+}
+
+#[tauri::command]
+async fn clear_history(app_handle: tauri::AppHandle, state: tauri::State<'_, History>) -> Result<(), OptimizeError> {
+    let mut entries = state.0.lock().unwrap();
+    entries.clear();
+    save_history(&app_handle, &entries);
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct UndoSummary {
+    /// Originals moved back from their `.bak`.
+    restored: Vec<String>,
+    /// Outputs deleted because they were written next to an untouched original.
+    removed: Vec<String>,
+    failed: Vec<BatchFailure>,
+}
+
+/// Reverts one run: overwritten files come back from their backups and outputs written
+/// elsewhere are deleted. Refuses to touch anything if an in-place overwrite has no
+/// backup to restore. Returns the summary and the indices of the entries it undid.
+fn undo_entries(entries: &[&HistoryEntry]) -> Result<(UndoSummary, Vec<usize>), OptimizeError> {
+    let unrecoverable: Vec<&str> = entries
+        .iter()
+        .filter(|entry| !entry.skipped && entry.output_path == entry.input_path)
+        .filter(|entry| !entry.backup_path.as_deref().is_some_and(|backup| Path::new(backup).is_file()))
+        .map(|entry| entry.input_path.as_str())
+        .collect();
+    if !unrecoverable.is_empty() {
+        return Err(OptimizeError::invalid(format!(
+            "The last run overwrote {} file(s) without a backup, so it can't be undone (first: {})",
+            unrecoverable.len(),
+            unrecoverable[0]
+        )));
+    }
+
+    let mut summary = UndoSummary { restored: Vec::new(), removed: Vec::new(), failed: Vec::new() };
+    let mut undone = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let outcome = match (&entry.backup_path, entry.skipped) {
+            (_, true) => Ok(None),
+            (Some(backup), false) => {
+                restore_file(Path::new(backup), Path::new(&entry.output_path)).map(|_| Some(true))
+            }
+            (None, false) => match fs::remove_file(&entry.output_path) {
+                Ok(()) => Ok(Some(false)),
+                // Already gone, e.g. the user deleted it
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(OptimizeError::from(e)),
+            },
+        };
+        match outcome {
+            Ok(Some(true)) => summary.restored.push(entry.output_path.clone()),
+            Ok(Some(false)) => summary.removed.push(entry.output_path.clone()),
+            Ok(None) => {}
+            Err(error) => {
+                summary.failed.push(BatchFailure { path: entry.output_path.clone(), error });
+                continue;
+            }
+        }
+        undone.push(index);
+    }
+    Ok((summary, undone))
+}
+
+/// Undoes the most recent run in the history and drops its undone entries, so the next
+/// call reaches the run before it.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle, state), err)]
+async fn undo_last_run(app_handle: tauri::AppHandle, state: tauri::State<'_, History>) -> Result<UndoSummary, OptimizeError> {
+    let mut entries = state.0.lock().unwrap();
+    let Some(last) = entries.back().map(|entry| entry.run_key().to_string()) else {
+        return Err(OptimizeError::invalid("There is no run in the history to undo"));
+    };
+    let positions: Vec<usize> = (0..entries.len()).filter(|&i| entries[i].run_key() == last).collect();
+    let run: Vec<&HistoryEntry> = positions.iter().map(|&i| &entries[i]).collect();
+    let (summary, undone) = undo_entries(&run)?;
+
+    for &index in undone.iter().rev() {
+        entries.remove(positions[index]);
+    }
+    save_history(&app_handle, &entries);
+    Ok(summary)
+}
+
+#[tauri::command]
+async fn get_stats(state: tauri::State<'_, std::sync::Mutex<AppConfig>>) -> Result<LifetimeStats, OptimizeError> {
+    Ok(state.lock().unwrap().stats.clone())
+}
+
+#[tauri::command]
+async fn reset_stats(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, std::sync::Mutex<AppConfig>>,
+) -> Result<(), OptimizeError> {
+    let mut config = state.lock().unwrap();
+    config.stats = LifetimeStats::default();
+    save_config(&app_handle, &config);
+    Ok(())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(app_handle, state), err)]
+async fn update_settings(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, std::sync::Mutex<AppConfig>>,
+    dark_mode: Option<bool>,
+    overwrite: Option<bool>,
+    convert_enabled: Option<bool>,
+    convert_format: Option<String>,
+    quality: Option<u32>,
+    backup: Option<bool>,
+    jpeg_quality: Option<u8>,
+    png_level: Option<u8>,
+    webp_quality: Option<f32>,
+    webp_lossless: Option<bool>,
+    jpeg_progressive: Option<bool>,
+    quality_presets: Option<std::collections::HashMap<String, u8>>,
+    temp_dir: Option<String>,
+    max_concurrency: Option<usize>,
+    min_savings_percent: Option<f32>,
+) -> Result<(), OptimizeError> {
+    // Validate every argument before touching the stored config, so a rejected value
+    // can't leave memory half-updated and out of step with sqsh.toml
+    if let Some(v) = jpeg_quality {
+        if !(1..=100).contains(&v) {
+            return Err(OptimizeError::invalid(format!("JPEG quality must be between 1 and 100, got {}", v)));
+        }
+    }
+    let png_level = png_level.map(|v| resolve_png_level(Some(v))).transpose()?;
+    let webp_quality = webp_quality.map(|v| resolve_webp_quality(Some(v))).transpose()?;
+    let quality_presets = quality_presets
+        .map(|presets| {
+            presets
+                .into_iter()
+                .map(|(format, quality)| {
+                    let format = match format.to_lowercase().as_str() {
+                        "jpg" | "jpeg" => "jpg".to_string(),
+                        f @ ("webp" | "avif" | "jxl") => f.to_string(),
+                        _ => return Err(OptimizeError::UnsupportedFormat { format }),
+                    };
+                    if !(1..=100).contains(&quality) {
+                        return Err(OptimizeError::invalid(format!(
+                            "Quality preset for {} must be between 1 and 100, got {}",
+                            format, quality
+                        )));
+                    }
+                    Ok((format, quality))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?;
+    // 0 removes the limit
+    let max_concurrency = max_concurrency.map(|limit| (limit > 0).then_some(limit));
+    // 0 goes back to keeping any output that is smaller at all
+    let min_savings_percent = min_savings_percent
+        .map(|percent| resolve_min_savings(Some(percent)).map(|p| p.filter(|&p| p > 0.0)))
+        .transpose()?;
+    // An empty string goes back to the system temp directory
+    let temp_dir = temp_dir
+        .map(|dir| {
+            if dir.is_empty() {
+                Ok(None)
+            } else if Path::new(&dir).is_dir() && dir_is_writable(Path::new(&dir)) {
+                Ok(Some(dir))
+            } else {
+                Err(OptimizeError::invalid(format!("Temp directory is missing or not writable: {}", dir)))
+            }
+        })
+        .transpose()?;
+
+    let mut config = state.lock().unwrap();
+    let pool = match max_concurrency {
+        Some(limit) if limit != config.max_concurrency => Some(build_pool(effective_threads(None, limit))?),
+        _ => None,
+    };
+
+    if let Some(v) = dark_mode { config.dark_mode = v; }
+    if let Some(v) = overwrite { config.overwrite = v; }
+    if let Some(v) = convert_enabled { config.convert_enabled = v; }
+    if let Some(v) = convert_format { config.convert_format = v; }
+    if let Some(v) = quality { config.quality = v; }
+    if let Some(v) = backup { config.backup = v; }
+    if let Some(v) = jpeg_quality { config.jpeg_quality = v; }
+    if let Some(v) = png_level { config.png_level = v; }
+    if let Some(v) = webp_quality { config.webp_quality = v; }
+    if let Some(v) = webp_lossless { config.webp_lossless = v; }
+    if let Some(v) = jpeg_progressive { config.jpeg_progressive = v; }
+    if let Some(presets) = quality_presets {
+        // Only the given formats change; other stored presets are kept
+        config.quality_presets.extend(presets);
+    }
+    if let Some(limit) = max_concurrency { config.max_concurrency = limit; }
+    if let Some(pool) = pool {
+        *app_handle.state::<WorkerPool>().0.lock().unwrap() = pool;
+    }
+    if let Some(percent) = min_savings_percent { config.min_savings_percent = percent; }
+    if let Some(dir) = temp_dir { config.temp_dir = dir; }
+
+    save_config(&app_handle, &config);
+    Ok(())
+}
+
+/// Layout version written to `sqsh.toml`; older files are upgraded by `migrate_config`.
+const CONFIG_VERSION: u32 = 2;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct AppConfig {
+    /// Files written before versioning have no `version` key and count as version 1.
+    #[serde(default = "legacy_config_version")]
+    version: u32,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    /// Scale factor of the monitor the rect above was saved on; the rect is in physical pixels.
+    #[serde(default)]
+    scale_factor: Option<f64>,
+    /// Reopen maximized; the rect above keeps the restored (un-maximized) bounds.
+    #[serde(default)]
+    maximized: bool,
+    /// Directory for intermediate files instead of the system temp directory.
+    #[serde(default)]
+    temp_dir: Option<String>,
+    /// Folders passed to `scan_directory`/`optimize_directory`, most recent first.
+    #[serde(default)]
+    recent_folders: Vec<String>,
+    /// Upper bound on worker threads for every optimization, oxipng included. Unset uses all CPUs.
+    #[serde(default)]
+    max_concurrency: Option<usize>,
+    /// Default for `min_savings_percent` when a call leaves it unset.
+    #[serde(default)]
+    min_savings_percent: Option<f32>,
+    #[serde(default = "default_dark_mode")]
+    dark_mode: bool,
+    #[serde(default = "default_overwrite")]
+    overwrite: bool,
+    #[serde(default = "default_convert_enabled")]
+    convert_enabled: bool,
+    #[serde(default = "default_convert_format")]
+    convert_format: String,
+    #[serde(default = "default_quality")]
+    quality: u32,
+    #[serde(default = "default_backup")]
+    backup: bool,
+    #[serde(default = "default_jpeg_quality")]
+    jpeg_quality: u8,
+    #[serde(default = "default_png_level")]
+    png_level: u8,
+    #[serde(default = "default_webp_quality")]
+    webp_quality: f32,
+    #[serde(default = "default_webp_lossless")]
+    webp_lossless: bool,
+    #[serde(default = "default_jpeg_progressive")]
+    jpeg_progressive: bool,
+    /// Default quality per target format ("jpg", "webp", "avif", "jxl").
+    #[serde(default)]
+    quality_presets: std::collections::HashMap<String, u8>,
+    #[serde(default)]
+    stats: LifetimeStats,
+}
+
+/// Running totals across sessions, updated after every successful optimization.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+struct LifetimeStats {
+    files_optimized: u64,
+    bytes_saved: u64,
+    time_ms: u64,
+}
+
+fn legacy_config_version() -> u32 { 1 }
+fn default_dark_mode() -> bool { true }
+fn default_overwrite() -> bool { true }
+fn default_convert_enabled() -> bool { false }
+fn default_convert_format() -> String { "jpg".to_string() }
+fn default_quality() -> u32 { 6 }
+fn default_backup() -> bool { false }
+fn default_jpeg_quality() -> u8 { DEFAULT_JPEG_QUALITY }
+fn default_png_level() -> u8 { DEFAULT_PNG_LEVEL }
+fn default_webp_quality() -> f32 { DEFAULT_WEBP_QUALITY }
+fn default_webp_lossless() -> bool { DEFAULT_WEBP_LOSSLESS }
+fn default_jpeg_progressive() -> bool { false }
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            x: 0,
+            y: 0,
+            width: 800,
+            height: 600,
+            scale_factor: None,
+            maximized: false,
+            temp_dir: None,
+            recent_folders: Vec::new(),
+            max_concurrency: None,
+            min_savings_percent: None,
+            dark_mode: default_dark_mode(),
+            overwrite: default_overwrite(),
+            convert_enabled: default_convert_enabled(),
+            convert_format: default_convert_format(),
+            quality: default_quality(),
+            backup: default_backup(),
+            jpeg_quality: default_jpeg_quality(),
+            png_level: default_png_level(),
+            webp_quality: default_webp_quality(),
+            webp_lossless: default_webp_lossless(),
+            jpeg_progressive: default_jpeg_progressive(),
+            quality_presets: std::collections::HashMap::new(),
+            stats: LifetimeStats::default(),
+        }
+    }
+}
+
+fn save_config(app_handle: &tauri::AppHandle, state: &AppConfig) {
+    if let Ok(config_dir) = app_handle.path().config_dir() {
+        let app_dir = config_dir.join("sqsh");
+        if !app_dir.exists() {
+            let _ = std::fs::create_dir_all(&app_dir);
+        }
+        let path = app_dir.join("sqsh.toml");
+        let _ = std::fs::write(path, toml::to_string(state).unwrap_or_default());
+    }
+}
+
+fn config_path(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    Some(app_handle.path().config_dir().ok()?.join("sqsh").join("sqsh.toml"))
+}
+
+fn load_config(app_handle: &tauri::AppHandle) -> Option<AppConfig> {
+    let path = config_path(app_handle)?;
+    let content = std::fs::read_to_string(&path).ok()?;
+    match content.parse::<toml::Table>() {
+        Ok(document) => {
+            let (config, migration) = migrate_config(document);
+            if migration.from_version != CONFIG_VERSION || !migration.changes.is_empty() {
+                for change in &migration.changes {
+                    tracing::info!(from_version = migration.from_version, "config: {}", change);
+                }
+                save_config(app_handle, &config);
+            }
+            Some(config)
+        }
+        Err(e) => {
+            // Not TOML at all: move it aside so the defaults saved later don't destroy it
+            let backup = path.with_extension(format!("toml.broken-{}", Local::now().format("%Y%m%d-%H%M%S")));
+            tracing::warn!(error = %e, backup = %backup.display(), "config is unreadable, backing it up");
+            let _ = std::fs::rename(&path, &backup);
+            None
+        }
+    }
+}
+
+/// What `migrate_config` did to bring a file up to `CONFIG_VERSION`.
+#[derive(serde::Serialize, Debug, Clone)]
+struct ConfigMigration {
+    from_version: u32,
+    changes: Vec<String>,
+}
+
+/// Upgrades a parsed `sqsh.toml` field by field: values that still deserialize are kept,
+/// invalid or unknown ones are dropped and missing ones take their defaults, so one bad
+/// value no longer resets every other setting.
+fn migrate_config(document: toml::Table) -> (AppConfig, ConfigMigration) {
+    let from_version = document
+        .get("version")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as u32)
+        .unwrap_or_else(legacy_config_version);
+    let mut changes = Vec::new();
+    if from_version > CONFIG_VERSION {
+        changes.push(format!("written by a newer version ({}), reading what this version understands", from_version));
+    } else if from_version < CONFIG_VERSION {
+        changes.push(format!("upgraded from version {} to {}", from_version, CONFIG_VERSION));
+    }
+
+    let defaults = toml::Value::try_from(AppConfig::default())
+        .ok()
+        .and_then(|v| v.as_table().cloned())
+        .unwrap_or_default();
+    let mut merged = defaults;
+    for (key, value) in &document {
+        if key == "version" {
+            continue;
+        }
+        let previous = merged.insert(key.clone(), value.clone());
+        if toml::Value::Table(merged.clone()).try_into::<AppConfig>().is_err() {
+            changes.push(format!("dropped invalid value for `{}`", key));
+            match previous {
+                Some(previous) => merged.insert(key.clone(), previous),
+                None => merged.remove(key),
+            };
+        }
+    }
+    let mut config: AppConfig = toml::Value::Table(merged).try_into().unwrap_or_default();
+    config.version = CONFIG_VERSION;
+
+    let written = toml::Value::try_from(&config)
+        .ok()
+        .and_then(|v| v.as_table().cloned())
+        .unwrap_or_default();
+    for key in document.keys().filter(|key| !written.contains_key(*key)) {
+        changes.push(format!("dropped unknown field `{}`", key));
+    }
+    for key in written.keys().filter(|key| *key != "version" && !document.contains_key(*key)) {
+        changes.push(format!("added `{}` with its default", key));
+    }
+
+    (config, ConfigMigration { from_version, changes })
+}
+
+/// Checks a config file (the app's own when `path` is omitted) and reports what loading it
+/// would change, without writing anything.
+#[tauri::command]
+#[tracing::instrument(skip(app_handle), err)]
+async fn validate_config(app_handle: tauri::AppHandle, path: Option<String>) -> Result<ConfigMigration, OptimizeError> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => config_path(&app_handle).ok_or_else(|| OptimizeError::invalid("No config directory available"))?,
+    };
+    let content = fs::read_to_string(&path)?;
+    let document = content
+        .parse::<toml::Table>()
+        .map_err(|e| OptimizeError::invalid(format!("{} is not valid TOML: {}", path.display(), e)))?;
+    Ok(migrate_config(document).1)
+}
+
+/// Intersection area of two `(x, y, width, height)` rects in physical pixels.
+fn overlap_area(a: (i32, i32, u32, u32), b: (i32, i32, u32, u32)) -> u64 {
+    let left = a.0.max(b.0) as i64;
+    let top = a.1.max(b.1) as i64;
+    let right = (a.0 as i64 + a.2 as i64).min(b.0 as i64 + b.2 as i64);
+    let bottom = (a.1 as i64 + a.3 as i64).min(b.1 as i64 + b.3 as i64);
+    ((right - left).max(0) * (bottom - top).max(0)) as u64
+}
+
+const WINDOW_SAVE_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
+const MIN_WINDOW_WIDTH: u32 = 400;
+const MIN_WINDOW_HEIGHT: u32 = 800;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -471,137 +5819,1785 @@ pub fn run() {
             let window = app.get_webview_window("main").unwrap();
             let app_handle = app.handle().clone();
 
-            // Enforce minimum size
-            let _ = window.set_min_size(Some(tauri::Size::Physical(tauri::PhysicalSize {
-                width: MIN_WINDOW_WIDTH,
-                height: MIN_WINDOW_HEIGHT,
-            })));
+            // Enforce minimum size
+            let _ = window.set_min_size(Some(tauri::Size::Physical(tauri::PhysicalSize {
+                width: MIN_WINDOW_WIDTH,
+                height: MIN_WINDOW_HEIGHT,
+            })));
+
+            init_logging(&app_handle);
+
+            // Load and apply state
+            let config = load_config(&app_handle).unwrap_or_default();
+            
+            // Manage state
+            app.manage(std::sync::Mutex::new(config.clone()));
+            app.manage(CancelFlag::default());
+            app.manage(WorkerPool::new(config.max_concurrency)?);
+            app.manage(History(std::sync::Mutex::new(load_history(&app_handle))));
+            app.manage(OptimizedMarkers(std::sync::Mutex::new(load_markers(&app_handle))));
+
+            let mut state = config;
+
+                // 1. Enforce min size
+                if state.width < MIN_WINDOW_WIDTH { state.width = MIN_WINDOW_WIDTH; }
+                if state.height < MIN_WINDOW_HEIGHT { state.height = MIN_WINDOW_HEIGHT; }
+
+                // 2. Validate on-screen
+                if let Ok(available_monitors) = window.available_monitors() {
+                    if !available_monitors.is_empty() {
+                        // Pick the monitor the saved rect overlaps most, so a window mostly on one
+                        // display stays there even if its corner is off-screen. With no overlap at all
+                        // (e.g. its display was unplugged) fall back to the first (usually primary) monitor.
+                        let window_rect = (state.x, state.y, state.width, state.height);
+                        let best_monitor = available_monitors
+                            .iter()
+                            .map(|monitor| {
+                                let (m_pos, m_size) = (monitor.position(), monitor.size());
+                                (monitor, overlap_area(window_rect, (m_pos.x, m_pos.y, m_size.width, m_size.height)))
+                            })
+                            .filter(|(_, area)| *area > 0)
+                            .max_by_key(|(_, area)| *area)
+                            .map_or(&available_monitors[0], |(monitor, _)| monitor);
+
+                        // Clamp to best_monitor
+                        let m_pos = best_monitor.position();
+                        let m_size = best_monitor.size();
+
+                        // The rect is saved in physical pixels, which match the monitor APIs. Rescale the
+                        // size if the target monitor's DPI differs so the window keeps its visual size.
+                        if let Some(saved_scale) = state.scale_factor.filter(|s| *s > 0.0) {
+                            let ratio = best_monitor.scale_factor() / saved_scale;
+                            state.width = (state.width as f64 * ratio).round() as u32;
+                            state.height = (state.height as f64 * ratio).round() as u32;
+                        }
+
+                        // Ensure width/height fits in monitor
+                        if state.width > m_size.width { state.width = m_size.width; }
+                        if state.height > m_size.height { state.height = m_size.height; }
+
+                        // Clamp X
+                        if state.x < m_pos.x { state.x = m_pos.x; }
+                        if state.x + state.width as i32 > m_pos.x + m_size.width as i32 {
+                            state.x = m_pos.x + m_size.width as i32 - state.width as i32;
+                        }
+
+                        // Clamp Y
+                        if state.y < m_pos.y { state.y = m_pos.y; }
+                        if state.y + state.height as i32 > m_pos.y + m_size.height as i32 {
+                            state.y = m_pos.y + m_size.height as i32 - state.height as i32;
+                        }
+                    }
+                }
+
+                // Apply state
+                let _ = window.set_size(tauri::Size::Physical(tauri::PhysicalSize {
+                    width: state.width,
+                    height: state.height,
+                }));
+                let _ = window.set_position(tauri::Position::Physical(tauri::PhysicalPosition {
+                    x: state.x,
+                    y: state.y,
+                }));
+                // Maximize on top of the restored rect so un-maximizing returns to it
+                if state.maximized {
+                    let _ = window.maximize();
+                }
+
+            // Setup listeners to save state
+            let app_handle = app.handle().clone();
+            let window_clone = window.clone();
+            
+            // Trailing debounce: the saver thread writes once no Moved/Resized event has arrived
+            // for WINDOW_SAVE_DEBOUNCE, so the final resting position is always persisted.
+            let (save_tx, save_rx) = std::sync::mpsc::channel::<()>();
+            let saver_handle = app.handle().clone();
+            std::thread::spawn(move || {
+                while save_rx.recv().is_ok() {
+                    while save_rx.recv_timeout(WINDOW_SAVE_DEBOUNCE).is_ok() {}
+                    let app_state: tauri::State<std::sync::Mutex<AppConfig>> = saver_handle.state();
+                    let state = app_state.lock().unwrap();
+                    save_config(&saver_handle, &state);
+                }
+            });
+
+            window.on_window_event(move |event| {
+                match event {
+                    tauri::WindowEvent::Moved(_) | tauri::WindowEvent::Resized(_) => {
+                        // 1. Always update in-memory state
+                        if let (Ok(pos), Ok(size)) = (window_clone.outer_position(), window_clone.inner_size()) {
+                            let app_state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+                            let mut state = app_state.lock().unwrap();
+                            state.maximized = window_clone.is_maximized().unwrap_or(false);
+                            if state.maximized {
+                                // Keep the restored rect; only the flag changes
+                                let _ = save_tx.send(());
+                                return;
+                            }
+                            state.x = pos.x;
+                            state.y = pos.y;
+                            state.width = size.width;
+                            state.height = size.height;
+                            state.scale_factor = window_clone.scale_factor().ok();
+
+                            // 2. Schedule a save; each event pushes it back
+                            let _ = save_tx.send(());
+                        }
+                    }
+                    tauri::WindowEvent::CloseRequested { .. } => {
+                        // 3. Save current in-memory state to disk (do not query window)
+                        let app_state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
+                        let state = app_state.lock().unwrap();
+                        save_config(&app_handle, &state);
+                    }
+                    _ => {}
+                }
+            });
+
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![optimize_image, optimize_images, cancel_optimization, zip_files, save_file, get_config, update_settings, scan_directory, backup_files, restore_backup, compare_quality, get_image_info, generate_thumbnail, get_stats, reset_stats, get_history, clear_history, optimize_bytes, get_log_tail, reveal_in_file_manager, optimize_and_zip, optimize_directory, extract_palette, get_recent_folders, clear_recent_folders, estimate_savings, validate_config, get_compare_previews, rename_by_metadata, project_directory_savings, benchmark_formats, find_duplicates, rotate_jpeg_lossless, extract_frames, assemble_animation, read_exif, undo_last_run, optimize_stdin, get_capabilities])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::{CompressionType, PngEncoder};
+    use image::codecs::png::FilterType as PngFilterType;
+
+    /// Fresh directory under the system temp dir, removed by the caller.
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sqsh-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a gradient PNG with the fastest compression, leaving room for oxipng.
+    fn write_loose_png(path: &Path, width: u32, height: u32) {
+        let img = image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, 255])
+        });
+        let file = fs::File::create(path).unwrap();
+        let encoder = PngEncoder::new_with_quality(file, CompressionType::Fast, PngFilterType::NoFilter);
+        img.write_with_encoder(encoder).unwrap();
+    }
+
+    #[test]
+    fn optimize_core_shrinks_png() {
+        let dir = scratch_dir();
+        let input = dir.join("gradient.png");
+        write_loose_png(&input, 64, 64);
+
+        let result = optimize_core(&input, &OptimizeOptions::default()).unwrap();
+        assert!(!result.skipped);
+        assert!(result.new_size < result.original_size);
+        assert!(image::open(&result.output_path).is_ok());
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn optimize_core_converts_png_to_jpeg() {
+        let dir = scratch_dir();
+        let input = dir.join("gradient.png");
+        write_loose_png(&input, 32, 32);
+
+        let options = OptimizeOptions { convert_to: Some("jpg".to_string()), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        let output = fs::read(&result.output_path).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), image::ImageFormat::Jpeg);
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Copies a checked-in fixture into `dir` so tests never modify the originals.
+    fn fixture(dir: &Path, name: &str) -> PathBuf {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let target = dir.join(name);
+        fs::copy(source, &target).unwrap();
+        target
+    }
+
+    #[test]
+    fn png_fixture_optimizes_smaller() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "gradient.png");
+
+        let options = OptimizeOptions { overwrite: true, ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(!result.skipped);
+        assert_eq!(result.output_path, input.to_string_lossy());
+        assert!(result.new_size < result.original_size);
+        assert_eq!(result.saved_bytes, result.original_size - result.new_size);
+        assert_eq!(fs::metadata(&input).unwrap().len(), result.new_size);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn jpeg_fixture_reencodes_at_quality() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "photo.jpg");
+
+        let options = OptimizeOptions { jpeg_quality: Some(50), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(!result.skipped);
+        assert!(result.new_size < result.original_size);
+        let output = fs::read(&result.output_path).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), image::ImageFormat::Jpeg);
+        assert_eq!(image::load_from_memory(&output).unwrap().dimensions(), (64, 64));
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rgba_to_jpeg_flattens_onto_background() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "rgba.png");
+
+        let options = OptimizeOptions {
+            convert_to: Some("jpg".to_string()),
+            background_color: Some([255, 0, 0]),
+            ..Default::default()
+        };
+        let result = optimize_core(&input, &options).unwrap();
+        let output = image::open(&result.output_path).unwrap().to_rgb8();
+        // The left half is fully transparent, so it should come out as the background
+        let image::Rgb([r, g, b]) = *output.get_pixel(4, 16);
+        assert!(r > 230 && g < 25 && b < 25, "expected red, got {:?}", (r, g, b));
+        // The opaque right half keeps its blue
+        let image::Rgb([r, _, b]) = *output.get_pixel(28, 16);
+        assert!(b > 230 && r < 25, "expected blue, got {:?}", (r, b));
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conversion_to_each_target_is_decodable() {
+        let dir = scratch_dir();
+        let targets = [
+            ("jpg", image::ImageFormat::Jpeg),
+            ("png", image::ImageFormat::Png),
+            ("webp", image::ImageFormat::WebP),
+            ("avif", image::ImageFormat::Avif),
+        ];
+
+        for source in ["gradient.png", "photo.jpg", "lossless.webp"] {
+            let input = fixture(&dir, source);
+            for (target, format) in targets {
+                let options = OptimizeOptions { convert_to: Some(target.to_string()), ..Default::default() };
+                let result = optimize_core(&input, &options).unwrap();
+                let output = fs::read(&result.output_path).unwrap();
+                assert_eq!(image::guess_format(&output).unwrap(), format, "{} -> {}", source, target);
+                // AVIF decoding needs the `avif-decode` feature
+                if format != image::ImageFormat::Avif {
+                    image::load_from_memory(&output).unwrap();
+                }
+                // A skipped same-format run points back at the input; keep it for the next target
+                if !result.skipped {
+                    let _ = fs::remove_file(&result.output_path);
+                }
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn larger_output_is_skipped() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "small.jpg");
+        let original = fs::read(&input).unwrap();
+
+        // Re-encoding a low-quality JPEG at 100 only adds bytes
+        let options = OptimizeOptions { overwrite: true, jpeg_quality: Some(100), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(result.skipped);
+        assert_eq!(result.output_path, input.to_string_lossy());
+        assert_eq!(result.new_size, result.original_size);
+        assert_eq!(result.saved_bytes, 0);
+        assert_eq!(fs::read(&input).unwrap(), original);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sixteen_bit_grayscale_png_converts_to_8bit_jpeg() {
+        let dir = scratch_dir();
+        let input = dir.join("gray16.png");
+        let img = image::ImageBuffer::<image::Luma<u16>, _>::from_fn(32, 32, |x, _| image::Luma([(x * 2048) as u16]));
+        img.save(&input).unwrap();
+
+        let options = OptimizeOptions { convert_to: Some("jpg".to_string()), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        let output = image::open(&result.output_path).unwrap();
+        assert_eq!(output.color(), image::ColorType::L8);
+        // 16-bit 30720 maps to 8-bit ~119; JPEG may drift a little
+        let value = output.to_luma8().get_pixel(15, 16)[0] as i32;
+        assert!((value - 119).abs() <= 4, "got {}", value);
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cmyk_jpeg_reencodes_as_rgb() {
+        let data = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cmyk.jpg")).unwrap();
+        let source = image::load_from_memory(&data).unwrap().to_rgb8();
+
+        // Go through encode_output directly so the skip rule can't hand back the source
+        let options = OptimizeOptions { jpeg_quality: Some(95), ..Default::default() };
+        let encoded = encode_output(&data, "jpg", "jpg", &options).unwrap();
+        let output = image::load_from_memory(&encoded.data).unwrap();
+        assert_eq!(output.color(), image::ColorType::Rgb8);
+        assert_eq!(output.dimensions(), source.dimensions());
+        for (x, y) in [(4, 4), (12, 20), (24, 8), (28, 28)] {
+            let (a, b) = (source.get_pixel(x, y), output.to_rgb8().get_pixel(x, y).to_owned());
+            for c in 0..3 {
+                assert!((a[c] as i32 - b[c] as i32).abs() <= 12, "pixel ({}, {}): {:?} vs {:?}", x, y, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn never_grow_skips_larger_conversion() {
+        let dir = scratch_dir();
+        let input = dir.join("icon.png");
+        // A checkerboard deflates to almost nothing but is expensive for lossy WebP
+        let img = image::RgbImage::from_fn(64, 64, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgb([0, 0, 0]) } else { image::Rgb([255, 255, 255]) }
+        });
+        img.save(&input).unwrap();
+
+        let mut options = OptimizeOptions {
+            convert_to: Some("webp".to_string()),
+            webp_quality: Some(100.0),
+            ..Default::default()
+        };
+        let grown = optimize_core(&input, &options).unwrap();
+        assert!(!grown.skipped);
+        assert!(grown.new_size > grown.original_size);
+        fs::remove_file(&grown.output_path).unwrap();
+
+        options.never_grow = Some(true);
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(result.skipped);
+        assert_eq!(result.output_path, input.to_string_lossy());
+        assert_eq!(result.new_size, result.original_size);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn palette_reduction_indexes_flat_png() {
+        let dir = scratch_dir();
+        let input = dir.join("flat.png");
+        let colors = [[200, 30, 30, 255], [30, 200, 30, 255], [30, 30, 200, 255], [250, 250, 250, 255]];
+        let img = image::RgbaImage::from_fn(128, 128, |x, y| image::Rgba(colors[((x / 32 + y / 32) % 4) as usize]));
+        let file = fs::File::create(&input).unwrap();
+        let encoder = PngEncoder::new_with_quality(file, CompressionType::Fast, PngFilterType::NoFilter);
+        img.write_with_encoder(encoder).unwrap();
+
+        let options = OptimizeOptions { png_palette_reduction: Some(true), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(!result.skipped);
+        assert!(result.new_size * 4 < result.original_size);
+        let output = fs::read(&result.output_path).unwrap();
+        // IHDR color type byte: 3 is indexed
+        assert_eq!(output[25], 3);
+        assert_eq!(image::load_from_memory(&output).unwrap().to_rgba8(), img);
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn grayscale_scan_converts_to_smaller_jpeg() {
+        let dir = scratch_dir();
+        let input = dir.join("scan.png");
+        // Cream paper with dark "text" lines
+        let img = image::RgbImage::from_fn(96, 96, |x, y| {
+            if y % 12 < 3 && (x / 5) % 4 != 0 { image::Rgb([40, 35, 30]) } else { image::Rgb([245, 238, 220]) }
+        });
+        img.save(&input).unwrap();
+
+        let color_options = OptimizeOptions { convert_to: Some("jpg".to_string()), ..Default::default() };
+        let color = optimize_core(&input, &color_options).unwrap();
+        fs::remove_file(&color.output_path).unwrap();
+
+        let options = OptimizeOptions { grayscale: Some(true), ..color_options };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(result.new_size < color.new_size);
+        let output = image::open(&result.output_path).unwrap();
+        assert_eq!(output.color(), image::ColorType::L8);
+        let expected = image::DynamicImage::ImageRgb8(img).to_luma8();
+        let output = output.to_luma8();
+        for (x, y) in [(2, 6), (7, 1), (50, 50), (90, 25)] {
+            let diff = expected.get_pixel(x, y)[0] as i32 - output.get_pixel(x, y)[0] as i32;
+            assert!(diff.abs() <= 24, "pixel ({}, {}) off by {}", x, y, diff);
+        }
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_stamp_changes_when_file_is_rewritten() {
+        let dir = scratch_dir();
+        let path = dir.join("stamp.bin");
+        fs::write(&path, [0u8; 16]).unwrap();
+        let before = file_stamp(&path).unwrap();
+        assert_eq!(file_stamp(&path), Some(before));
+
+        fs::write(&path, [0u8; 32]).unwrap();
+        assert_ne!(file_stamp(&path), Some(before));
+        assert_eq!(file_stamp(&dir.join("missing.bin")), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_fingerprint_ignores_where_outputs_go() {
+        let base = OptimizeOptions { jpeg_quality: Some(80), ..Default::default() };
+        let moved = OptimizeOptions { overwrite: true, output_dir: Some("out".to_string()), force: Some(true), ..base.clone() };
+        assert_eq!(base.output_fingerprint(), moved.output_fingerprint());
+
+        let requality = OptimizeOptions { jpeg_quality: Some(60), ..base.clone() };
+        let resized = OptimizeOptions { max_width: Some(800), ..base.clone() };
+        assert_ne!(base.output_fingerprint(), requality.output_fingerprint());
+        assert_ne!(base.output_fingerprint(), resized.output_fingerprint());
+    }
+
+    #[test]
+    fn tail_lines_keeps_last_lines() {
+        assert_eq!(tail_lines("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail_lines("a\nb", 10), "a\nb");
+        assert_eq!(tail_lines("", 5), "");
+    }
+
+    #[test]
+    fn overlap_area_picks_mostly_covered_monitor() {
+        let primary = (0, 0, 1920, 1080);
+        let secondary = (1920, 0, 2560, 1440);
+        // Corner sits on the primary, but most of the window is on the secondary
+        let window = (1800, 100, 800, 600);
+        assert_eq!(overlap_area(window, primary), 120 * 600);
+        assert_eq!(overlap_area(window, secondary), 680 * 600);
+        assert_eq!(overlap_area((5000, 0, 800, 600), primary), 0);
+        assert_eq!(overlap_area((-900, -700, 800, 600), primary), 0);
+    }
+
+    #[test]
+    fn optimize_to_zip_packs_optimized_outputs() {
+        let dir = scratch_dir();
+        let png = dir.join("gradient.png");
+        write_loose_png(&png, 64, 64);
+        let jpg = fixture(&dir, "photo.jpg");
+        let corrupt = dir.join("corrupt.png");
+        fs::write(&corrupt, b"not a png").unwrap();
+        let files = vec![
+            png.to_string_lossy().to_string(),
+            corrupt.to_string_lossy().to_string(),
+            jpg.to_string_lossy().to_string(),
+        ];
+        let zip_path = dir.join("out.zip");
+
+        let temps = dir.join("temps");
+        fs::create_dir(&temps).unwrap();
+        let options =
+            OptimizeOptions { convert_to: Some("webp".to_string()), temp_dir: Some(temps.clone()), ..Default::default() };
+        let progress = AtomicUsize::new(0);
+        let failures = AtomicUsize::new(0);
+        let (results, failed) = optimize_to_zip(
+            &files,
+            &options,
+            &zip_path,
+            |_, _| {
+                progress.fetch_add(1, Ordering::SeqCst);
+            },
+            |_| {
+                failures.fetch_add(1, Ordering::SeqCst);
+            },
+        )
+        .unwrap();
+        assert_eq!(progress.load(Ordering::SeqCst), 2);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        // The bad file is reported and left out instead of sinking the archive
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, files[1]);
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        for (name, result) in ["gradient.webp", "photo.webp"].iter().zip(&results) {
+            assert_eq!(result.output_path, *name);
+            let mut entry = archive.by_name(name).unwrap();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+            assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::WebP);
+            assert_eq!(data.len() as u64, result.new_size);
+        }
+        // Temps are cleaned up once zipped, and sources are untouched
+        assert_eq!(fs::read_dir(&temps).unwrap().count(), 0);
+        assert!(png.exists() && jpg.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn uncompressed_tiff_shrinks_and_converts() {
+        let dir = scratch_dir();
+        let input = dir.join("scan.tiff");
+        let img = image::RgbImage::from_fn(64, 64, |x, y| {
+            if (x / 8 + y / 8) % 2 == 0 { image::Rgb([250, 250, 245]) } else { image::Rgb([20, 20, 30]) }
+        });
+        // `image` writes TIFFs uncompressed
+        img.save(&input).unwrap();
+
+        let result = optimize_core(&input, &OptimizeOptions::default()).unwrap();
+        assert!(!result.skipped);
+        assert!(result.new_size * 4 < result.original_size);
+        assert_eq!(image::open(&result.output_path).unwrap().to_rgb8(), img);
+        let _ = fs::remove_file(&result.output_path);
+
+        for target in ["png", "webp", "jpg"] {
+            let options = OptimizeOptions { convert_to: Some(target.to_string()), ..Default::default() };
+            let result = optimize_core(&input, &options).unwrap();
+            let output = image::open(&result.output_path).unwrap();
+            assert_eq!(output.dimensions(), (64, 64), "{}", target);
+            let _ = fs::remove_file(&result.output_path);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn auto_convert_lossless_turns_bmp_into_png() {
+        let dir = scratch_dir();
+        let input = dir.join("screenshot.bmp");
+        let img = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 90]));
+        img.save(&input).unwrap();
+
+        assert!(matches!(
+            optimize_core(&input, &OptimizeOptions::default()),
+            Err(OptimizeError::NeedsConversion { .. })
+        ));
+
+        let options = OptimizeOptions { auto_convert_lossless: Some(true), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(result.output_path.ends_with(".png"));
+        assert!(result.new_size * 4 < result.original_size);
+        assert_eq!(image::open(&result.output_path).unwrap().to_rgb8(), img);
+
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn intermediates_use_configured_temp_dir() {
+        let dir = scratch_dir();
+        let input = dir.join("gradient.png");
+        write_loose_png(&input, 64, 64);
+        let temp = dir.join("intermediates");
+        fs::create_dir(&temp).unwrap();
+
+        let options = OptimizeOptions { temp_dir: Some(temp.clone()), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(Path::new(&result.output_path).starts_with(&temp));
+        assert!(result.warnings.is_empty());
+
+        let options = OptimizeOptions { temp_dir: Some(dir.join("missing")), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert!(Path::new(&result.output_path).starts_with(std::env::temp_dir()));
+        assert_eq!(result.warnings.len(), 1);
+        let _ = fs::remove_file(&result.output_path);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_and_truncated_files_report_distinct_errors() {
+        let dir = scratch_dir();
+        let empty = dir.join("empty.png");
+        fs::write(&empty, []).unwrap();
+        assert!(matches!(
+            optimize_core(&empty, &OptimizeOptions::default()),
+            Err(OptimizeError::EmptyFile { .. })
+        ));
+
+        let photo = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/photo.jpg")).unwrap();
+        let partial = dir.join("partial.jpg");
+        fs::write(&partial, &photo[..photo.len() / 2]).unwrap();
+        assert!(matches!(
+            optimize_core(&partial, &OptimizeOptions::default()),
+            Err(OptimizeError::TruncatedImage { .. })
+        ));
+
+        let gradient = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gradient.png")).unwrap();
+        let partial = dir.join("partial.png");
+        fs::write(&partial, &gradient[..gradient.len() / 2]).unwrap();
+        assert!(matches!(
+            optimize_core(&partial, &OptimizeOptions::default()),
+            Err(OptimizeError::TruncatedImage { .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwriting_read_only_file_fails_up_front() {
+        let dir = scratch_dir();
+        let input = dir.join("locked.png");
+        write_loose_png(&input, 32, 32);
+        let mut permissions = fs::metadata(&input).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&input, permissions).unwrap();
+
+        let options = OptimizeOptions { overwrite: true, ..Default::default() };
+        match optimize_core(&input, &options) {
+            Err(OptimizeError::TargetNotWritable { path, suggested_path }) => {
+                assert_eq!(path, input.to_string_lossy());
+                assert_eq!(suggested_path, Some(dir.join("locked (1).png").to_string_lossy().to_string()));
+            }
+            other => panic!("expected TargetNotWritable, got {:?}", other.map(|r| r.output_path)),
+        }
+
+        // Windows refuses to delete read-only files
+        let mut permissions = fs::metadata(&input).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&input, permissions).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scan_paths_walks_nested_folders_with_filters() {
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("2024/raw")).unwrap();
+        write_loose_png(&dir.join("top.png"), 8, 8);
+        write_loose_png(&dir.join("2024/nested.png"), 8, 8);
+        write_loose_png(&dir.join("2024/raw/skip.png"), 8, 8);
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let root = vec![dir.to_string_lossy().to_string()];
+        let filter = ScanFilter::new(&[], &["**/raw/**".to_string()]).unwrap();
+        let scan = scan_paths(root.clone(), &filter, None, false).unwrap();
+        let mut names: Vec<_> = scan.files.iter().map(|f| Path::new(&f.path).file_name().unwrap().to_owned()).collect();
+        names.sort();
+        assert_eq!(names, ["nested.png", "top.png"]);
+
+        let shallow = scan_paths(root, &ScanFilter::new(&[], &[]).unwrap(), Some(1), false).unwrap();
+        assert_eq!(shallow.total_files, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn png_to_qoi_round_trips_losslessly() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "rgba.png");
+        let original = image::open(&input).unwrap().to_rgba8();
+
+        let options = OptimizeOptions { convert_to: Some("qoi".to_string()), ..Default::default() };
+        let qoi = optimize_core(&input, &options).unwrap();
+        let data = fs::read(&qoi.output_path).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::Qoi);
+
+        let qoi_path = dir.join("rgba.qoi");
+        fs::rename(&qoi.output_path, &qoi_path).unwrap();
+        let options = OptimizeOptions { convert_to: Some("png".to_string()), ..Default::default() };
+        let png = optimize_core(&qoi_path, &options).unwrap();
+        assert_eq!(image::open(&png.output_path).unwrap().to_rgba8(), original);
+
+        let _ = fs::remove_file(&png.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exr_tonemaps_to_png_with_exposure() {
+        // Linear middle gray
+        let hdr = image::DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(16, 16, image::Rgb([0.18, 0.18, 0.18])));
+        let mut exr = Vec::new();
+        hdr.write_to(&mut std::io::Cursor::new(&mut exr), image::ImageFormat::OpenExr).unwrap();
+
+        let gray = |exposure: Option<f32>| {
+            let options = OptimizeOptions {
+                convert_to: Some("png".to_string()),
+                hdr_exposure: exposure,
+                ..Default::default()
+            };
+            let encoded = encode_output(&exr, "exr", "png", &options).unwrap();
+            let png = image::load_from_memory(&encoded.data).unwrap();
+            assert_eq!(png.color(), image::ColorType::Rgb8);
+            png.to_rgb8().get_pixel(8, 8)[0]
+        };
+        let neutral = gray(None);
+        assert!((95..=125).contains(&neutral), "middle gray mapped to {}", neutral);
+        assert!(gray(Some(2.0)) > neutral + 40);
+    }
+
+    #[test]
+    fn converts_to_pnm_and_farbfeld() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "rgba.png");
+        let source = fs::read(&input).unwrap();
+        let original = image::load_from_memory(&source).unwrap();
+
+        let options = OptimizeOptions { convert_to: Some("ff".to_string()), ..Default::default() };
+        let ff = encode_output(&source, "png", "ff", &options).unwrap();
+        let decoded = image::load_from_memory_with_format(&ff.data, image::ImageFormat::Farbfeld).unwrap();
+        assert_eq!(decoded.to_rgba8(), original.to_rgba8());
+
+        let options = OptimizeOptions { convert_to: Some("pnm".to_string()), ..Default::default() };
+        let pnm = encode_output(&source, "png", "pnm", &options).unwrap();
+        let decoded = image::load_from_memory_with_format(&pnm.data, image::ImageFormat::Pnm).unwrap();
+        assert_eq!(decoded.dimensions(), original.dimensions());
+        assert!(!decoded.color().has_alpha());
+
+        assert!(matches!(resolve_target_extension(Some("dds"), "png"), Err(OptimizeError::InvalidInput { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dominant_colors_ranks_by_coverage_and_skips_transparency() {
+        // 3/4 red, 1/4 blue, plus a transparent green strip that must not show up
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| match (x, y) {
+            (_, 0..=7) => image::Rgba([0, 255, 0, 0]),
+            (0..=47, _) => image::Rgba([220, 20, 20, 255]),
+            _ => image::Rgba([20, 20, 220, 255]),
+        });
+        let colors = dominant_colors(&image::DynamicImage::ImageRgba8(img), 2).unwrap();
+        assert_eq!(colors.len(), 2);
+        let close = |a: [u8; 3], b: [u8; 3]| a.iter().zip(b).all(|(x, y)| (*x as i32 - y as i32).abs() <= 24);
+        assert!(close(colors[0], [220, 20, 20]), "{:?}", colors);
+        assert!(close(colors[1], [20, 20, 220]), "{:?}", colors);
+
+        assert!(dominant_colors(&image::DynamicImage::new_rgb8(4, 4), 0).is_err());
+    }
+
+    #[test]
+    fn auto_format_keeps_alpha_and_reports_choice() {
+        let dir = scratch_dir();
+        let options = OptimizeOptions { convert_to: Some("auto".to_string()), ..Default::default() };
+
+        let graphic = fixture(&dir, "rgba.png");
+        let result = optimize_core(&graphic, &options).unwrap();
+        if !result.skipped {
+            let format = result.chosen_format.as_deref().unwrap();
+            assert!(["png", "webp"].contains(&format), "{}", format);
+            assert!(image::open(&result.output_path).unwrap().color().has_alpha());
+            let _ = fs::remove_file(&result.output_path);
+        }
+
+        let photo = fixture(&dir, "photo.jpg");
+        let result = optimize_core(&photo, &options).unwrap();
+        assert!(result.new_size <= result.original_size);
+        if !result.skipped {
+            let format = result.chosen_format.as_deref().unwrap();
+            assert!(["jpg", "webp", "avif"].contains(&format), "{}", format);
+            assert!(result.output_path.ends_with(format));
+            let _ = fs::remove_file(&result.output_path);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recent_folders_dedupe_and_cap() {
+        let dir = scratch_dir();
+        let folders: Vec<String> = (0..12)
+            .map(|i| {
+                let folder = dir.join(format!("f{}", i));
+                fs::create_dir(&folder).unwrap();
+                folder.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let mut recent = Vec::new();
+        push_recent_folders(&mut recent, &folders);
+        assert_eq!(recent.len(), RECENT_FOLDERS_LIMIT);
+        assert_eq!(recent[0], folders[11]);
+
+        // Re-scanning moves an entry to the front without duplicating it; files are ignored
+        let file = dir.join("f5/image.png").to_string_lossy().to_string();
+        push_recent_folders(&mut recent, &[folders[5].clone(), file]);
+        assert_eq!(recent[0], folders[5]);
+        assert_eq!(recent.iter().filter(|p| **p == folders[5]).count(), 1);
+        assert_eq!(recent.len(), RECENT_FOLDERS_LIMIT);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            // Load and apply state
-            let config = load_config(&app_handle).unwrap_or_default();
-            
-            // Manage state
-            app.manage(std::sync::Mutex::new(config.clone()));
+    #[test]
+    fn estimate_tracks_bytes_per_pixel() {
+        // A 1000x1000 BMP at 3 bytes per pixel should look very compressible
+        let bmp = estimate_from_header("bmp", 1000, 1000, 3_000_000).unwrap();
+        assert!(bmp.is_estimate);
+        assert!(bmp.estimated_savings_percent > 50.0);
+        assert!(bmp.estimated_bytes < bmp.size_bytes);
 
-            let mut state = config;
+        // A JPEG already at 0.2 bytes per pixel has nothing left to give
+        let jpeg = estimate_from_header("jpg", 1000, 1000, 200_000).unwrap();
+        assert_eq!(jpeg.estimated_savings_percent, 0.0);
+        assert_eq!(jpeg.estimated_bytes, jpeg.size_bytes);
 
-                // 1. Enforce min size
-                if state.width < MIN_WINDOW_WIDTH { state.width = MIN_WINDOW_WIDTH; }
-                if state.height < MIN_WINDOW_HEIGHT { state.height = MIN_WINDOW_HEIGHT; }
+        // Savings are capped per format
+        let png = estimate_from_header("png", 100, 100, 1_000_000).unwrap();
+        assert!(png.estimated_savings_percent <= 40.0);
 
-                // 2. Validate on-screen
-                if let Ok(available_monitors) = window.available_monitors() {
-                    if !available_monitors.is_empty() {
-                        let mut best_monitor = &available_monitors[0];
-
-
-                        // Find monitor with most overlap or closest
-                        // For simplicity, let's just find the monitor that contains the top-left corner
-                        // OR just clamp to the primary/first if completely off.
-                        
-                        // Let's try to clamp to the monitor that the window is *mostly* on.
-                        // But since we are restoring, we just check if the saved rect is valid in ANY monitor.
-                        
-                        let mut is_visible = false;
-                        for monitor in &available_monitors {
-                            let m_pos = monitor.position();
-                            let m_size = monitor.size();
-                            
-                            // Check if top-left is inside
-                            if state.x >= m_pos.x && state.x < m_pos.x + m_size.width as i32 &&
-                               state.y >= m_pos.y && state.y < m_pos.y + m_size.height as i32 {
-                                best_monitor = monitor;
-                                is_visible = true;
-                                break;
-                            }
-                        }
+        assert!(estimate_from_header("ico", 16, 16, 1000).is_err());
+    }
 
-                        if !is_visible {
-                            // If top-left is not visible, try to find a monitor where the window *could* fit
-                            // Default to the first monitor (usually primary)
-                            best_monitor = &available_monitors[0];
-                        }
+    /// Inserts an APP1 EXIF segment carrying only an orientation tag after the SOI marker.
+    fn with_exif_orientation(jpeg: &[u8], orientation: u8) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+        exif.extend_from_slice(&[0, 1]); // one IFD entry
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]); // no next IFD
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
 
-                        // Clamp to best_monitor
-                        let m_pos = best_monitor.position();
-                        let m_size = best_monitor.size();
-                        // Wait, Tauri sizes are usually logical or physical depending on API.
-                        // set_size uses LogicalSize by default or PhysicalSize?
-                        // window.set_size(Size::Physical(...))
-                        // The state we saved... we should probably save Physical to be safe, or Logical.
-                        // Let's assume we save/load Physical for consistency with monitor APIs which return Physical.
-                        
-                        // Ensure width/height fits in monitor
-                        if state.width > m_size.width { state.width = m_size.width; }
-                        if state.height > m_size.height { state.height = m_size.height; }
+    #[test]
+    fn exif_orientation_is_baked_into_pixels() {
+        let dir = scratch_dir();
+        // Left half red, right half blue; orientation 6 means "rotate 90 degrees clockwise"
+        let img = image::RgbImage::from_fn(32, 16, |x, _| {
+            if x < 16 { image::Rgb([230, 20, 20]) } else { image::Rgb([20, 20, 230]) }
+        });
+        let mut jpeg = Vec::new();
+        img.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 95)).unwrap();
+        let input = dir.join("sideways.jpg");
+        fs::write(&input, with_exif_orientation(&jpeg, 6)).unwrap();
 
-                        // Clamp X
-                        if state.x < m_pos.x { state.x = m_pos.x; }
-                        if state.x + state.width as i32 > m_pos.x + m_size.width as i32 {
-                            state.x = m_pos.x + m_size.width as i32 - state.width as i32;
-                        }
+        let options = OptimizeOptions { strip_metadata: Some(true), jpeg_quality: Some(90), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        let data = fs::read(&result.output_path).unwrap();
+        // The output carries no EXIF, so its pixels alone must be upright
+        assert!(!data.windows(4).any(|w| w == b"Exif"));
+        let output = image::load_from_memory(&data).unwrap().to_rgb8();
+        assert_eq!(output.dimensions(), (16, 32));
+        assert!(output.get_pixel(8, 4)[0] > 180, "top should be red");
+        assert!(output.get_pixel(8, 28)[2] > 180, "bottom should be blue");
 
-                        // Clamp Y
-                        if state.y < m_pos.y { state.y = m_pos.y; }
-                        if state.y + state.height as i32 > m_pos.y + m_size.height as i32 {
-                            state.y = m_pos.y + m_size.height as i32 - state.height as i32;
-                        }
-                    }
-                }
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-                // Apply state
-                let _ = window.set_size(tauri::Size::Physical(tauri::PhysicalSize {
-                    width: state.width,
-                    height: state.height,
-                }));
-                let _ = window.set_position(tauri::Position::Physical(tauri::PhysicalPosition {
-                    x: state.x,
-                    y: state.y,
-                }));
+    #[test]
+    fn effective_threads_respects_limit() {
+        assert_eq!(effective_threads(None, None), 0);
+        assert_eq!(effective_threads(Some(8), None), 8);
+        assert_eq!(effective_threads(None, Some(2)), 2);
+        assert_eq!(effective_threads(Some(8), Some(2)), 2);
+        assert_eq!(effective_threads(Some(1), Some(2)), 1);
+    }
 
-            // Setup listeners to save state
-            let app_handle = app.handle().clone();
-            let window_clone = window.clone();
-            
-            // Use a mutex to debounce/throttle saving
-            use std::sync::{Arc, Mutex};
-            use std::time::{Duration, Instant};
-            
-            let last_save = Arc::new(Mutex::new(Instant::now()));
-            
-            window.on_window_event(move |event| {
-                match event {
-                    tauri::WindowEvent::Moved(_) | tauri::WindowEvent::Resized(_) => {
-                        // 1. Always update in-memory state
-                        if let (Ok(pos), Ok(size)) = (window_clone.outer_position(), window_clone.inner_size()) {
-                            let app_state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
-                            let mut state = app_state.lock().unwrap();
-                            state.x = pos.x;
-                            state.y = pos.y;
-                            state.width = size.width;
-                            state.height = size.height;
+    #[test]
+    fn migrate_config_upgrades_v1_layout() {
+        let v1 = "x = 10\ny = 20\nwidth = 900\nheight = 700\ndark_mode = false\n\
+                  convert_format = \"webp\"\nquality = 4\nbackup = \"yes\"\nlegacy_flag = true\n";
+        let (config, migration) = migrate_config(v1.parse::<toml::Table>().unwrap());
 
-                            // 2. Save to disk if throttled
-                            let mut last = last_save.lock().unwrap();
-                            if last.elapsed() > Duration::from_millis(500) {
-                                *last = Instant::now();
-                                save_config(&app_handle, &state);
-                            }
-                        }
-                    }
-                    tauri::WindowEvent::CloseRequested { .. } => {
-                        // 3. Save current in-memory state to disk (do not query window)
-                        let app_state: tauri::State<std::sync::Mutex<AppConfig>> = app_handle.state();
-                        let state = app_state.lock().unwrap();
-                        save_config(&app_handle, &state);
-                    }
-                    _ => {}
-                }
-            });
+        assert_eq!(migration.from_version, 1);
+        assert_eq!((config.x, config.width, config.quality), (10, 900, 4));
+        assert!(!config.dark_mode);
+        assert_eq!(config.convert_format, "webp");
+        // The bad value falls back to its default instead of discarding the file
+        assert_eq!(config.backup, default_backup());
+        assert_eq!(config.jpeg_quality, DEFAULT_JPEG_QUALITY);
+        assert_eq!(config.png_level, DEFAULT_PNG_LEVEL);
+        assert!(migration.changes.iter().any(|c| c.contains("`backup`")));
+        assert!(migration.changes.iter().any(|c| c.contains("`legacy_flag`")));
+        assert!(migration.changes.iter().any(|c| c.contains("`jpeg_quality`")));
 
-            Ok(())
+        let rewritten: toml::Table = toml::to_string(&config).unwrap().parse().unwrap();
+        assert_eq!(rewritten["version"].as_integer(), Some(CONFIG_VERSION as i64));
+        let (_, again) = migrate_config(rewritten);
+        assert!(again.changes.is_empty(), "{:?}", again.changes);
+    }
+
+    #[test]
+    #[cfg(feature = "mozjpeg")]
+    fn jpeg_lossless_keeps_pixels_and_shrinks() {
+        let img = image::RgbImage::from_fn(96, 64, |x, y| image::Rgb([(x * 2) as u8, (y * 3) as u8, ((x + y) % 256) as u8]));
+        let mut jpeg = Vec::new();
+        img.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 85)).unwrap();
+
+        let options = OptimizeOptions { jpeg_lossless: Some(true), ..Default::default() };
+        let encoded = encode_output(&jpeg, "jpg", "jpg", &options).unwrap();
+        assert!(encoded.data.len() < jpeg.len(), "{} >= {}", encoded.data.len(), jpeg.len());
+
+        let before = image::load_from_memory(&jpeg).unwrap();
+        let after = image::load_from_memory(&encoded.data).unwrap();
+        assert_eq!(before.as_bytes(), after.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "mozjpeg")]
+    fn jpeg_lossless_leaves_fixture_pixels_untouched() {
+        let dir = scratch_dir();
+        let source = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let before = image::load_from_memory(&source).unwrap();
+        for progressive in [false, true] {
+            let output = optimize_jpeg_lossless(&source, false, false, progressive).unwrap();
+            assert!(output.len() <= source.len());
+            let after = image::load_from_memory(&output).unwrap();
+            assert_eq!(before.as_bytes(), after.as_bytes());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "mozjpeg"))]
+    fn jpeg_lossless_needs_mozjpeg() {
+        let dir = scratch_dir();
+        let source = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let options = OptimizeOptions { jpeg_lossless: Some(true), ..Default::default() };
+        let result = encode_output(&source, "jpg", "jpg", &options);
+        assert!(matches!(result, Err(OptimizeError::UnsupportedFormat { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compare_previews_match_dimensions() {
+        let original = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(300, 200, image::Rgb([200, 40, 40])));
+        // The optimized copy was resized, yet both previews come out the same size
+        let optimized = original.resize_exact(150, 100, FilterType::Triangle);
+
+        let (before, after) = compare_previews(&original, &optimized, 64).unwrap();
+        let before = image::load_from_memory(&before).unwrap();
+        let after = image::load_from_memory(&after).unwrap();
+        assert_eq!(before.dimensions(), (64, 43));
+        assert_eq!(after.dimensions(), before.dimensions());
+
+        assert!(compare_previews(&original, &optimized, 0).is_err());
+    }
+
+    /// Inserts an APP1 EXIF segment whose EXIF sub-IFD holds only DateTimeOriginal.
+    fn with_exif_capture_date(jpeg: &[u8], date: &str) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+        // IFD0: one entry pointing at the EXIF sub-IFD at offset 26
+        exif.extend_from_slice(&[0, 1, 0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0]);
+        // Sub-IFD: DateTimeOriginal, 20 ASCII bytes at offset 44
+        exif.extend_from_slice(&[0, 1, 0x90, 0x03, 0, 2, 0, 0, 0, 20, 0, 0, 0, 44, 0, 0, 0, 0]);
+        exif.extend_from_slice(date.as_bytes());
+        exif.push(0);
+
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn rename_by_metadata_uses_capture_date_and_sequence() {
+        let dir = scratch_dir();
+        let jpeg = fs::read(fixture(&dir, "small.jpg")).unwrap();
+        fs::remove_file(dir.join("small.jpg")).unwrap();
+        let first = dir.join("DSC0042.jpg");
+        fs::write(&first, with_exif_capture_date(&jpeg, "2024:01:15 09:30:00")).unwrap();
+        let second = dir.join("DSC0043.jpg");
+        fs::write(&second, with_exif_capture_date(&jpeg, "2024:01:15 09:31:10")).unwrap();
+
+        let paths = vec![first.to_string_lossy().to_string(), second.to_string_lossy().to_string()];
+        let renamed = rename_files_by_metadata(&paths, "{date:%Y%m%d}_IMG_{seq}").unwrap();
+        assert_eq!(renamed[0].to, dir.join("20240115_IMG_001.jpg").to_string_lossy());
+        assert_eq!(renamed[1].to, dir.join("20240115_IMG_002.jpg").to_string_lossy());
+        assert!(!first.exists() && dir.join("20240115_IMG_002.jpg").exists());
+
+        // Clashing names fall back to the (n) scheme
+        let paths: Vec<String> = renamed.into_iter().map(|r| r.to).collect();
+        let renamed = rename_files_by_metadata(&paths, "{width}x{height}").unwrap();
+        assert_ne!(renamed[0].to, renamed[1].to);
+        assert!(renamed[1].to.ends_with(" (1).jpg"), "{}", renamed[1].to);
+
+        // A bad pattern is rejected before anything moves
+        let paths: Vec<String> = renamed.iter().map(|r| r.to.clone()).collect();
+        assert!(rename_files_by_metadata(&paths, "{camera}").is_err());
+        assert!(Path::new(&renamed[0].to).exists());
+
+        // A rename failing midway (the file is already gone) undoes the earlier ones
+        let paths = vec![paths[0].clone(), paths[0].clone()];
+        assert!(rename_files_by_metadata(&paths, "moved_{seq}").is_err());
+        assert!(Path::new(&paths[0]).exists());
+        assert!(!dir.join("moved_001.jpg").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_manifest_lists_every_file_with_totals() {
+        let dir = scratch_dir();
+        let files: Vec<String> = ["gradient.png", "photo.jpg"]
+            .iter()
+            .map(|name| fixture(&dir, name).to_string_lossy().to_string())
+            .chain([dir.join("missing.png").to_string_lossy().to_string()])
+            .collect();
+        let options = OptimizeOptions::default();
+        let outcomes: Vec<_> = files.iter().map(|f| Some(optimize_core(Path::new(f), &options))).collect();
+
+        let manifest = BatchManifest::new(&options, &files, &outcomes);
+        assert_eq!(manifest.files.len(), files.len());
+        assert_eq!(manifest.totals.failed, 1);
+        assert_eq!(manifest.totals.optimized + manifest.totals.skipped, 2);
+        let sum = |f: fn(&ManifestEntry) -> Option<u64>| manifest.files.iter().filter_map(f).sum::<u64>();
+        assert_eq!(manifest.totals.new_size, sum(|e| e.new_size));
+        assert_eq!(manifest.totals.saved_bytes, sum(|e| e.saved_bytes));
+
+        let csv_path = dir.join("report.csv");
+        manifest.write(&csv_path).unwrap();
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(csv.lines().count(), files.len() + 1);
+        assert!(csv.lines().last().unwrap().contains(",failed,"));
+
+        let json_path = dir.join("report.json");
+        manifest.write(&json_path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(json["files"].as_array().unwrap().len(), files.len());
+        assert_eq!(json["totals"]["original_size"].as_u64(), Some(manifest.totals.original_size));
+
+        assert!(check_manifest_path(&dir.join("nope").join("report.json")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn project_files_predicts_without_writing() {
+        let dir = scratch_dir();
+        write_loose_png(&dir.join("loose.png"), 64, 64);
+        fixture(&dir, "photo.jpg");
+        fs::write(dir.join("empty.png"), b"").unwrap();
+        let before: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+
+        let scan = scan_paths(vec![dir.to_string_lossy().to_string()], &ScanFilter::new(&[], &[]).unwrap(), None, false).unwrap();
+        let files: Vec<String> = scan.files.into_iter().map(|entry| entry.path).collect();
+        let progress = AtomicUsize::new(0);
+        let savings = project_files(&files, &OptimizeOptions::default(), &AtomicBool::new(false), |_| {
+            progress.fetch_add(1, Ordering::SeqCst);
         })
-        .invoke_handler(tauri::generate_handler![optimize_image, zip_files, save_file, get_config, update_settings, scan_directory, backup_files])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .unwrap();
+
+        assert_eq!(savings.files, 3);
+        assert_eq!(progress.load(Ordering::SeqCst), 3);
+        assert_eq!(savings.current_total, scan.total_bytes);
+        // The loose PNG always shrinks under oxipng
+        assert!(savings.projected_total < savings.current_total);
+        assert_eq!(savings.failed.len(), 1);
+        assert!(savings.skipped >= 1);
+        let after: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(before.len(), after.len());
+
+        let cancelled = project_files(&files, &OptimizeOptions::default(), &AtomicBool::new(true), |_| {});
+        assert!(matches!(cancelled, Err(OptimizeError::Cancelled)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn target_max_bytes_fits_or_reports_smallest() {
+        let img = image::RgbImage::from_fn(400, 300, |x, y| {
+            image::Rgb([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x * y) % 256) as u8])
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(img)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let options = OptimizeOptions {
+            convert_to: Some("jpg".to_string()),
+            target_max_bytes: Some(12_000),
+            ..Default::default()
+        };
+        let (extension, encoded) = encode_to_target(&png, "png", &options).unwrap();
+        assert_eq!(extension, "jpg");
+        assert!(encoded.data.len() <= 12_000, "{} bytes", encoded.data.len());
+
+        // Lossless PNG can only shrink by resizing
+        let options = OptimizeOptions { target_max_bytes: Some(40_000), ..Default::default() };
+        let (_, encoded) = encode_to_target(&png, "png", &options).unwrap();
+        assert!(encoded.data.len() <= 40_000);
+        assert!(encoded.resized);
+
+        let options = OptimizeOptions { target_max_bytes: Some(10), ..Default::default() };
+        match encode_to_target(&png, "png", &options) {
+            Err(OptimizeError::CannotMeetTarget { max_bytes: 10, smallest_size }) => assert!(smallest_size > 10),
+            other => panic!("expected CannotMeetTarget, got {:?}", other.map(|(e, _)| e)),
+        }
+    }
+
+    #[test]
+    fn write_zip_reports_progress_and_cleans_up_on_cancel() {
+        let dir = scratch_dir();
+        let files: Vec<(String, String)> = (0..3)
+            .map(|i| {
+                let path = dir.join(format!("{}.png", i));
+                write_loose_png(&path, 8, 8);
+                (path.to_string_lossy().to_string(), format!("{}.png", i))
+            })
+            .collect();
+
+        let archive = dir.join("all.zip");
+        let mut seen = Vec::new();
+        write_zip(files.clone(), &archive, zip_file_options(None, None).unwrap(), None, |n, _| seen.push(n)).unwrap();
+        assert_eq!(seen, vec![1, 2, 3]);
+        assert!(archive.exists());
+
+        // Cancel after the first file: the partial archive must not be left behind
+        let cancelled = dir.join("cancelled.zip");
+        let flag = AtomicBool::new(false);
+        let result = write_zip(files, &cancelled, zip_file_options(None, None).unwrap(), Some(&flag), |_, _| {
+            flag.store(true, Ordering::SeqCst)
+        });
+        assert!(matches!(result, Err(OptimizeError::Cancelled)));
+        assert!(!cancelled.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn zip_structure_mode_keeps_subfolders_apart() {
+        let dir = scratch_dir();
+        let files: Vec<(String, String)> = ["a", "b"]
+            .iter()
+            .map(|folder| {
+                fs::create_dir_all(dir.join(folder)).unwrap();
+                let path = dir.join(folder).join("1.png");
+                write_loose_png(&path, 8, 8);
+                (path.to_string_lossy().to_string(), "1.png".to_string())
+            })
+            .collect();
+        let names = |archive: &Path| {
+            let mut zip = zip::ZipArchive::new(fs::File::open(archive).unwrap()).unwrap();
+            let mut names: Vec<String> = (0..zip.len()).map(|i| zip.by_index(i).unwrap().name().to_string()).collect();
+            names.sort();
+            names
+        };
+        let options = || zip_file_options(None, None).unwrap();
+
+        let flat = dir.join("flat.zip");
+        write_zip(files.clone(), &flat, options(), None, |_, _| {}).unwrap();
+        assert_eq!(names(&flat), ["1 (1).png", "1.png"]);
+
+        let nested = dir.join("nested.zip");
+        write_zip(structured_zip_entries(files, &dir).unwrap(), &nested, options(), None, |_, _| {}).unwrap();
+        assert_eq!(names(&nested), ["a/1.png", "b/1.png"]);
+
+        assert!(structured_zip_entries(vec![("/elsewhere/x.png".into(), "x.png".into())], &dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn jpeg_444_keeps_red_text_sharper_than_420() {
+        // Screenshot-like: one-pixel red strokes on white
+        let img = image::RgbImage::from_fn(64, 64, |x, y| {
+            if (x % 4 == 1 && y % 16 < 10) || y % 16 == 5 {
+                image::Rgb([220, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(img.clone())
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let error = |subsampling: &str| {
+            let options = OptimizeOptions {
+                convert_to: Some("jpg".to_string()),
+                jpeg_quality: Some(90),
+                jpeg_subsampling: Some(subsampling.to_string()),
+                ..Default::default()
+            };
+            let encoded = encode_output(&png, "png", "jpg", &options).unwrap();
+            let decoded = image::load_from_memory(&encoded.data).unwrap().to_rgb8();
+            decoded
+                .as_raw()
+                .iter()
+                .zip(img.as_raw())
+                .map(|(a, b)| (*a as i64 - *b as i64).abs())
+                .sum::<i64>()
+        };
+        assert!(error("444") < error("420"));
+        assert!(resolve_jpeg_subsampling(Some("411")).is_err());
+    }
+
+    #[test]
+    fn sharpen_applies_only_after_a_downscale() {
+        let dir = scratch_dir();
+        let source = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let (width, _) = image_dimensions(&source).unwrap();
+
+        let render = |max_width: u32, sharpen: Option<f32>| {
+            let options = OptimizeOptions {
+                convert_to: Some("png".to_string()),
+                max_width: Some(max_width),
+                sharpen,
+                ..Default::default()
+            };
+            image::load_from_memory(&encode_output(&source, "jpg", "png", &options).unwrap().data)
+                .unwrap()
+                .to_luma8()
+        };
+        // Sum of horizontal gradients: higher means crisper edges
+        let edges = |img: &image::GrayImage| {
+            img.rows()
+                .flat_map(|row| row.collect::<Vec<_>>().windows(2).map(|w| (w[0][0] as i64 - w[1][0] as i64).abs()).collect::<Vec<_>>())
+                .sum::<i64>()
+        };
+
+        assert!(edges(&render(width / 2, Some(1.0))) > edges(&render(width / 2, None)));
+        assert_eq!(render(width, Some(1.0)), render(width, None));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates_groups_reencoded_copies() {
+        let dir = scratch_dir();
+        let photo = fixture(&dir, "photo.jpg");
+        let gradient = fixture(&dir, "gradient.png");
+        let copy = dir.join("copy.png");
+        let img = load_image(&photo).unwrap();
+        img.resize(img.width() / 2, img.height() / 2, FilterType::Lanczos3).save(&copy).unwrap();
+
+        let files: Vec<String> = [&photo, &gradient, &copy].iter().map(|p| p.to_string_lossy().to_string()).collect();
+        let hashes = hash_files(&files, &AtomicBool::new(false), |_| {}).unwrap();
+        assert_eq!(group_by_hash(&hashes, 5), vec![vec![files[0].clone(), files[2].clone()]]);
+
+        let chained = [("a".to_string(), 0b000), ("b".to_string(), 0b001), ("c".to_string(), 0b011)];
+        assert_eq!(group_by_hash(&chained, 1), vec![vec!["a", "b", "c"]]);
+        assert!(group_by_hash(&chained, 0).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nearest_resize_filter_keeps_pixel_art_edges() {
+        // 2x2 checkerboard cells, so halving maps every cell onto one pixel
+        let art = image::RgbImage::from_fn(32, 32, |x, y| {
+            if (x / 2 + y / 2) % 2 == 0 { image::Rgb([0, 0, 0]) } else { image::Rgb([255, 255, 255]) }
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(art)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let colors = |filter: &str| {
+            let options = OptimizeOptions {
+                max_width: Some(16),
+                resize_filter: Some(filter.to_string()),
+                ..Default::default()
+            };
+            let encoded = encode_output(&png, "png", "png", &options).unwrap();
+            let img = image::load_from_memory(&encoded.data).unwrap().to_luma8();
+            img.pixels().map(|p| p[0]).collect::<std::collections::BTreeSet<_>>()
+        };
+        assert_eq!(colors("nearest").into_iter().collect::<Vec<_>>(), [0, 255]);
+        assert!(colors("lanczos3").iter().any(|&value| value != 0 && value != 255));
+        assert!(resolve_resize_filter(Some("bicubic")).is_err());
+    }
+
+    #[test]
+    fn benchmark_formats_reports_each_target() {
+        let dir = scratch_dir();
+        let source = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let formats: Vec<String> = ["jpg", "webp", "png"].iter().map(|f| f.to_string()).collect();
+
+        let rows = benchmark_source(&source, "jpg", &formats, 75).unwrap();
+        assert_eq!(rows.iter().map(|r| r.format.as_str()).collect::<Vec<_>>(), ["jpg", "webp", "png"]);
+        for row in &rows {
+            assert!(row.error.is_none() && row.bytes > 0, "{:?}", row);
+            let ssim = row.ssim.unwrap();
+            assert!(ssim > 0.8 && ssim <= 1.0, "{:?}", row);
+        }
+        // PNG is lossless, so it reproduces the decoded source exactly
+        assert!(rows[2].ssim.unwrap() > 0.9999);
+
+        assert!(benchmark_source(&source, "jpg", &["bmp2".to_string()], 75).is_err());
+        assert!(benchmark_source(&source, "jpg", &formats, 0).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_reported_instead_of_mangled() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch_dir();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.png");
+        let input = dir.join(name);
+        write_loose_png(&input, 8, 8);
+
+        let scan = scan_paths(vec![dir.to_string_lossy().to_string()], &ScanFilter::new(&[], &[]).unwrap(), None, false).unwrap();
+        assert!(scan.files.is_empty());
+        assert_eq!(scan.unrepresentable.len(), 1);
+
+        let err = optimize_core(&input, &OptimizeOptions::default()).err().unwrap();
+        assert!(matches!(err, OptimizeError::UnrepresentablePath { .. }));
+
+        let free = next_free_path(input.clone(), &dir.join("other.png"));
+        assert_eq!(free.file_name().unwrap().as_bytes(), b"caf\xe9 (1).png");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_root_mirrors_source_folders() {
+        let dir = scratch_dir();
+        let source_root = dir.join("photos");
+        fs::create_dir_all(source_root.join("2024/trip")).unwrap();
+        let input = source_root.join("2024/trip/beach.png");
+        write_loose_png(&input, 16, 16);
+        let original = fs::read(&input).unwrap();
+
+        let mut options = OptimizeOptions { overwrite: true, ..Default::default() };
+        let output_root = dir.join("photos_optimized");
+        options.mirror = Some(mirror_roots(&source_root, &output_root, &options).unwrap());
+
+        let result = optimize_core(&input, &options).unwrap();
+        assert_eq!(Path::new(&result.output_path), output_root.join("2024/trip/beach.png"));
+        assert_eq!(fs::read(&input).unwrap(), original);
+
+        assert!(mirror_roots(&source_root, &source_root.join("out"), &options).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scan_detects_images_by_content() {
+        let dir = scratch_dir();
+        write_loose_png(&dir.join("renamed.bin"), 8, 8);
+        fs::copy(fixture(&dir, "gradient.png"), dir.join("mislabeled.jpg")).unwrap();
+        fs::write(dir.join("notes.dat"), "not an image").unwrap();
+
+        let root = vec![dir.to_string_lossy().to_string()];
+        let by_name = scan_paths(root.clone(), &ScanFilter::new(&[], &[]).unwrap(), None, false).unwrap();
+        assert!(!by_name.files.iter().any(|f| f.path.ends_with("renamed.bin")));
+
+        let filter = ScanFilter { detect_by_content: true, ..ScanFilter::new(&[], &[]).unwrap() };
+        let by_content = scan_paths(root, &filter, None, false).unwrap();
+        let format = |name: &str| by_content.files.iter().find(|f| f.path.ends_with(name)).map(|f| f.extension.as_str());
+        assert_eq!(format("renamed.bin"), Some("png"));
+        assert_eq!(format("mislabeled.jpg"), Some("png"));
+        assert_eq!(format("notes.dat"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn rotate_jpeg_lossless_moves_coefficients() {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]));
+        let mut jpeg = Vec::new();
+        encode_jpeg(&mut jpeg, img.as_raw(), 64, 48, image::ColorType::Rgb8, 85, false, ChromaSubsampling::Ratio420, JpegBackend::Image)
+            .unwrap();
+        let decode = |data: &[u8]| image::load_from_memory(data).unwrap().to_rgb8();
+
+        let rotated = rotate_jpeg_coefficients(&jpeg, 90).unwrap();
+        let (expected, actual) = (image::imageops::rotate90(&decode(&jpeg)), decode(&rotated));
+        assert_eq!(actual.dimensions(), (48, 64));
+        let max_error = expected.as_raw().iter().zip(actual.as_raw()).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+        // Only rounding in the decoder's IDCT and chroma upsampling differs
+        assert!(max_error <= 3, "max error {}", max_error);
+
+        // Four quarter turns give back the original coefficients exactly
+        let full_turn = (0..3).fold(rotated, |data, _| rotate_jpeg_coefficients(&data, 90).unwrap());
+        assert_eq!(decode(&full_turn), decode(&jpeg));
+
+        // A 50-pixel width isn't whole MCUs, so turning it falls back to a re-encode
+        let dir = scratch_dir();
+        let odd = dir.join("odd.jpg");
+        let small = image::DynamicImage::ImageRgb8(image::imageops::crop_imm(&img, 0, 0, 50, 40).to_image());
+        small.save(&odd).unwrap();
+        assert!(rotate_jpeg_coefficients(&fs::read(&odd).unwrap(), 270).is_err());
+        let result = rotate_jpeg(&odd, -90, Some(&dir)).unwrap();
+        assert!(!result.lossless && !result.warnings.is_empty());
+        assert_eq!(image::image_dimensions(&odd).unwrap(), (40, 50));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(not(feature = "mozjpeg"))]
+    #[test]
+    fn rotate_jpeg_reencodes_without_mozjpeg() {
+        let dir = scratch_dir();
+        let input = dir.join("photo.jpg");
+        image::RgbImage::from_pixel(64, 48, image::Rgb([40, 120, 200])).save(&input).unwrap();
+        let result = rotate_jpeg(&input, 90, Some(&dir.join("missing"))).unwrap();
+        assert!(!result.lossless);
+        assert_eq!(result.warnings.len(), 2, "{:?}", result.warnings);
+        assert_eq!(image::image_dimensions(&input).unwrap(), (48, 64));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_options_follow_saved_convert_format() {
+        let dir = scratch_dir();
+        let input = dir.join("photo.png");
+        write_loose_png(&input, 16, 16);
+        let config = AppConfig { convert_enabled: true, convert_format: "webp".to_string(), ..Default::default() };
+
+        let mut options = OptimizeOptions { overwrite: true, ..Default::default() };
+        options.apply_default_format(&config);
+        let result = optimize_core(&input, &options).unwrap();
+        assert_eq!(Path::new(&result.output_path), dir.join("photo.webp"));
+
+        let mut explicit = OptimizeOptions { convert_to: Some("jpg".to_string()), ..Default::default() };
+        explicit.apply_default_format(&config);
+        assert_eq!(explicit.convert_to.as_deref(), Some("jpg"));
+
+        let mut opted_out = OptimizeOptions { convert_to: Some("none".to_string()), ..Default::default() };
+        opted_out.apply_default_format(&config);
+        assert_eq!(opted_out.convert_to, None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extract_frames_writes_composited_pngs() {
+        let dir = scratch_dir();
+        let input = dir.join("walk.gif");
+        {
+            // Black canvas, then nine 2x2 red squares that each keep what came before
+            let mut encoder = gif::Encoder::new(fs::File::create(&input).unwrap(), 20, 20, &[0, 0, 0, 255, 0, 0]).unwrap();
+            let mut background = gif::Frame { width: 20, height: 20, ..Default::default() };
+            background.buffer = std::borrow::Cow::Owned(vec![0; 400]);
+            encoder.write_frame(&background).unwrap();
+            for i in 1..10u16 {
+                let mut square = gif::Frame { width: 2, height: 2, left: i * 2, top: 0, ..Default::default() };
+                square.dispose = gif::DisposalMethod::Keep;
+                square.buffer = std::borrow::Cow::Owned(vec![1; 4]);
+                encoder.write_frame(&square).unwrap();
+            }
+        }
+
+        let paths = write_frames(&input, &dir.join("frames")).unwrap();
+        assert_eq!(paths.len(), 10);
+        assert!(paths[0].ends_with("walk_001.png") && paths[9].ends_with("walk_010.png"));
+        let last = image::open(&paths[9]).unwrap().to_rgb8();
+        assert_eq!(last.dimensions(), (20, 20));
+        assert_eq!(last.get_pixel(2, 0), &image::Rgb([255, 0, 0]));
+        assert_eq!(last.get_pixel(18, 1), &image::Rgb([255, 0, 0]));
+        assert_eq!(last.get_pixel(0, 10), &image::Rgb([0, 0, 0]));
+        let first = image::open(&paths[0]).unwrap().to_rgb8();
+        assert_eq!(first.get_pixel(2, 0), &image::Rgb([0, 0, 0]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn assemble_animation_keeps_delays_and_loop_count() {
+        let dir = scratch_dir();
+        let frames: Vec<String> = (0..5u8)
+            .map(|i| {
+                let path = dir.join(format!("frame{}.png", i));
+                image::RgbaImage::from_pixel(8, 8, image::Rgba([i * 50, 0, 255 - i * 50, 255])).save(&path).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let delays = [100, 200, 100, 300, 500];
+
+        let gif_path = dir.join("out.gif");
+        write_animation(&frames, &delays, Some(3), &gif_path, "gif").unwrap();
+        let data = fs::read(&gif_path).unwrap();
+        let decoded = decode_animation_frames(&data).unwrap();
+        assert_eq!(decoded.iter().map(|f| f.1).collect::<Vec<_>>(), delays);
+        // Palette quantization may shift colors slightly
+        assert!(decoded[3].0.get_pixel(0, 0)[0].abs_diff(150) <= 8);
+        assert!(matches!(read_gif_repeat(&data).unwrap(), gif::Repeat::Finite(2)));
+
+        let webp_path = dir.join("out.webp");
+        write_animation(&frames, &delays, None, &webp_path, "webp").unwrap();
+        let decoded = decode_animation_frames(&fs::read(&webp_path).unwrap()).unwrap();
+        assert_eq!(decoded.len(), 5);
+        // libwebp derives the last frame's duration itself
+        assert_eq!(decoded[..4].iter().map(|f| f.1).collect::<Vec<_>>(), delays[..4]);
+
+        assert!(write_animation(&frames, &delays[..4], None, &gif_path, "gif").is_err());
+        let odd = dir.join("odd.png");
+        image::RgbaImage::new(4, 4).save(&odd).unwrap();
+        let mixed = vec![frames[0].clone(), odd.to_string_lossy().to_string()];
+        assert!(write_animation(&mixed, &[100, 100], None, &gif_path, "gif").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn webp_target_size_lands_near_budget() {
+        let dir = scratch_dir();
+        let source = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let img = decode_image(&source, "jpg").unwrap();
+        let budget = encode_lossy_webp(&img, 90.0).unwrap().len() as u64 / 2;
+
+        let single_pass = encode_webp_to_size(&img, budget).unwrap().len() as u64;
+        assert!(single_pass.abs_diff(budget) <= budget / 5, "{} vs {}", single_pass, budget);
+
+        let options = OptimizeOptions {
+            convert_to: Some("webp".to_string()),
+            target_max_bytes: Some(budget),
+            ..Default::default()
+        };
+        let (_, encoded) = encode_to_target(&source, "jpg", &options).unwrap();
+        assert!(encoded.data.len() as u64 <= budget);
+        assert!(encoded.data.len() as u64 >= budget / 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn png_timeout_falls_back_instead_of_stalling() {
+        // Noise doesn't compress, so every oxipng trial has to chew through all of it
+        let mut seed = 1u32;
+        let noise = image::RgbImage::from_fn(1500, 1500, |_, _| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let [r, g, b, _] = seed.to_le_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(noise)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let timeout = std::time::Duration::from_millis(500);
+        let options = OptimizeOptions {
+            png_level: Some(MAX_PNG_LEVEL),
+            png_timeout_ms: Some(timeout.as_millis() as u64),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let encoded = encode_output(&png, "png", "png", &options).unwrap();
+        // The first run stops at the deadline; a fallback costs at most one more
+        let elapsed = started.elapsed();
+        assert!(elapsed < timeout * 3, "took {:?}", elapsed);
+        assert!(matches!(encoded.png_strategy.as_deref(), Some("timeout" | "fast" | "reencode" | "none")));
+        assert!(!encoded.warnings.is_empty());
+        assert!(encoded.data.len() <= png.len());
+
+        let unbounded = encode_output(&png[..], "png", "png", &OptimizeOptions::default()).unwrap();
+        assert_eq!(unbounded.png_strategy, None);
+    }
+
+    #[test]
+    fn read_exif_lists_tags_and_tolerates_missing_exif() {
+        let dir = scratch_dir();
+        let jpeg = fs::read(fixture(&dir, "small.jpg")).unwrap();
+        let tagged = dir.join("tagged.jpg");
+        fs::write(&tagged, with_exif_capture_date(&jpeg, "2024:01:15 09:30:00")).unwrap();
+
+        let fields = exif_fields(&tagged).unwrap();
+        let (_, value) = fields.iter().find(|(tag, _)| tag == "DateTimeOriginal").unwrap();
+        assert!(value.contains("2024") && value.contains("09:30:00"), "{}", value);
+
+        assert!(exif_fields(&fixture(&dir, "gradient.png")).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn comment_is_written_as_png_text_chunk_and_jpeg_com_segment() {
+        let dir = scratch_dir();
+        let png = fs::read(fixture(&dir, "gradient.png")).unwrap();
+        let options = OptimizeOptions { comment: Some("optimized by sqsh".to_string()), ..Default::default() };
+        let encoded = encode_output(&png, "png", "png", &options).unwrap();
+        // Walk the chunks, checking each CRC the way a PNG reader does
+        let mut at = 8;
+        let mut text = None;
+        while at + 12 <= encoded.data.len() {
+            let length = u32::from_be_bytes(encoded.data[at..at + 4].try_into().unwrap()) as usize;
+            let body = &encoded.data[at + 4..at + 8 + length];
+            let crc = u32::from_be_bytes(encoded.data[at + 8 + length..at + 12 + length].try_into().unwrap());
+            assert_eq!(crc32fast::hash(body), crc);
+            if &body[..4] == b"tEXt" {
+                text = Some(body[4..].to_vec());
+            }
+            at += 12 + length;
+        }
+        assert_eq!(text.as_deref(), Some(&b"Comment\0optimized by sqsh"[..]));
+        image::load_from_memory(&encoded.data).unwrap();
+
+        let unicode = OptimizeOptions { comment: Some("圧縮済み".to_string()), ..Default::default() };
+        let encoded = encode_output(&png, "png", "png", &unicode).unwrap();
+        assert!(encoded.data.windows(4).any(|w| w == b"iTXt"));
+
+        let jpeg = fs::read(fixture(&dir, "photo.jpg")).unwrap();
+        let encoded = encode_output(&jpeg, "jpg", "jpg", &options).unwrap();
+        let marker = encoded.data.windows(2).position(|w| w == [0xFF, 0xFE]).unwrap();
+        let length = u16::from_be_bytes([encoded.data[marker + 2], encoded.data[marker + 3]]) as usize;
+        assert_eq!(&encoded.data[marker + 4..marker + 2 + length], b"optimized by sqsh");
+        image::load_from_memory(&encoded.data).unwrap();
+
+        let webp = OptimizeOptions { convert_to: Some("webp".to_string()), ..options };
+        assert!(!encode_output(&png, "png", "webp", &webp).unwrap().warnings.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn phase_reporter_sees_each_boundary() {
+        let dir = scratch_dir();
+        let input = fixture(&dir, "gradient.png");
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let phases = PhaseReporter(std::sync::Arc::new(move |phase| sink.lock().unwrap().push(phase)));
+
+        let options = OptimizeOptions { phases: Some(phases.clone()), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["decode", "optimize", "write"]);
+        if !result.skipped {
+            let _ = fs::remove_file(&result.output_path);
+        }
+
+        seen.lock().unwrap().clear();
+        let options = OptimizeOptions { convert_to: Some("jpg".to_string()), phases: Some(phases), ..Default::default() };
+        let result = optimize_core(&input, &options).unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["decode", "encode", "write"]);
+        let _ = fs::remove_file(&result.output_path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn min_savings_percent_skips_negligible_gains() {
+        let options = OptimizeOptions { min_savings_percent: Some(5.0), ..Default::default() };
+        // 0.5% smaller
+        assert!(should_keep_original(1000, 995, "png", "png", &options, false));
+        assert!(!should_keep_original(1000, 995, "png", "png", &OptimizeOptions::default(), false));
+        assert!(!should_keep_original(1000, 900, "png", "png", &options, false));
+        // Conversions are still kept whatever they save
+        let converting = OptimizeOptions { convert_to: Some("webp".to_string()), ..options };
+        assert!(!should_keep_original(1000, 995, "png", "webp", &converting, false));
+
+        assert!(resolve_min_savings(Some(100.0)).is_err());
+        assert!(resolve_min_savings(Some(-1.0)).is_err());
+    }
+
+    #[test]
+    fn undo_entries_restores_backups_and_removes_outputs() {
+        let dir = scratch_dir();
+        let entry = |input: &Path, output: &Path, backup: Option<&Path>| HistoryEntry {
+            timestamp: String::new(),
+            input_path: input.to_string_lossy().to_string(),
+            output_path: output.to_string_lossy().to_string(),
+            original_size: 0,
+            new_size: 0,
+            skipped: false,
+            backup_path: backup.map(|path| path.to_string_lossy().to_string()),
+            run_id: None,
+        };
+        let overwritten = dir.join("a.png");
+        let backup = dir.join("a.png.bak");
+        fs::write(&overwritten, b"optimized").unwrap();
+        fs::write(&backup, b"original").unwrap();
+        let source = dir.join("b.png");
+        let converted = dir.join("b.webp");
+        fs::write(&source, b"source").unwrap();
+        fs::write(&converted, b"converted").unwrap();
+
+        let no_backup = dir.join("c.png");
+        fs::write(&no_backup, b"optimized").unwrap();
+        let unrecoverable = entry(&no_backup, &no_backup, None);
+        let run = [entry(&overwritten, &overwritten, Some(&backup)), entry(&source, &converted, None)];
+        let with_unrecoverable: Vec<&HistoryEntry> = run.iter().chain([&unrecoverable]).collect();
+        assert!(undo_entries(&with_unrecoverable).is_err());
+        assert!(converted.exists(), "a refused undo must not touch anything");
+
+        let (summary, undone) = undo_entries(&run.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!((summary.restored.len(), summary.removed.len(), undone), (1, 1, vec![0, 1]));
+        assert_eq!(fs::read(&overwritten).unwrap(), b"original");
+        assert!(!backup.exists() && !converted.exists());
+        assert_eq!(fs::read(&source).unwrap(), b"source");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn optimize_reader_turns_piped_bytes_into_optimized_bytes() {
+        let dir = scratch_dir();
+        let input = dir.join("loose.png");
+        write_loose_png(&input, 64, 64);
+        let data = fs::read(&input).unwrap();
+
+        let result = optimize_reader(std::io::Cursor::new(&data), None, &OptimizeOptions::default()).unwrap();
+        assert_eq!(result.format, "png");
+        assert_eq!(result.original_size, data.len() as u64);
+        assert_eq!(result.new_size, result.data.len() as u64);
+        assert!(!result.skipped && result.new_size < result.original_size);
+        image::load_from_memory(&result.data).unwrap();
+
+        let empty = optimize_reader(std::io::empty(), Some("png"), &OptimizeOptions::default());
+        assert!(matches!(empty, Err(OptimizeError::EmptyFile { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn heif_is_recognized_and_needs_conversion() {
+        let mut heic = vec![0, 0, 0, 24];
+        heic.extend_from_slice(b"ftypheic\0\0\0\0mif1heic");
+        assert!(is_heif(&heic));
+        let mut avif = heic.clone();
+        avif[8..12].copy_from_slice(b"avif");
+        assert!(!is_heif(&avif));
+        // A generic `mif1` major brand needs an HEVC brand among the compatible ones
+        let mut generic = vec![0, 0, 0, 24];
+        generic.extend_from_slice(b"ftypmif1\0\0\0\0mif1heic");
+        assert!(is_heif(&generic));
+        generic[20..24].copy_from_slice(b"avif");
+        assert!(!is_heif(&generic));
+
+        let dir = scratch_dir();
+        let input = dir.join("IMG_0001.HEIC");
+        fs::write(&input, &heic).unwrap();
+        assert_eq!(sniff_format(&input).as_deref(), Some("heic"));
+        // Same-format optimization isn't possible, so the file has to be converted
+        assert!(matches!(
+            optimize_core(&input, &OptimizeOptions::default()),
+            Err(OptimizeError::NeedsConversion { .. })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn auto_contrast_expands_a_dull_scan() {
+        // A washed-out "scan": tones only span 100-140
+        let dull = image::RgbImage::from_fn(64, 64, |x, y| {
+            let level = 100 + ((x + y) * 40 / 126) as u8;
+            image::Rgb([level, level, level.saturating_sub(4)])
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(dull)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let range = |data: &[u8]| {
+            let luma = image::load_from_memory(data).unwrap().to_luma8();
+            let (min, max) = luma.pixels().fold((255, 0), |(min, max), p| (p[0].min(min), p[0].max(max)));
+            max - min
+        };
+        assert!(range(&png) <= 40);
+
+        let options = OptimizeOptions { auto_contrast: Some(true), ..Default::default() };
+        let encoded = encode_output(&png, "png", "png", &options).unwrap();
+        assert!(range(&encoded.data) >= 240, "range is {}", range(&encoded.data));
+        // Off by default
+        let plain = encode_output(&png, "png", "png", &OptimizeOptions::default()).unwrap();
+        assert!(range(&plain.data) <= 40);
+    }
+
+    #[test]
+    fn capabilities_follow_compiled_features() {
+        let caps = capabilities();
+        assert_eq!(caps.features.contains(&"mozjpeg"), cfg!(feature = "mozjpeg"));
+        assert_eq!(caps.inputs.contains(&"avif"), cfg!(feature = "avif-decode"));
+        assert_eq!(caps.inputs.contains(&"jxl"), cfg!(feature = "jxl"));
+        assert_eq!(caps.targets.contains(&"jxl"), cfg!(feature = "jxl"));
+        assert_eq!(caps.inputs.contains(&"heic"), cfg!(feature = "heif"));
+        assert!(caps.inputs.contains(&"png") && caps.targets.contains(&"avif"));
+        for target in caps.targets {
+            assert_eq!(resolve_target_extension(Some(target), "png").unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn optimize_core_reports_missing_file() {
+        let missing = std::env::temp_dir().join(format!("sqsh-missing-{}.png", uuid::Uuid::new_v4()));
+        let err = optimize_core(&missing, &OptimizeOptions::default()).err().unwrap();
+        assert!(matches!(err, OptimizeError::FileNotFound { .. }));
+    }
 }
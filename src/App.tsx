@@ -11,14 +11,70 @@ interface OptimizationResult {
   output_path: string;
   skipped: boolean;
   duration_ms: number;
+  backup_path: string | null;
+  chosen_quality: number | null;
+  warnings: string[];
+  webp_mode: string | null;
+  chosen_format: string | null;
+}
+
+interface ScanEntry {
+  path: string;
+  size_bytes: number;
+  extension: string;
+}
+
+interface ScanResult {
+  files: ScanEntry[];
+  total_files: number;
+  total_bytes: number;
+  unrepresentable: string[];
+}
+
+interface CommandError {
+  type: string;
+  message?: string;
+  path?: string;
+  format?: string;
 }
 
 interface ProcessedFile {
   id: string;
   path: string;
   status: "pending" | "optimizing" | "done" | "error";
+  phase?: string;
   result?: OptimizationResult;
   error?: string;
+  errorType?: string;
+}
+
+const FORMAT_LABELS: Record<string, string> = {
+  jpg: 'JPEG',
+  webp: 'WEBP',
+  ff: 'Farbfeld',
+  jxl: 'JPEG XL',
+};
+
+function describePhase(phase?: string): string {
+  switch (phase) {
+    case 'decode': return 'Decoding...';
+    case 'encode': return 'Encoding...';
+    case 'write': return 'Writing...';
+    default: return 'Optimizing...';
+  }
+}
+
+function describeError(e: unknown): string {
+  if (typeof e !== 'object' || e === null || !('type' in e)) return String(e);
+
+  const err = e as CommandError;
+  switch (err.type) {
+    case 'FileNotFound': return 'File not found';
+    case 'UnsupportedFormat': return `Unsupported format: ${err.format}`;
+    case 'NeedsConversion': return 'Skipped: Enable auto-convert';
+    case 'Cancelled': return 'Cancelled';
+    default: return err.message ?? err.type;
+  }
 }
 
 function formatBytes(bytes: number, decimals = 2) {
@@ -51,9 +107,16 @@ function AppContent() {
   const [backup, setBackup] = useState(false);
   const [loaded, setLoaded] = useState(false);
   const [isDragging, setIsDragging] = useState(false);
+  const [targets, setTargets] = useState<string[]>(["jpg", "png", "webp", "avif", "qoi", "pnm", "ff"]);
   
   const { addToast } = useToast();
 
+  useEffect(() => {
+    invoke<{ inputs: string[]; targets: string[]; features: string[] }>("get_capabilities")
+      .then((capabilities) => setTargets(capabilities.targets))
+      .catch(console.error);
+  }, []);
+
   useEffect(() => {
     invoke<{
       dark_mode: boolean;
@@ -113,10 +176,20 @@ function AppContent() {
       setIsDragging(false);
     });
 
+    const unlistenPhasePromise = listen("optimize://phase", (event) => {
+      const { path, phase } = event.payload as { path: string; phase: string };
+      setFiles((prev) =>
+        prev.map((f) =>
+          f.path === path && f.status === "optimizing" ? { ...f, phase } : f
+        )
+      );
+    });
+
     return () => {
       unlistenDropPromise.then((f) => f());
       unlistenEnterPromise.then((f) => f());
       unlistenLeavePromise.then((f) => f());
+      unlistenPhasePromise.then((f) => f());
     };
   }, [overwrite, convertEnabled, convertFormat, quality, backup]);
 
@@ -128,13 +201,17 @@ function AppContent() {
         addToast("Backup created successfully", "success");
       } catch (e) {
         console.error("Backup failed:", e);
-        addToast("Backup failed: " + e, "error");
+        addToast("Backup failed: " + describeError(e), "error");
       }
     }
 
     let allPaths: string[] = [];
     try {
-      allPaths = await invoke<string[]>("scan_directory", { paths: droppedPaths });
+      const scan = await invoke<ScanResult>("scan_directory", { paths: droppedPaths });
+      allPaths = scan.files.map((entry) => entry.path);
+      if (scan.unrepresentable.length > 0) {
+        console.warn("Skipped files with non-UTF-8 names:", scan.unrepresentable);
+      }
     } catch (e) {
       console.error("Failed to scan directories:", e);
       allPaths = droppedPaths;
@@ -170,9 +247,11 @@ function AppContent() {
       try {
         const result = await invoke<OptimizationResult>("optimize_image", {
           filePath: file.path,
-          overwrite: overwrite,
-          convertTo: convertEnabled ? convertFormat : null,
-          qualityStep: quality,
+          options: {
+            overwrite: overwrite,
+            convertTo: convertEnabled ? convertFormat : null,
+            qualityStep: quality,
+          },
         });
         
         if (!result.skipped) {
@@ -188,7 +267,7 @@ function AppContent() {
         setFiles((prev) =>
           prev.map((f) =>
             f.id === file.id
-              ? { ...f, status: "error", error: String(e) }
+              ? { ...f, status: "error", error: describeError(e), errorType: (e as CommandError)?.type }
               : f
           )
         );
@@ -219,7 +298,7 @@ function AppContent() {
                 defaultPath: originalPath,
                 filters: [{
                   name: 'Image',
-                  extensions: ['png', 'jpg', 'jpeg', 'webp']
+                  extensions: ['png', 'jpg', 'jpeg', 'webp', 'avif', 'qoi', 'pnm', 'ff']
                 }]
               });
     
@@ -379,9 +458,9 @@ function AppContent() {
               disabled={!convertEnabled}
               className="bg-background border border-border text-foreground text-sm rounded-md focus:ring-primary focus:border-primary block p-1.5 disabled:opacity-50 disabled:cursor-not-allowed"
             >
-              <option value="jpg">JPEG</option>
-              <option value="png">PNG</option>
-              <option value="webp">WEBP</option>
+              {targets.map((target) => (
+                <option key={target} value={target}>{FORMAT_LABELS[target] ?? target.toUpperCase()}</option>
+              ))}
             </select>
           </div>
 
@@ -416,7 +495,7 @@ function AppContent() {
             {isDragging ? "Drop files to squash!" : "Drag & drop images or folders here"}
           </p>
           <p className="text-sm text-muted-foreground/60">
-            Supports PNG, JPG, WEBP, BMP, TIFF, GIF, ICO, TGA, DDS, PNM, QOI
+            Supports PNG, JPG, WEBP, AVIF, BMP, TIFF, GIF, ICO, TGA, DDS, PNM, QOI
           </p>
         </div>
       </div>
@@ -490,7 +569,7 @@ function AppContent() {
             <div className="flex-1 min-w-0">
               <p className="font-medium truncate" title={file.path}>{file.path.split(/[\\/]/).pop()}</p>
               {file.error ? (
-                <p className={`text-sm ${file.error.includes("Skipped") ? "text-yellow-500" : "text-destructive"}`}>
+                <p className={`text-sm ${file.errorType === "NeedsConversion" ? "text-yellow-500" : "text-destructive"}`}>
                   {file.error}
                 </p>
               ) : (
@@ -509,7 +588,7 @@ function AppContent() {
                       </>
                     )
                   ) : (
-                    <span>{file.status === "optimizing" ? "Optimizing..." : "Pending"}</span>
+                    <span>{file.status === "optimizing" ? describePhase(file.phase) : "Pending"}</span>
                   )}
                 </div>
               )}